@@ -0,0 +1,192 @@
+// End-to-end encryption in the browser. Files are sealed and opened here
+// with WebCrypto in the same container format the server uses (see
+// src/container.rs), so the server only ever sees the sealed blob, the salt
+// and the KDF cost.
+//
+// Forms opt in with `data-e2e-upload` or `data-e2e-download` and an `e2e`
+// checkbox. Unticked, they submit through htmx as usual.
+
+const E2E = (() => {
+    const MAGIC = [0x53, 0x4e, 0x4d, 0x4e]; // "SNMN"
+    const VERSION = 1;
+    const HEADER_LEN = 29;
+    const CHUNK_SIZE = 64 * 1024;
+    const TAG_LEN = 16;
+    const KDF_ITERATIONS = 600000;
+    const encoder = new TextEncoder();
+
+    async function deriveKey(password, salt, iterations) {
+        const material = await crypto.subtle.importKey(
+            'raw', encoder.encode(password), 'PBKDF2', false, ['deriveKey']);
+        return crypto.subtle.deriveKey(
+            { name: 'PBKDF2', hash: 'SHA-512', salt: encoder.encode(salt), iterations },
+            material,
+            { name: 'AES-GCM', length: 256 },
+            false,
+            ['encrypt', 'decrypt']);
+    }
+
+    function randomSalt() {
+        const alphabet = 'ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789';
+        const bytes = crypto.getRandomValues(new Uint8Array(32));
+        return Array.from(bytes, (b) => alphabet[b % alphabet.length]).join('');
+    }
+
+    function parseHeader(bytes) {
+        if (bytes.length < HEADER_LEN || MAGIC.some((b, i) => bytes[i] !== b) || bytes[4] !== VERSION) {
+            throw new Error('not a senmon blob');
+        }
+        const view = new DataView(bytes.buffer, bytes.byteOffset, HEADER_LEN);
+        return {
+            raw: bytes.slice(0, HEADER_LEN),
+            chunkSize: view.getUint32(5),
+            plaintextLen: Number(view.getBigUint64(9)),
+            nonce: bytes.slice(17, HEADER_LEN),
+        };
+    }
+
+    // An empty file is still sealed as one empty chunk, so that opening it
+    // checks the password.
+    function chunkCount(header) {
+        return Math.max(1, Math.ceil(header.plaintextLen / header.chunkSize));
+    }
+
+    // The base nonce with the chunk index folded into its last eight bytes.
+    function chunkNonce(header, index) {
+        const counter = new Uint8Array(8);
+        new DataView(counter.buffer).setBigUint64(0, BigInt(index));
+        const nonce = header.nonce.slice();
+        for (let i = 0; i < 8; i++) {
+            nonce[4 + i] ^= counter[i];
+        }
+        return nonce;
+    }
+
+    async function seal(plaintext, password) {
+        const salt = randomSalt();
+        const key = await deriveKey(password, salt, KDF_ITERATIONS);
+        const raw = new Uint8Array(HEADER_LEN);
+        const view = new DataView(raw.buffer);
+        raw.set(MAGIC, 0);
+        raw[4] = VERSION;
+        view.setUint32(5, CHUNK_SIZE);
+        view.setBigUint64(9, BigInt(plaintext.length));
+        raw.set(crypto.getRandomValues(new Uint8Array(12)), 17);
+        const header = parseHeader(raw);
+
+        const parts = [raw];
+        for (let index = 0; index < chunkCount(header); index++) {
+            const chunk = plaintext.subarray(index * CHUNK_SIZE, (index + 1) * CHUNK_SIZE);
+            parts.push(new Uint8Array(await crypto.subtle.encrypt(
+                { name: 'AES-GCM', iv: chunkNonce(header, index), additionalData: raw },
+                key, chunk)));
+        }
+        return { blob: new Blob(parts), salt, iterations: KDF_ITERATIONS };
+    }
+
+    async function open(sealed, password, salt, iterations) {
+        const header = parseHeader(sealed);
+        const key = await deriveKey(password, salt, iterations);
+        const parts = [];
+        let offset = HEADER_LEN;
+        for (let index = 0; index < chunkCount(header); index++) {
+            const len = Math.min(header.chunkSize, header.plaintextLen - index * header.chunkSize);
+            const chunk = sealed.subarray(offset, offset + len + TAG_LEN);
+            try {
+                parts.push(new Uint8Array(await crypto.subtle.decrypt(
+                    { name: 'AES-GCM', iv: chunkNonce(header, index), additionalData: header.raw },
+                    key, chunk)));
+            } catch (_) {
+                throw new Error('wrong file password, or the file is damaged');
+            }
+            offset += len + TAG_LEN;
+        }
+        return new Blob(parts);
+    }
+
+    async function apiError(response) {
+        try {
+            return new Error((await response.json()).message);
+        } catch (_) {
+            return new Error(response.statusText);
+        }
+    }
+
+    async function upload(form) {
+        const file = form.elements.file.files[0];
+        if (!file) {
+            throw new Error('choose a file');
+        }
+        const plaintext = new Uint8Array(await file.arrayBuffer());
+        const sealed = await seal(plaintext, form.elements.pwd.value);
+        const body = new FormData();
+        body.append('file', sealed.blob, file.name);
+        body.append('salt', sealed.salt);
+        body.append('kdf_iterations', String(sealed.iterations));
+        body.append('folder', form.elements.folder.value);
+        const response = await fetch('/api/v1/files', { method: 'POST', body });
+        if (!response.ok) {
+            throw await apiError(response);
+        }
+        window.location.href = '/assets/html/land.html';
+    }
+
+    async function download(form) {
+        const path = form.elements.file_name.value.replace(/^\/+|\/+$/g, '');
+        const slash = path.lastIndexOf('/');
+        const folder = slash < 0 ? '' : path.slice(0, slash);
+        const name = path.slice(slash + 1);
+
+        const listing = await fetch('/api/v1/folders?path=' + encodeURIComponent(folder));
+        if (!listing.ok) {
+            throw await apiError(listing);
+        }
+        const entry = (await listing.json()).files.find((f) => f.file_name === name);
+        if (!entry) {
+            throw new Error(path + ': no such file');
+        }
+        const info = await fetch('/api/v1/files/' + entry.file_id);
+        if (!info.ok) {
+            throw await apiError(info);
+        }
+        const { salt, kdf_iterations } = await info.json();
+        const blob = await fetch('/api/v1/files/' + entry.file_id + '/blob');
+        if (!blob.ok) {
+            throw await apiError(blob);
+        }
+        const sealed = new Uint8Array(await blob.arrayBuffer());
+        const plaintext = await open(sealed, form.elements.password.value, salt, kdf_iterations);
+
+        const url = URL.createObjectURL(plaintext);
+        const link = document.createElement('a');
+        link.style.display = 'none';
+        link.href = url;
+        link.download = name;
+        document.body.appendChild(link);
+        link.click();
+        setTimeout(() => {
+            URL.revokeObjectURL(url);
+            link.remove();
+        }, 100);
+    }
+
+    // Runs before htmx sees the submit, which is bound on the form itself.
+    document.addEventListener('submit', (evt) => {
+        const form = evt.target;
+        const action = form.matches('[data-e2e-upload]') ? upload
+            : form.matches('[data-e2e-download]') ? download
+            : null;
+        if (!action || !form.elements.e2e || !form.elements.e2e.checked) {
+            return;
+        }
+        evt.preventDefault();
+        evt.stopPropagation();
+        const status = document.getElementById(form.dataset.e2eStatus);
+        status.textContent = 'Working...';
+        action(form)
+            .then(() => { status.textContent = ''; })
+            .catch((why) => { status.textContent = why.message; });
+    }, true);
+
+    return { seal, open };
+})();
@@ -24,9 +24,13 @@ htmx.defineExtension('htmx-download', {
                 // Extract filename
                 let filename = 'downloaded_file.xlsx';
                 if (headers['content-disposition']) {
-                    const filenameMatch = headers['content-disposition'].match(/filename\*?=(?:UTF-8'')?"?([^;\n"]+)/i);
-                    if (filenameMatch && filenameMatch[1]) {
-                        filename = decodeURIComponent(filenameMatch[1].replace(/['"]/g, ''));
+                    const disposition = headers['content-disposition'];
+                    const encodedMatch = disposition.match(/filename\*=UTF-8''([^;\n]+)/i);
+                    const plainMatch = disposition.match(/filename="?([^;\n"]+)/i);
+                    if (encodedMatch && encodedMatch[1]) {
+                        filename = decodeURIComponent(encodedMatch[1]);
+                    } else if (plainMatch && plainMatch[1]) {
+                        filename = plainMatch[1];
                     }
                 }
                 
@@ -0,0 +1,714 @@
+//! `/api/v1`, a JSON API for scripts. It runs on the same auth, storage and
+//! crypto paths as the HTMX front end, but takes JSON (or multipart for
+//! uploads), answers with JSON and plain status codes instead of `HX-*`
+//! headers, and reports errors as `{"error": code, "message": text}`.
+//!
+//! Clients authenticate with the `session` cookie set by `/api/v1/login`,
+//! or with a personal API token sent as `Authorization: Bearer snm_...`.
+//! Tokens are limited to their scopes and can not manage tokens.
+
+use std::sync::Arc;
+
+use axum::extract::multipart::MultipartRejection;
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{DefaultBodyLimit, FromRef, FromRequestParts, Multipart, Path, Query, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::body::Body;
+use axum::{async_trait, Json, Router};
+use axum_extra::extract::{CookieJar, WithRejection};
+use serde::{Deserialize, Serialize};
+use tokio_util::io::ReaderStream;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::auth::{self, AuthRequest, Caller};
+use crate::config::Config;
+use crate::db::DatabaseConnection;
+use crate::handlers::{self, UnlockReq, UnlockResponse};
+use crate::keyring::Keyring;
+use crate::metrics::METRICS;
+use crate::session::Session;
+use crate::tokens::{self, ApiToken, Scope};
+use crate::types::SenmonError;
+use crate::{folders, trash, tus, unlock, AppState};
+
+/// A [`SenmonError`] rendered as JSON.
+pub struct ApiError(SenmonError);
+
+type ApiResult<T> = Result<T, ApiError>;
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// Stable machine readable code, e.g. `not_found`.
+    error: &'static str,
+    message: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.0.status();
+        if status.is_server_error() {
+            tracing::error!("{}", self.0);
+        }
+        // Server side details only go to the log.
+        let message = if status.is_server_error() {
+            status.canonical_reason().unwrap_or_default().to_string()
+        } else {
+            self.0.to_string()
+        };
+        let mut response = (
+            status,
+            Json(ErrorBody {
+                error: self.0.code(),
+                message,
+            }),
+        )
+            .into_response();
+        if let SenmonError::RangeNotSatisfiable(len) = self.0 {
+            response.headers_mut().insert(
+                header::CONTENT_RANGE,
+                format!("bytes */{len}").parse().unwrap(),
+            );
+        }
+        response
+    }
+}
+
+impl From<SenmonError> for ApiError {
+    fn from(why: SenmonError) -> Self {
+        ApiError(why)
+    }
+}
+
+impl From<rusqlite::Error> for ApiError {
+    fn from(why: rusqlite::Error) -> Self {
+        ApiError(why.into())
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(_: JsonRejection) -> Self {
+        ApiError(SenmonError::BadRequest)
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(_: QueryRejection) -> Self {
+        ApiError(SenmonError::BadRequest)
+    }
+}
+
+impl From<MultipartRejection> for ApiError {
+    fn from(_: MultipartRejection) -> Self {
+        ApiError(SenmonError::BadRequest)
+    }
+}
+
+/// Ids that do not parse can not name anything.
+impl From<PathRejection> for ApiError {
+    fn from(_: PathRejection) -> Self {
+        ApiError(SenmonError::NotFound)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Caller
+where
+    DatabaseConnection: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let db = DatabaseConnection::from_ref(state);
+        let jar = CookieJar::from_headers(&parts.headers);
+        Ok(auth::caller(&db, &parts.headers, &jar).await?)
+    }
+}
+
+type JsonBody<T> = WithRejection<Json<T>, ApiError>;
+type ApiQuery<T> = WithRejection<Query<T>, ApiError>;
+type ApiPath<T> = WithRejection<Path<T>, ApiError>;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/signup", post(signup))
+        .route("/login", post(login))
+        .route(
+            "/folders",
+            get(list_folder).post(create_folder).delete(delete_folder),
+        )
+        .route(
+            "/files",
+            post(upload_file).layer(DefaultBodyLimit::max(tus::TUS_MAX_SIZE as usize)),
+        )
+        .route("/files/:file_id", get(file_info).delete(delete_file))
+        .route("/files/:file_id/blob", get(file_blob))
+        .route("/files/:file_id/unlock", post(unlock_file))
+        .route("/files/:file_id/content", get(file_content))
+        .route("/trash", get(list_trash))
+        .route("/trash/:trash_id/restore", post(restore_file))
+        .route("/tokens", get(list_tokens).post(create_token))
+        .route("/tokens/:token_id", axum::routing::delete(revoke_token))
+        .fallback(|| async { ApiError(SenmonError::NotFound) })
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SessionBody {
+    user_id: u32,
+    expires_at: String,
+}
+
+fn session_response(config: &Config, status: StatusCode, session: &Session) -> Response {
+    (
+        status,
+        [(header::SET_COOKIE, auth::session_cookie(config, session).to_string())],
+        Json(SessionBody {
+            user_id: session.user_id,
+            expires_at: session.expires_at.to_rfc3339(),
+        }),
+    )
+        .into_response()
+}
+
+/// `POST /api/v1/signup` with `{"username", "password"}`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/signup",
+    tag = "auth",
+    request_body = AuthRequest,
+    responses(
+        (status = 201, description = "Account created and logged in, sets the `session` cookie", body = SessionBody),
+        (status = 409, description = "User name is taken", body = ErrorBody),
+    )
+)]
+pub async fn signup(
+    State(db): State<DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    WithRejection(Json(req), _): JsonBody<AuthRequest>,
+) -> ApiResult<Response> {
+    let session = auth::register(&db, &config, &req.username, &req.password).await?;
+    Ok(session_response(&config, StatusCode::CREATED, &session))
+}
+
+/// `POST /api/v1/login` with `{"username", "password"}`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/login",
+    tag = "auth",
+    request_body = AuthRequest,
+    responses(
+        (status = 200, description = "Logged in, sets the `session` cookie", body = SessionBody),
+        (status = 401, description = "Wrong user name or password", body = ErrorBody),
+    )
+)]
+pub async fn login(
+    State(db): State<DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    WithRejection(Json(req), _): JsonBody<AuthRequest>,
+) -> ApiResult<Response> {
+    let session = auth::authenticate(&db, &config, &req.username, &req.password).await?;
+    Ok(session_response(&config, StatusCode::OK, &session))
+}
+
+#[derive(Deserialize, ToSchema, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FolderPath {
+    /// Slash separated folder path, empty for the root folder.
+    #[serde(default)]
+    path: String,
+}
+
+/// Fields of `POST /api/v1/files`, for the OpenAPI document only. Send
+/// either `pwd`, or `salt` and `kdf_iterations` for a file sealed by the
+/// client.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct UploadForm {
+    /// The file, or for end-to-end encryption the blob the client sealed.
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>,
+    /// File password the server encrypts the contents with.
+    pwd: Option<String>,
+    /// Salt the client derived its key with.
+    salt: Option<String>,
+    /// PBKDF2-HMAC-SHA512 iterations the client derived its key with.
+    kdf_iterations: Option<u32>,
+    /// Folder to store the file in, the root folder if empty.
+    folder: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FileBody {
+    file_id: i64,
+    file_name: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FileInfo {
+    file_id: i64,
+    file_name: String,
+    /// Sealed by the client. The server refuses to decrypt such files, fetch
+    /// the blob and open it locally instead.
+    end_to_end: bool,
+    salt: String,
+    kdf_iterations: u32,
+    /// Size of the encrypted blob in bytes.
+    blob_size: u64,
+    /// Size of the file in bytes, if known.
+    size: Option<u64>,
+    /// Content type the file was uploaded with, if any.
+    content_type: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FolderBody {
+    path: String,
+    folders: Vec<String>,
+    files: Vec<FileBody>,
+}
+
+/// `GET /api/v1/folders?path=a/b` lists a folder, the root without `path`.
+#[utoipa::path(
+    get,
+    path = "/api/v1/folders",
+    tag = "folders",
+    params(FolderPath),
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Subfolders and files of the folder", body = FolderBody),
+        (status = 404, description = "No such folder", body = ErrorBody),
+    )
+)]
+pub async fn list_folder(
+    State(db): State<DatabaseConnection>,
+    State(keyring): State<Keyring>,
+    caller: Caller,
+    WithRejection(Query(req), _): ApiQuery<FolderPath>,
+) -> ApiResult<Json<FolderBody>> {
+    caller.require(Scope::Read)?;
+    let user_id = caller.user_id;
+    let path = folders::split_path(&req.path)?.join("/");
+    let listing = folders::list_folder(&db, &keyring, user_id, &path).await?;
+    Ok(Json(FolderBody {
+        path,
+        folders: listing.folders,
+        files: listing
+            .files
+            .into_iter()
+            .map(|file| FileBody {
+                file_id: file.file_id,
+                file_name: file.file_name,
+            })
+            .collect(),
+    }))
+}
+
+/// `POST /api/v1/folders` with `{"path"}`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/folders",
+    tag = "folders",
+    request_body = FolderPath,
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 201, description = "Folder created"),
+        (status = 409, description = "The name is taken", body = ErrorBody),
+    )
+)]
+pub async fn create_folder(
+    State(db): State<DatabaseConnection>,
+    State(keyring): State<Keyring>,
+    caller: Caller,
+    WithRejection(Json(req), _): JsonBody<FolderPath>,
+) -> ApiResult<StatusCode> {
+    caller.require(Scope::Write)?;
+    let user_id = caller.user_id;
+    folders::create_folder(&db, &keyring, user_id, &req.path).await?;
+    Ok(StatusCode::CREATED)
+}
+
+/// `DELETE /api/v1/folders?path=a/b` moves the folder's files to the trash.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/folders",
+    tag = "folders",
+    params(FolderPath),
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 204, description = "Folder deleted, its files are in the trash"),
+        (status = 404, description = "No such folder", body = ErrorBody),
+    )
+)]
+pub async fn delete_folder(
+    State(db): State<DatabaseConnection>,
+    State(keyring): State<Keyring>,
+    caller: Caller,
+    WithRejection(Query(req), _): ApiQuery<FolderPath>,
+) -> ApiResult<StatusCode> {
+    caller.require(Scope::Write)?;
+    let user_id = caller.user_id;
+    folders::delete_folder(&db, &keyring, user_id, &req.path).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `POST /api/v1/files` takes the same multipart fields as the upload form:
+/// `file`, `pwd` and optionally `folder`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/files",
+    tag = "files",
+    request_body(content = UploadForm, content_type = "multipart/form-data"),
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 201, description = "File stored", body = FileBody),
+        (status = 409, description = "A file with that name exists in the folder", body = ErrorBody),
+        (status = 413, description = "The file is over 256 MiB", body = ErrorBody),
+    )
+)]
+pub async fn upload_file(
+    State(db): State<DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    State(keyring): State<Keyring>,
+    caller: Caller,
+    WithRejection(form, _): WithRejection<Multipart, ApiError>,
+) -> ApiResult<(StatusCode, Json<FileBody>)> {
+    caller.require(Scope::Write)?;
+    let req = handlers::parse_multipart(form).await?;
+    let file_name = req.file_name.clone();
+    let file_id =
+        handlers::store_upload(&db, &config, &keyring, caller.user_id, req).await?;
+    Ok((StatusCode::CREATED, Json(FileBody { file_id, file_name })))
+}
+
+/// `DELETE /api/v1/files/{id}` moves a file to the trash.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/files/{file_id}",
+    tag = "files",
+    params(("file_id" = i64, Path, description = "File id")),
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 204, description = "File moved to the trash"),
+        (status = 404, description = "No such file", body = ErrorBody),
+    )
+)]
+pub async fn delete_file(
+    State(db): State<DatabaseConnection>,
+    caller: Caller,
+    WithRejection(Path(file_id), _): ApiPath<i64>,
+) -> ApiResult<StatusCode> {
+    caller.require(Scope::Write)?;
+    let user_id = caller.user_id;
+    trash::trash_file(&db, user_id, file_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `GET /api/v1/files/{id}` describes a file and how its key is derived.
+#[utoipa::path(
+    get,
+    path = "/api/v1/files/{file_id}",
+    tag = "files",
+    params(("file_id" = i64, Path, description = "File id")),
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "The file", body = FileInfo),
+        (status = 404, description = "No such file", body = ErrorBody),
+    )
+)]
+pub async fn file_info(
+    State(db): State<DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    State(keyring): State<Keyring>,
+    caller: Caller,
+    WithRejection(Path(file_id), _): ApiPath<i64>,
+) -> ApiResult<Json<FileInfo>> {
+    caller.require(Scope::Read)?;
+    let db_row = handlers::file_row(&db, &keyring, caller.user_id, file_id).await?;
+    let blob_size = tokio::fs::metadata(config.blob_path(&db_row.blob))
+        .await
+        .map_err(SenmonError::from)?
+        .len();
+    Ok(Json(FileInfo {
+        file_id,
+        file_name: db_row.file_name,
+        end_to_end: db_row.end_to_end,
+        salt: db_row.salt,
+        kdf_iterations: db_row.kdf_iterations,
+        blob_size,
+        size: db_row.meta.size,
+        content_type: db_row.meta.content_type,
+    }))
+}
+
+/// `GET /api/v1/files/{id}/blob` sends the encrypted blob as stored, for
+/// clients that decrypt themselves.
+#[utoipa::path(
+    get,
+    path = "/api/v1/files/{file_id}/blob",
+    tag = "files",
+    params(("file_id" = i64, Path, description = "File id")),
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Encrypted blob", content_type = "application/octet-stream", body = Vec<u8>),
+        (status = 404, description = "No such file", body = ErrorBody),
+    )
+)]
+pub async fn file_blob(
+    State(db): State<DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    State(keyring): State<Keyring>,
+    caller: Caller,
+    WithRejection(Path(file_id), _): ApiPath<i64>,
+) -> ApiResult<Response> {
+    caller.require(Scope::Read)?;
+    let db_row = handlers::file_row(&db, &keyring, caller.user_id, file_id).await?;
+    let blob = tokio::fs::File::open(config.blob_path(&db_row.blob))
+        .await
+        .map_err(SenmonError::from)?;
+    let len = blob.metadata().await.map_err(SenmonError::from)?.len();
+    METRICS.downloads.inc();
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_LENGTH, len.to_string()),
+        ],
+        Body::from_stream(ReaderStream::new(blob)),
+    )
+        .into_response())
+}
+
+/// `POST /api/v1/files/{id}/unlock` with `{"password"}`.
+#[utoipa::path(
+    post,
+    path = "/api/v1/files/{file_id}/unlock",
+    tag = "files",
+    params(("file_id" = i64, Path, description = "File id")),
+    request_body = UnlockReq,
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Token that opens the file for this session or API token", body = UnlockResponse),
+        (status = 401, description = "Wrong file password", body = ErrorBody),
+        (status = 409, description = "The file is end-to-end encrypted", body = ErrorBody),
+    )
+)]
+pub async fn unlock_file(
+    State(db): State<DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    State(keys): State<unlock::UnlockKeys>,
+    State(keyring): State<Keyring>,
+    caller: Caller,
+    WithRejection(Path(file_id), _): ApiPath<i64>,
+    WithRejection(Json(req), _): JsonBody<UnlockReq>,
+) -> ApiResult<Json<UnlockResponse>> {
+    caller.require(Scope::Read)?;
+    let unlocked = handlers::unlock_with_password(
+        &db,
+        &config,
+        &keys,
+        &keyring,
+        caller.holder,
+        caller.user_id,
+        file_id,
+        &req.password,
+    )
+    .await?;
+    Ok(Json(unlocked))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ContentQuery {
+    /// Unlock token, for clients that can not set `X-Unlock-Token`.
+    unlock: Option<String>,
+}
+
+/// `GET /api/v1/files/{id}/content` answers like
+/// `GET /api/files/{id}/content`, including ranges and conditional requests.
+#[utoipa::path(
+    get,
+    path = "/api/v1/files/{file_id}/content",
+    tag = "files",
+    params(
+        ("file_id" = i64, Path, description = "File id"),
+        ContentQuery,
+        ("X-Unlock-Token" = Option<String>, Header, description = "Token from the unlock endpoint"),
+        ("X-Unlock-Password" = Option<String>, Header, description = "File password"),
+        ("Range" = Option<String>, Header, description = "A single `bytes=` range"),
+    ),
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Decrypted file", content_type = "application/octet-stream", body = Vec<u8>),
+        (status = 206, description = "Requested range", content_type = "application/octet-stream", body = Vec<u8>),
+        (status = 304, description = "Not modified"),
+        (status = 401, description = "Missing or wrong file secret", body = ErrorBody),
+        (status = 409, description = "The file is end-to-end encrypted", body = ErrorBody),
+        (status = 416, description = "Range outside the file", body = ErrorBody),
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn file_content(
+    State(db): State<DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    State(keys): State<unlock::UnlockKeys>,
+    State(keyring): State<Keyring>,
+    caller: Caller,
+    headers: HeaderMap,
+    WithRejection(Path(file_id), _): ApiPath<i64>,
+    WithRejection(Query(query), _): ApiQuery<ContentQuery>,
+) -> ApiResult<Response> {
+    caller.require(Scope::Read)?;
+    let secret = handlers::request_secret(&keys, &headers, query.unlock, caller.holder, file_id)?
+        .ok_or(SenmonError::WrongFileKey)?;
+    let db_row = handlers::file_row(&db, &keyring, caller.user_id, file_id).await?;
+    Ok(handlers::serve_file(&db, &config, &headers, db_row, secret).await?)
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TrashBody {
+    trash_id: i64,
+    file_name: String,
+    deleted_at: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/trash",
+    tag = "trash",
+    security(("session" = []), ("bearer" = [])),
+    responses((status = 200, description = "Trashed files, newest first", body = Vec<TrashBody>))
+)]
+pub async fn list_trash(
+    State(db): State<DatabaseConnection>,
+    State(keyring): State<Keyring>,
+    caller: Caller,
+) -> ApiResult<Json<Vec<TrashBody>>> {
+    caller.require(Scope::Read)?;
+    let user_id = caller.user_id;
+    let entries = trash::list_trash(&db, &keyring, user_id).await?;
+    Ok(Json(
+        entries
+            .into_iter()
+            .map(|entry| TrashBody {
+                trash_id: entry.trash_id,
+                file_name: entry.file_name,
+                deleted_at: entry.deleted_at,
+            })
+            .collect(),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/trash/{trash_id}/restore",
+    tag = "trash",
+    params(("trash_id" = i64, Path, description = "Trash entry id")),
+    security(("session" = []), ("bearer" = [])),
+    responses(
+        (status = 204, description = "File restored where it was"),
+        (status = 409, description = "The name has been reused", body = ErrorBody),
+    )
+)]
+pub async fn restore_file(
+    State(db): State<DatabaseConnection>,
+    caller: Caller,
+    WithRejection(Path(trash_id), _): ApiPath<i64>,
+) -> ApiResult<StatusCode> {
+    caller.require(Scope::Write)?;
+    let user_id = caller.user_id;
+    trash::restore(&db, user_id, trash_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct NewToken {
+    /// What the token is for, up to 64 characters.
+    name: String,
+    scopes: Vec<Scope>,
+    /// Days until the token expires, 1 to 365.
+    #[serde(default = "default_lifetime")]
+    #[schema(default = 30)]
+    expires_in_days: u32,
+}
+
+fn default_lifetime() -> u32 {
+    tokens::DEFAULT_LIFETIME_DAYS
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CreatedToken {
+    /// The token itself. It is shown only this once.
+    token: String,
+    #[serde(flatten)]
+    details: ApiToken,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tokens",
+    tag = "tokens",
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "API tokens of the user, newest first", body = Vec<ApiToken>),
+        (status = 403, description = "Called with an API token", body = ErrorBody),
+    )
+)]
+pub async fn list_tokens(
+    State(db): State<DatabaseConnection>,
+    caller: Caller,
+) -> ApiResult<Json<Vec<ApiToken>>> {
+    caller.require_session()?;
+    Ok(Json(tokens::list(&db, caller.user_id).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/tokens",
+    tag = "tokens",
+    request_body = NewToken,
+    security(("session" = [])),
+    responses(
+        (status = 201, description = "Token created", body = CreatedToken),
+        (status = 400, description = "Bad name, scopes or lifetime", body = ErrorBody),
+        (status = 403, description = "Called with an API token", body = ErrorBody),
+    )
+)]
+pub async fn create_token(
+    State(db): State<DatabaseConnection>,
+    caller: Caller,
+    WithRejection(Json(req), _): JsonBody<NewToken>,
+) -> ApiResult<(StatusCode, Json<CreatedToken>)> {
+    caller.require_session()?;
+    let (token, details) = tokens::create(
+        &db,
+        caller.user_id,
+        &req.name,
+        &req.scopes,
+        req.expires_in_days,
+    )
+    .await?;
+    Ok((StatusCode::CREATED, Json(CreatedToken { token, details })))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/tokens/{token_id}",
+    tag = "tokens",
+    params(("token_id" = i64, Path, description = "Token id")),
+    security(("session" = [])),
+    responses(
+        (status = 204, description = "Token revoked"),
+        (status = 404, description = "No such token", body = ErrorBody),
+    )
+)]
+pub async fn revoke_token(
+    State(db): State<DatabaseConnection>,
+    caller: Caller,
+    WithRejection(Path(token_id), _): ApiPath<i64>,
+) -> ApiResult<StatusCode> {
+    caller.require_session()?;
+    tokens::revoke(&db, caller.user_id, token_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
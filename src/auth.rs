@@ -1,106 +1,214 @@
-use axum::http::StatusCode;
+use std::sync::Arc;
+
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::{body::Body, http::Response, Form};
 use axum_extra::extract::cookie::Cookie;
+use axum_extra::extract::CookieJar;
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
+use crate::config::Config;
 use crate::db::{self, *};
+use crate::handlers;
+use crate::logging;
+use crate::metrics::METRICS;
 use crate::session::*;
+use crate::tokens::{self, Scope};
+use crate::types::SenmonError;
+use crate::unlock::Holder;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct AuthRequest {
-    username: String,
-    password: String,
+    pub username: String,
+    pub password: String,
+}
+
+/// Creates an account and logs it in. Shared by the HTMX form and
+/// `POST /api/v1/signup`.
+pub async fn register(
+    db: &DatabaseConnection,
+    config: &Config,
+    username: &str,
+    password: &str,
+) -> Result<Session, SenmonError> {
+    if is_present(db, username).await {
+        METRICS.auth("signup", false);
+        return Err(SenmonError::UserExists);
+    }
+    if let Some(why) = db::add_user(db, username, password).await {
+        return Err(why.into());
+    }
+    let id = db::get_user_id(db, username).await?;
+    logging::record_user(id);
+    METRICS.auth("signup", true);
+    start_session(db, config, id).await
+}
+
+/// Checks a user name and password and starts a session. Shared by the HTMX
+/// form and `POST /api/v1/login`.
+pub async fn authenticate(
+    db: &DatabaseConnection,
+    config: &Config,
+    username: &str,
+    password: &str,
+) -> Result<Session, SenmonError> {
+    if !is_present(db, username).await || !validate_user(db, username, password).await {
+        METRICS.auth("login", false);
+        return Err(SenmonError::InvalidCredentials);
+    }
+
+    let id = get_user_id(db, username).await?;
+    logging::record_user(id);
+    METRICS.auth("login", true);
+    start_session(db, config, id).await
+}
+
+async fn start_session(
+    db: &DatabaseConnection,
+    config: &Config,
+    user_id: u32,
+) -> Result<Session, SenmonError> {
+    let session = Session::new(user_id, config.session_lifetime());
+    if let Some(why) = session_serialize(db, &session).await {
+        return Err(why.into());
+    }
+    Ok(session)
+}
+
+/// The session cookie is kept from scripts, and from plain HTTP when the
+/// server speaks HTTPS, so it never crosses the redirect port in clear text.
+pub fn session_cookie(config: &Config, session: &Session) -> Cookie<'static> {
+    Cookie::build(("session", session.session_id.to_string()))
+        .path("/")
+        .http_only(true)
+        .secure(config.tls().is_some())
+        .build()
 }
 
 // #[axum::debug_handler]
 pub async fn auth(
     axum::extract::State(state): axum::extract::State<DatabaseConnection>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
     Form(req): Form<AuthRequest>,
-) -> Response<Body> {
-    if is_present(&state, &req.username).await {
-        return Response::builder()
-            .status(StatusCode::CONFLICT)
-            .header("HX-Location", "/")
-            .body(Body::empty())
-            .unwrap();
-    }
-    let result = db::add_user(&state, &req.username, &req.password).await;
-    match result {
-        Some(_) => {
-            return Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .header("HX-Location", "/assets/html/home.html")
-                .body(Body::empty())
-                .unwrap();
-        }
-        None => {}
-    }
-    let result = db::get_user_id(&state, &req.username).await;
-    return match result {
-        Ok(id) => {
-            let session = Session::new(id);
-            session_serialize(&state, &session).await.unwrap();
-            Response::builder()
-                .status(StatusCode::ACCEPTED)
-                .header("HX-Location", "/assets/html/land.html")
-                .header("Set-Cookie", format!("session={}", session.session_id))
-                .body(Body::empty())
-                .unwrap()
-        }
-        Err(_) => Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .header("HX-Location", "/")
-            .body(Body::empty())
-            .unwrap(),
-    };
+) -> Result<Response<Body>, SenmonError> {
+    let session = register(&state, &config, &req.username, &req.password).await?;
+    Ok(Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header("HX-Location", "/assets/html/land.html")
+        .header("Set-Cookie", session_cookie(&config, &session).to_string())
+        .body(Body::empty())
+        .unwrap())
 }
 
 // #[axum::debug_handler]
 pub async fn login(
     axum::extract::State(state): axum::extract::State<DatabaseConnection>,
+    axum::extract::State(config): axum::extract::State<Arc<Config>>,
     Form(req): Form<AuthRequest>,
-) -> Response<Body> {
-    if !is_present(&state, &req.username).await {
-        return Response::builder()
-            .header("HX-Redirect", "/assets/html/home.html")
-            .body(Body::empty())
-            .unwrap();
+) -> Result<Response<Body>, SenmonError> {
+    let session = authenticate(&state, &config, &req.username, &req.password).await?;
+    Ok(Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header("HX-Redirect", "/assets/html/land.html")
+        .header("Set-Cookie", session_cookie(&config, &session).to_string())
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// The user behind a JSON API request, logged in either with the session
+/// cookie or with an `Authorization: Bearer` API token.
+pub struct Caller {
+    pub user_id: u32,
+    /// What unlock tokens handed to this caller are bound to.
+    pub holder: Holder,
+    /// `None` for sessions, which may do everything.
+    scopes: Option<Vec<Scope>>,
+}
+
+impl Caller {
+    pub fn require(&self, scope: Scope) -> Result<(), SenmonError> {
+        match &self.scopes {
+            Some(scopes) if !scopes.contains(&scope) => Err(SenmonError::Forbidden),
+            _ => Ok(()),
+        }
     }
 
-    if !validate_user(&state, &req.username, &req.password).await {
-        return Response::builder()
-            .status(StatusCode::UNAUTHORIZED)
-            .header("HX-Redirect", "/assets/html/home.html")
-            .body(Body::empty())
-            .unwrap();
+    /// Token management is only open to a logged in user, so that a leaked
+    /// token can not mint or keep itself alive.
+    pub fn require_session(&self) -> Result<(), SenmonError> {
+        match self.holder {
+            Holder::Session(_) => Ok(()),
+            Holder::ApiToken(_) => Err(SenmonError::Forbidden),
+        }
     }
+}
 
-    let result = get_user_id(&state, &req.username).await;
-    if let Ok(id) = result {
-        let session = Session::new(id);
-        if let Some(err) = session_serialize(&state, &session).await {
-            eprintln!("{err}");
-            return Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .header("HX-Redirect", "/assets/html/home.html")
-                .body(Body::empty())
-                .unwrap();
+/// A request that carries `Authorization` is judged by its token alone and
+/// never falls back to the cookie.
+pub async fn caller(
+    db: &DatabaseConnection,
+    headers: &HeaderMap,
+    jar: &CookieJar,
+) -> Result<Caller, SenmonError> {
+    if let Some(authorization) = headers.get(header::AUTHORIZATION) {
+        let token = authorization
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(SenmonError::Unauthenticated)?;
+        let owner = tokens::authenticate(db, token.trim()).await?;
+        METRICS.auth("token", owner.is_some());
+        let owner = owner.ok_or(SenmonError::Unauthenticated)?;
+        logging::record_user(owner.user_id);
+        return Ok(Caller {
+            user_id: owner.user_id,
+            holder: Holder::ApiToken(owner.token_id),
+            scopes: Some(owner.scopes),
+        });
+    }
+
+    let session_id = handlers::session_id(jar).ok_or(SenmonError::Unauthenticated)?;
+    let user_id = handlers::session_user_id(db, jar)
+        .await
+        .ok_or(SenmonError::Unauthenticated)?;
+    Ok(Caller {
+        user_id,
+        holder: Holder::Session(session_id),
+        scopes: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caller(holder: Holder, scopes: Option<Vec<Scope>>) -> Caller {
+        Caller {
+            user_id: 1,
+            holder,
+            scopes,
         }
-        
-        let cookie = Cookie::build(("session", session.session_id.to_string()))
-            .path("/")
-            .build();
-        return Response::builder()
-            .status(StatusCode::ACCEPTED)
-            .header("HX-Redirect", "/assets/html/land.html")
-            .header("Set-Cookie", cookie.to_string())
-            .body(Body::empty())
-            .unwrap();
-    } else {
-        return Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .header("HX-Location", "/")
-            .body(Body::empty())
-            .unwrap();
+    }
+
+    #[test]
+    fn sessions_may_do_everything() {
+        let session = caller(Holder::Session(1), None);
+        assert!(session.require(Scope::Read).is_ok());
+        assert!(session.require(Scope::Write).is_ok());
+        assert!(session.require_session().is_ok());
+    }
+
+    #[test]
+    fn tokens_are_limited_to_their_scopes() {
+        let token = caller(Holder::ApiToken(1), Some(vec![Scope::Read]));
+        assert!(token.require(Scope::Read).is_ok());
+        assert!(matches!(
+            token.require(Scope::Write),
+            Err(SenmonError::Forbidden)
+        ));
+        assert!(matches!(
+            token.require_session(),
+            Err(SenmonError::Forbidden)
+        ));
     }
 }
@@ -0,0 +1,483 @@
+//! A thin client for `/api/v1`.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use futures_util::{StreamExt, TryStreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use rand::Rng;
+use reqwest::header::{self, HeaderMap};
+use reqwest::multipart::{Form, Part};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use zeroize::Zeroizing;
+
+use crate::container;
+
+/// How requests are authenticated.
+pub enum Auth {
+    Session(String),
+    Token(String),
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Http(reqwest::Error),
+    Io(std::io::Error),
+    /// The server answered with an error body.
+    Api {
+        status: StatusCode,
+        message: String,
+    },
+    NotLoggedIn,
+    /// The credentials file could not be read or written.
+    Credentials(String),
+    NoSuchFile(String),
+    /// A blob did not open with the key, or is cut short.
+    Decrypt,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Http(why) => write!(f, "request failed: {why}"),
+            ClientError::Io(why) => write!(f, "{why}"),
+            ClientError::Api { status, message } => write!(f, "{message} ({status})"),
+            ClientError::NotLoggedIn => f.write_str("not logged in, run `senmon-cli login`"),
+            ClientError::Credentials(why) => write!(f, "credentials: {why}"),
+            ClientError::NoSuchFile(path) => write!(f, "{path}: no such file"),
+            ClientError::Decrypt => f.write_str("wrong file password, or the file is damaged"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(why: reqwest::Error) -> Self {
+        ClientError::Http(why)
+    }
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(why: std::io::Error) -> Self {
+        ClientError::Io(why)
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    message: String,
+}
+
+#[derive(Deserialize)]
+pub struct FileEntry {
+    pub file_id: i64,
+    pub file_name: String,
+}
+
+#[derive(Deserialize)]
+pub struct FileInfo {
+    pub file_id: i64,
+    pub end_to_end: bool,
+    pub salt: String,
+    pub kdf_iterations: u32,
+}
+
+#[derive(Deserialize)]
+pub struct Folder {
+    pub folders: Vec<String>,
+    pub files: Vec<FileEntry>,
+}
+
+pub struct Client {
+    http: reqwest::Client,
+    server: String,
+    auth: Auth,
+}
+
+impl Client {
+    pub fn new(server: &str, auth: Auth) -> Self {
+        Client {
+            http: reqwest::Client::new(),
+            server: server.trim_end_matches('/').to_string(),
+            auth,
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .http
+            .request(method, format!("{}/api/v1{path}", self.server));
+        match &self.auth {
+            Auth::Session(session) => request.header(header::COOKIE, format!("session={session}")),
+            Auth::Token(token) => request.bearer_auth(token),
+        }
+    }
+
+    /// Logs in with a user name and password and returns the session cookie.
+    pub async fn login(
+        server: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<String, ClientError> {
+        let response = reqwest::Client::new()
+            .post(format!("{}/api/v1/login", server.trim_end_matches('/')))
+            .json(&serde_json::json!({ "username": username, "password": password }))
+            .send()
+            .await?;
+        let response = check(response).await?;
+        session_cookie(response.headers()).ok_or_else(|| ClientError::Api {
+            status: response.status(),
+            message: "the server did not set a session cookie".to_string(),
+        })
+    }
+
+    /// Makes sure the credentials are accepted. A token without the `read`
+    /// scope can not list folders, but it is still a valid token.
+    pub async fn check_auth(&self) -> Result<(), ClientError> {
+        let response = self.request(Method::GET, "/folders").send().await?;
+        match response.status() {
+            StatusCode::FORBIDDEN => Ok(()),
+            _ => check(response).await.map(drop),
+        }
+    }
+
+    pub async fn list(&self, path: &str) -> Result<Folder, ClientError> {
+        let response = self
+            .request(Method::GET, "/folders")
+            .query(&[("path", path)])
+            .send()
+            .await?;
+        Ok(check(response).await?.json().await?)
+    }
+
+    /// Finds the id of the file at a path such as `reports/q1.txt`.
+    pub async fn resolve(&self, path: &str) -> Result<i64, ClientError> {
+        let path = path.trim_matches('/');
+        let (folder, name) = path.rsplit_once('/').unwrap_or(("", path));
+        self.list(folder)
+            .await?
+            .files
+            .into_iter()
+            .find(|file| file.file_name == name)
+            .map(|file| file.file_id)
+            .ok_or_else(|| ClientError::NoSuchFile(path.to_string()))
+    }
+
+    /// Streams `local` to the server, which encrypts it with `password`.
+    pub async fn upload(
+        &self,
+        local: &Path,
+        folder: &str,
+        password: &str,
+    ) -> Result<i64, ClientError> {
+        let file_name = local
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| ClientError::NoSuchFile(local.display().to_string()))?;
+        let file = tokio::fs::File::open(local).await?;
+        let len = file.metadata().await?.len();
+
+        let bar = progress_bar(Some(len));
+        let stream = ReaderStream::new(file).inspect_ok({
+            let bar = bar.clone();
+            move |chunk| bar.inc(chunk.len() as u64)
+        });
+        let form = Form::new()
+            .part(
+                "file",
+                Part::stream_with_length(reqwest::Body::wrap_stream(stream), len)
+                    .file_name(file_name),
+            )
+            .text("pwd", password.to_string())
+            .text("folder", folder.to_string());
+        let response = self
+            .request(Method::POST, "/files")
+            .multipart(form)
+            .send()
+            .await;
+        bar.finish_and_clear();
+
+        #[derive(Deserialize)]
+        struct Created {
+            file_id: i64,
+        }
+        let created: Created = check(response?).await?.json().await?;
+        Ok(created.file_id)
+    }
+
+    /// Encrypts `local` here and uploads the sealed blob, so the server
+    /// never sees the contents or the password. Chunks are sealed as the
+    /// upload reads them.
+    pub async fn upload_sealed(
+        &self,
+        local: &Path,
+        folder: &str,
+        password: &str,
+    ) -> Result<i64, ClientError> {
+        let file_name = local
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| ClientError::NoSuchFile(local.display().to_string()))?;
+        let file = tokio::fs::File::open(local).await?;
+        let len = file.metadata().await?.len();
+        let sealed_len = container::sealed_len(len, container::CHUNK_SIZE)
+            .ok_or_else(|| std::io::Error::other("file too large"))?;
+
+        let salt: String = rand::thread_rng()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        let iterations = container::DEFAULT_KDF_ITERATIONS;
+        let key = derive_key(password, &salt, iterations).await;
+        let header = container::Header::new(len);
+        let head = header.as_bytes().to_vec();
+
+        let bar = progress_bar(Some(len));
+        let chunks = futures_util::stream::try_unfold(
+            (file, 0, container::cipher(&key), header, bar.clone()),
+            |(mut file, index, cipher, header, bar)| async move {
+                if index == header.chunk_count() {
+                    return Ok(None);
+                }
+                let mut chunk = vec![0; header.chunk_len(index) as usize];
+                file.read_exact(&mut chunk).await?;
+                bar.inc(chunk.len() as u64);
+                let sealed = container::seal_chunk(&cipher, &header, index, &chunk);
+                Ok::<_, std::io::Error>(Some((sealed, (file, index + 1, cipher, header, bar))))
+            },
+        );
+        let stream = futures_util::stream::once(async { Ok(head) }).chain(chunks);
+        let form = Form::new()
+            .part(
+                "file",
+                Part::stream_with_length(reqwest::Body::wrap_stream(stream), sealed_len)
+                    .file_name(file_name),
+            )
+            .text("salt", salt)
+            .text("kdf_iterations", iterations.to_string())
+            .text("folder", folder.to_string());
+        let response = self
+            .request(Method::POST, "/files")
+            .multipart(form)
+            .send()
+            .await;
+        bar.finish_and_clear();
+
+        #[derive(Deserialize)]
+        struct Created {
+            file_id: i64,
+        }
+        let created: Created = check(response?).await?.json().await?;
+        Ok(created.file_id)
+    }
+
+    pub async fn info(&self, file_id: i64) -> Result<FileInfo, ClientError> {
+        let response = self
+            .request(Method::GET, &format!("/files/{file_id}"))
+            .send()
+            .await?;
+        Ok(check(response).await?.json().await?)
+    }
+
+    /// Has the server decrypt the file and writes it to `out`, or to stdout
+    /// for `-`.
+    pub async fn download(
+        &self,
+        file_id: i64,
+        password: &str,
+        out: &Path,
+    ) -> Result<(), ClientError> {
+        let response = self
+            .request(Method::GET, &format!("/files/{file_id}/content"))
+            .header("X-Unlock-Password", password)
+            .send()
+            .await?;
+        let response = check(response).await?;
+        let bar = progress_bar(response.content_length());
+        let mut body = response.bytes_stream();
+        let mut output = Output::create(out).await?;
+        let result = async {
+            while let Some(chunk) = body.try_next().await? {
+                output.write(&chunk).await?;
+                bar.inc(chunk.len() as u64);
+            }
+            Ok(())
+        }
+        .await;
+        bar.finish_and_clear();
+        output.finish(result).await
+    }
+
+    /// Fetches the blob of an end-to-end encrypted file and opens it here,
+    /// chunk by chunk as it arrives.
+    pub async fn download_sealed(
+        &self,
+        info: &FileInfo,
+        password: &str,
+        out: &Path,
+    ) -> Result<(), ClientError> {
+        let key = derive_key(password, &info.salt, info.kdf_iterations).await;
+        let cipher = container::cipher(&key);
+        let response = self
+            .request(Method::GET, &format!("/files/{}/blob", info.file_id))
+            .send()
+            .await?;
+        let response = check(response).await?;
+        let bar = progress_bar(response.content_length());
+        let mut body = response.bytes_stream();
+        let mut output = Output::create(out).await?;
+
+        let result = async {
+            let mut pending = Vec::new();
+            let mut header = None;
+            let mut index = 0;
+            while let Some(bytes) = body.try_next().await? {
+                bar.inc(bytes.len() as u64);
+                pending.extend_from_slice(&bytes);
+                if header.is_none() && pending.len() >= container::HEADER_LEN {
+                    header = Some(container::Header::parse(&pending).ok_or(ClientError::Decrypt)?);
+                    pending.drain(..container::HEADER_LEN);
+                }
+                let Some(header) = &header else {
+                    continue;
+                };
+                while index < header.chunk_count() {
+                    let sealed_len = (header.chunk_len(index) + container::TAG_LEN) as usize;
+                    if pending.len() < sealed_len {
+                        break;
+                    }
+                    let opened =
+                        container::open_chunk(&cipher, header, index, &pending[..sealed_len])
+                            .ok_or(ClientError::Decrypt)?;
+                    output.write(&opened).await?;
+                    pending.drain(..sealed_len);
+                    index += 1;
+                }
+            }
+            match header {
+                Some(header) if index == header.chunk_count() && pending.is_empty() => Ok(()),
+                _ => Err(ClientError::Decrypt),
+            }
+        }
+        .await;
+        bar.finish_and_clear();
+        output.finish(result).await
+    }
+
+    /// Moves a file to the trash.
+    pub async fn delete(&self, file_id: i64) -> Result<(), ClientError> {
+        let response = self
+            .request(Method::DELETE, &format!("/files/{file_id}"))
+            .send()
+            .await?;
+        check(response).await.map(drop)
+    }
+}
+
+/// Where a download goes. A file is written next to its destination first
+/// and only renamed once it is complete.
+enum Output {
+    Stdout(tokio::io::Stdout),
+    File {
+        file: tokio::fs::File,
+        partial: PathBuf,
+        out: PathBuf,
+    },
+}
+
+impl Output {
+    async fn create(out: &Path) -> Result<Self, ClientError> {
+        if out == Path::new("-") {
+            return Ok(Output::Stdout(tokio::io::stdout()));
+        }
+        let mut partial = out.as_os_str().to_owned();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+        Ok(Output::File {
+            file: tokio::fs::File::create(&partial).await?,
+            partial,
+            out: out.to_path_buf(),
+        })
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.write_all(bytes).await,
+            Output::File { file, .. } => file.write_all(bytes).await,
+        }
+    }
+
+    /// Puts the file in place if the download went well, removes what was
+    /// written so far otherwise.
+    async fn finish(self, result: Result<(), ClientError>) -> Result<(), ClientError> {
+        match self {
+            Output::Stdout(mut stdout) => {
+                result?;
+                Ok(stdout.flush().await?)
+            }
+            Output::File { file, partial, out } => {
+                let result = match result {
+                    Ok(()) => async {
+                        file.sync_all().await?;
+                        tokio::fs::rename(&partial, &out).await
+                    }
+                    .await
+                    .map_err(ClientError::from),
+                    Err(why) => Err(why),
+                };
+                if result.is_err() {
+                    let _ = tokio::fs::remove_file(&partial).await;
+                }
+                result
+            }
+        }
+    }
+}
+
+async fn derive_key(password: &str, salt: &str, iterations: u32) -> Zeroizing<[u8; 32]> {
+    let (password, salt) = (Zeroizing::new(password.to_string()), salt.to_string());
+    tokio::task::spawn_blocking(move || {
+        Zeroizing::new(container::derive_key(&password, &salt, iterations))
+    })
+    .await
+    .unwrap()
+}
+
+/// Turns an error status into [`ClientError::Api`] with the server's message.
+async fn check(response: Response) -> Result<Response, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let message = match response.json::<ErrorBody>().await {
+        Ok(body) => body.message,
+        Err(_) => status.canonical_reason().unwrap_or_default().to_string(),
+    };
+    Err(ClientError::Api { status, message })
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|cookie| cookie.split(';').next()?.trim().strip_prefix("session="))
+        .map(str::to_string)
+        .next()
+}
+
+/// Progress goes to stderr and is not drawn when that is not a terminal,
+/// so CI logs stay clean.
+fn progress_bar(len: Option<u64>) -> ProgressBar {
+    match len {
+        Some(len) => ProgressBar::new(len).with_style(
+            ProgressStyle::with_template("{bar:40} {bytes}/{total_bytes} {bytes_per_sec} {eta}")
+                .unwrap(),
+        ),
+        None => ProgressBar::new_spinner()
+            .with_style(ProgressStyle::with_template("{spinner} {bytes} {bytes_per_sec}").unwrap()),
+    }
+}
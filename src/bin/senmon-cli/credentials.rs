@@ -0,0 +1,84 @@
+//! Where the client keeps the server address and how it logs in.
+//!
+//! The file is TOML, `$XDG_CONFIG_HOME/senmon/credentials.toml` by default,
+//! and is written readable by its owner only. `SENMON_SERVER` and
+//! `SENMON_TOKEN` take precedence over it, so CI jobs need no file at all.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::client::{Auth, ClientError};
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Credentials {
+    pub server: Option<String>,
+    /// Value of the `session` cookie from `senmon-cli login`.
+    pub session: Option<String>,
+    /// Personal API token from `senmon-cli login --token`.
+    pub token: Option<String>,
+}
+
+impl Credentials {
+    /// Default location of the credentials file.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("senmon").join("credentials.toml"))
+    }
+
+    /// Reads `path` if it exists, then applies the environment overrides.
+    pub fn load(path: Option<&Path>) -> Result<Self, ClientError> {
+        let mut credentials = match path {
+            Some(path) if path.exists() => {
+                let contents = std::fs::read_to_string(path)?;
+                toml::from_str(&contents)
+                    .map_err(|why| ClientError::Credentials(format!("{}: {why}", path.display())))?
+            }
+            _ => Credentials::default(),
+        };
+        if let Some(server) = env("SENMON_SERVER") {
+            credentials.server = Some(server);
+        }
+        if let Some(token) = env("SENMON_TOKEN") {
+            credentials.session = None;
+            credentials.token = Some(token);
+        }
+        Ok(credentials)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ClientError> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let contents =
+            toml::to_string(self).map_err(|why| ClientError::Credentials(why.to_string()))?;
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            if path.exists() {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+            }
+        }
+        options.open(path)?.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn server(&self) -> Result<&str, ClientError> {
+        self.server.as_deref().ok_or(ClientError::NotLoggedIn)
+    }
+
+    pub fn auth(&self) -> Result<Auth, ClientError> {
+        match (&self.token, &self.session) {
+            (Some(token), _) => Ok(Auth::Token(token.clone())),
+            (None, Some(session)) => Ok(Auth::Session(session.clone())),
+            (None, None) => Err(ClientError::NotLoggedIn),
+        }
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
@@ -0,0 +1,215 @@
+//! `senmon-cli`, a command line client for a senmon server, for scripts and
+//! CI jobs that push and pull files without a browser.
+
+mod client;
+/// The server's blob format, so that files can be sealed and opened here.
+#[allow(dead_code)]
+#[path = "../../container.rs"]
+mod container;
+mod credentials;
+
+use std::io::{BufRead, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+
+use client::{Auth, Client, ClientError};
+use credentials::Credentials;
+
+#[derive(Parser)]
+#[command(name = "senmon-cli", version, about = "Command line client for senmon")]
+struct Cli {
+    /// Credentials file to use instead of `SENMON_CREDENTIALS` or the one in
+    /// the user's config directory.
+    #[arg(long, global = true)]
+    credentials: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Log in and remember the server and credentials.
+    Login {
+        /// Base URL of the server, e.g. `https://files.example.com`.
+        #[arg(long)]
+        server: String,
+        /// Log in with a password as this user. The password is prompted
+        /// for, or read from stdin.
+        #[arg(long, conflicts_with = "token", required_unless_present = "token")]
+        user: Option<String>,
+        /// Log in with a personal API token, prompted for or read from stdin.
+        #[arg(long)]
+        token: bool,
+    },
+    /// Forget the stored credentials.
+    Logout,
+    /// Upload a file. The file password is taken from
+    /// `SENMON_FILE_PASSWORD`, prompted for, or read from stdin.
+    Upload {
+        file: PathBuf,
+        /// Folder to upload into, the root folder by default.
+        #[arg(long, default_value = "")]
+        folder: String,
+        /// Encrypt here, end to end. The server never sees the contents or
+        /// the password and will refuse to decrypt the file.
+        #[arg(long)]
+        e2e: bool,
+    },
+    /// Download and decrypt a file, e.g. `reports/q1.txt`. End-to-end
+    /// encrypted files are decrypted here.
+    Download {
+        path: String,
+        /// Where to write the file, `-` for stdout. Defaults to the file
+        /// name in the current directory.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// List a folder, the root folder by default.
+    List {
+        #[arg(default_value = "")]
+        path: String,
+    },
+    /// Move a file to the trash.
+    Delete { path: String },
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let path = cli
+        .credentials
+        .or_else(|| std::env::var_os("SENMON_CREDENTIALS").map(PathBuf::from))
+        .or_else(Credentials::default_path);
+    match run(cli.command, path.as_deref()).await {
+        Ok(code) => code,
+        Err(why) => {
+            eprintln!("{why}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(command: Command, path: Option<&Path>) -> Result<ExitCode, ClientError> {
+    let credentials = Credentials::load(path)?;
+    match command {
+        Command::Login {
+            server,
+            user,
+            token,
+        } => {
+            let path = path.ok_or_else(|| {
+                ClientError::Credentials("no config directory, pass --credentials".to_string())
+            })?;
+            let mut stored = Credentials {
+                server: Some(server.clone()),
+                ..Credentials::default()
+            };
+            if token {
+                let Some(token) = read_secret("token: ", false) else {
+                    return Ok(ExitCode::FAILURE);
+                };
+                Client::new(&server, Auth::Token(token.clone()))
+                    .check_auth()
+                    .await?;
+                stored.token = Some(token);
+            } else if let Some(user) = user {
+                let Some(password) = read_secret("password: ", false) else {
+                    return Ok(ExitCode::FAILURE);
+                };
+                stored.session = Some(Client::login(&server, &user, &password).await?);
+            }
+            stored.save(path)?;
+            eprintln!("logged in to {server}");
+        }
+        Command::Logout => {
+            if let Some(path) = path.filter(|path| path.exists()) {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Command::Upload { file, folder, e2e } => {
+            let client = client(&credentials)?;
+            let Some(password) = file_password(true) else {
+                return Ok(ExitCode::FAILURE);
+            };
+            if e2e {
+                client.upload_sealed(&file, &folder, &password).await?;
+            } else {
+                client.upload(&file, &folder, &password).await?;
+            }
+            let name = file.file_name().unwrap_or_default().to_string_lossy();
+            eprintln!("uploaded {}", remote_path(&folder, &name));
+        }
+        Command::Download { path, output } => {
+            let client = client(&credentials)?;
+            let file_id = client.resolve(&path).await?;
+            let output =
+                output.unwrap_or_else(|| PathBuf::from(path.rsplit('/').next().unwrap_or(&path)));
+            let Some(password) = file_password(false) else {
+                return Ok(ExitCode::FAILURE);
+            };
+            let info = client.info(file_id).await?;
+            if info.end_to_end {
+                client.download_sealed(&info, &password, &output).await?;
+            } else {
+                client.download(file_id, &password, &output).await?;
+            }
+        }
+        Command::List { path } => {
+            let folder = client(&credentials)?.list(&path).await?;
+            for name in folder.folders {
+                println!("{name}/");
+            }
+            for file in folder.files {
+                println!("{}", file.file_name);
+            }
+        }
+        Command::Delete { path } => {
+            let client = client(&credentials)?;
+            let file_id = client.resolve(&path).await?;
+            client.delete(file_id).await?;
+            eprintln!("moved {path} to the trash");
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn client(credentials: &Credentials) -> Result<Client, ClientError> {
+    Ok(Client::new(credentials.server()?, credentials.auth()?))
+}
+
+fn remote_path(folder: &str, name: &str) -> String {
+    match folder.trim_matches('/') {
+        "" => name.to_string(),
+        folder => format!("{folder}/{name}"),
+    }
+}
+
+fn file_password(confirm: bool) -> Option<String> {
+    match std::env::var("SENMON_FILE_PASSWORD") {
+        Ok(password) if !password.is_empty() => Some(password),
+        _ => read_secret("file password: ", confirm),
+    }
+}
+
+/// Prompts on a terminal, otherwise reads one line from stdin.
+fn read_secret(prompt: &str, confirm: bool) -> Option<String> {
+    let secret = if std::io::stdin().is_terminal() {
+        let secret = rpassword::prompt_password(prompt).ok()?;
+        if confirm && rpassword::prompt_password(format!("repeat {prompt}")).ok()? != secret {
+            eprintln!("they do not match");
+            return None;
+        }
+        secret
+    } else {
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line).ok()?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    };
+    if secret.is_empty() {
+        eprintln!("{} must not be empty", prompt.trim_end_matches(": "));
+        return None;
+    }
+    Some(secret)
+}
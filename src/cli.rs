@@ -0,0 +1,251 @@
+//! Command line of the `senmon` binary. Without a subcommand it serves, the
+//! other subcommands work on the configured database and stash directly and
+//! can be run next to a live server.
+
+use std::io::{BufRead, IsTerminal};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::config::Config;
+use crate::db::{self, DatabaseConnection};
+use crate::{gc, scrub};
+
+#[derive(Parser)]
+#[command(name = "senmon", version, about = "Encrypted file storage server")]
+pub struct Cli {
+    /// Config file to read instead of `SENMON_CONFIG` or `./senmon.toml`.
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the server (the default).
+    Serve,
+    /// Manage accounts.
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Apply pending schema migrations.
+    Migrate {
+        /// Only report the migrations that would be applied.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Purge expired trash and sessions and remove unreferenced blobs.
+    Gc(GcArgs),
+    /// Check every blob against its recorded checksum once.
+    Scrub,
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Create an account. The password is prompted for, or read from stdin.
+    Add {
+        name: String,
+        #[arg(long)]
+        admin: bool,
+    },
+    /// Delete an account.
+    Remove {
+        name: String,
+        /// Also delete the user's files, trash and folders.
+        #[arg(long)]
+        delete_files: bool,
+    },
+    /// Set a new login password and end the user's sessions.
+    ResetPassword { name: String },
+    /// List all accounts.
+    List,
+}
+
+#[derive(Args)]
+pub struct GcArgs {
+    /// Only report what would be removed.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+pub async fn user(db: &DatabaseConnection, config: &Config, command: UserCommand) -> ExitCode {
+    match command {
+        UserCommand::Add { name, admin } => {
+            if db::is_present(db, &name).await {
+                eprintln!("user {name} already exists");
+                return ExitCode::FAILURE;
+            }
+            let Some(password) = read_password() else {
+                return ExitCode::FAILURE;
+            };
+            if let Some(why) = db::add_user(db, &name, &password).await {
+                eprintln!("{why}");
+                return ExitCode::FAILURE;
+            }
+            if admin {
+                let result = match db::get_user_id(db, &name).await {
+                    Ok(user_id) => db::set_admin(db, user_id).await,
+                    Err(why) => Err(why),
+                };
+                if let Err(why) = result {
+                    eprintln!("{why}");
+                    return ExitCode::FAILURE;
+                }
+            }
+            println!("added user {name}");
+        }
+        UserCommand::Remove { name, delete_files } => {
+            let Some(user_id) = lookup(db, &name).await else {
+                return ExitCode::FAILURE;
+            };
+            if !delete_files {
+                let owns_files = match db::list_users(db).await {
+                    Ok(users) => users
+                        .iter()
+                        .any(|u| u.user_id == user_id && u.files + u.trashed > 0),
+                    Err(why) => {
+                        eprintln!("{why}");
+                        return ExitCode::FAILURE;
+                    }
+                };
+                if owns_files {
+                    eprintln!("user {name} still owns files or has files in the trash, pass --delete-files to remove them too");
+                    return ExitCode::FAILURE;
+                }
+            }
+            let blobs = match db::delete_user(db, user_id).await {
+                Ok(blobs) => blobs,
+                Err(why) => {
+                    eprintln!("{why}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            for blob in &blobs {
+                let path = config.blob_path(blob);
+                if let Err(why) = std::fs::remove_file(&path) {
+                    eprintln!("{}: {why}", path.display());
+                }
+            }
+            println!("removed user {name} and {} blob(s)", blobs.len());
+        }
+        UserCommand::ResetPassword { name } => {
+            let Some(user_id) = lookup(db, &name).await else {
+                return ExitCode::FAILURE;
+            };
+            let Some(password) = read_password() else {
+                return ExitCode::FAILURE;
+            };
+            if let Err(why) = db::set_password(db, user_id, &password).await {
+                eprintln!("{why}");
+                return ExitCode::FAILURE;
+            }
+            println!("password of {name} reset, existing sessions ended");
+        }
+        UserCommand::List => match db::list_users(db).await {
+            Ok(users) => {
+                for user in users {
+                    println!(
+                        "{}\t{}\t{}\t{} file(s)\t{} in trash",
+                        user.user_id,
+                        user.user_name,
+                        if user.is_admin { "admin" } else { "user" },
+                        user.files,
+                        user.trashed
+                    );
+                }
+            }
+            Err(why) => {
+                eprintln!("{why}");
+                return ExitCode::FAILURE;
+            }
+        },
+    }
+    ExitCode::SUCCESS
+}
+
+pub async fn gc(db: &DatabaseConnection, config: Arc<Config>, args: GcArgs) -> ExitCode {
+    let report = match gc::collect(db, config, args.dry_run).await {
+        Ok(report) => report,
+        Err(why) => {
+            eprintln!("{why}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let verb = if args.dry_run { "would remove" } else { "removed" };
+    for path in &report.orphan_blobs {
+        println!("{verb} unreferenced blob {}", path.display());
+    }
+    println!("{verb} {} expired trash entries", report.expired_trash);
+    println!("{verb} {} unreferenced blob(s)", report.orphan_blobs.len());
+    println!("{verb} {} stale health record(s)", report.stale_health);
+    println!("{verb} {} expired session(s)", report.expired_sessions);
+    println!("{verb} {} expired API token(s)", report.expired_tokens);
+    ExitCode::SUCCESS
+}
+
+pub async fn scrub(db: &DatabaseConnection, config: &Config) -> ExitCode {
+    let report = match scrub::scrub_once(db, config).await {
+        Ok(report) => report,
+        Err(why) => {
+            eprintln!("{why}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if report.unhealthy.is_empty() {
+        println!("all blobs are healthy");
+    }
+    for entry in report.unhealthy {
+        println!(
+            "{}\t{}\t{}\t{}",
+            entry.status.as_str(),
+            entry.file_owner,
+            entry.file_id,
+            entry.blob
+        );
+    }
+    if report.unbound > 0 {
+        println!(
+            "{} blob(s) are not bound to their file yet, they are rewritten when next opened",
+            report.unbound
+        );
+    }
+    ExitCode::SUCCESS
+}
+
+async fn lookup(db: &DatabaseConnection, name: &str) -> Option<u32> {
+    match db::get_user_id(db, name).await {
+        Ok(user_id) => Some(user_id),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            eprintln!("no such user: {name}");
+            None
+        }
+        Err(why) => {
+            eprintln!("{why}");
+            None
+        }
+    }
+}
+
+/// Prompts twice on a terminal, otherwise takes the first line of stdin so
+/// that scripts can pipe the password in.
+fn read_password() -> Option<String> {
+    let password = if std::io::stdin().is_terminal() {
+        let password = rpassword::prompt_password("password: ").ok()?;
+        if rpassword::prompt_password("repeat password: ").ok()? != password {
+            eprintln!("passwords do not match");
+            return None;
+        }
+        password
+    } else {
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line).ok()?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    };
+    if password.is_empty() {
+        eprintln!("password must not be empty");
+        return None;
+    }
+    Some(password)
+}
@@ -0,0 +1,349 @@
+//! Server settings. They are read from a TOML file, `./senmon.toml` or the
+//! file named by `--config` or `SENMON_CONFIG`, and every key can be overridden with an
+//! environment variable of the same name in upper case with a `SENMON_`
+//! prefix, e.g. `SENMON_LISTEN=127.0.0.1:8080`. Missing keys keep their
+//! defaults, which match how the server behaved before it was configurable.
+//!
+//! ```toml
+//! listen = "0.0.0.0:42069"
+//! database = "./file_storage.db"
+//! assets_dir = "./assets"
+//! stash_dir = "./stash"
+//! master_key_file = "./senmon.key"
+//! session_lifetime_minutes = 60
+//! kdf_iterations = 600000
+//! accept_legacy_blobs = true
+//! trash_retention_days = 30
+//! shutdown_grace_seconds = 5
+//! shutdown_timeout_seconds = 30
+//! log_format = "text" # or "json"
+//! log_level = "info"  # tracing-subscriber filter directives
+//!
+//! # Optional, serve HTTPS on `listen` and redirect plain HTTP to it.
+//! tls_cert = "./tls/fullchain.pem"
+//! tls_key = "./tls/key.pem"
+//! redirect_listen = "0.0.0.0:80"
+//! ```
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+const DEFAULT_CONFIG_PATH: &str = "./senmon.toml";
+/// OWASP's 2023 recommendation for PBKDF2-HMAC-SHA512 is 210 000, anything
+/// far below that is a misconfiguration rather than a trade-off.
+const MIN_KDF_ITERATIONS: u32 = 100_000;
+/// Upper bounds keep the `chrono` arithmetic on these settings from
+/// overflowing, which panics, and are far beyond any sensible value.
+const MAX_SESSION_LIFETIME_MINUTES: i64 = 366 * 24 * 60;
+const MAX_TRASH_RETENTION_DAYS: i64 = 10 * 366;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub database: PathBuf,
+    pub assets_dir: PathBuf,
+    /// Where encrypted blobs live. `file_state.blob` is relative to it.
+    pub stash_dir: PathBuf,
+    /// Key that file and folder names are encrypted under, created on first
+    /// start. Without it no name can be read, so back it up, and keep it off
+    /// the disk that holds the database where possible.
+    pub master_key_file: PathBuf,
+    pub session_lifetime_minutes: i64,
+    /// PBKDF2 cost for files uploaded from now on. Every file records the
+    /// cost it was stored with, so changing this never locks anyone out.
+    pub kdf_iterations: u32,
+    /// Whether files stored before blobs were bound to their owner and file
+    /// id still open. They are rewritten in the bound format the first time
+    /// they are opened; `senmon scrub` counts the ones left. Turn this off
+    /// once none are, so that blobs swapped on disk can not be passed off as
+    /// one another.
+    pub accept_legacy_blobs: bool,
+    pub trash_retention_days: i64,
+    /// How long SIGTERM keeps accepting connections with `/readyz` failing
+    /// before it starts draining.
+    pub shutdown_grace_seconds: u64,
+    /// How long SIGTERM or SIGINT waits for in-flight requests, uploads
+    /// included, before dropping them.
+    pub shutdown_timeout_seconds: u64,
+    pub log_format: LogFormat,
+    /// Filter in `RUST_LOG` syntax, e.g. `info,senmon=debug`.
+    pub log_level: String,
+    /// PEM certificate chain. With `tls_key` set too the server speaks
+    /// HTTPS only; both are reread on SIGHUP and when they change.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Where to answer plain HTTP with a redirect to HTTPS, if anywhere.
+    pub redirect_listen: Option<SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: SocketAddr::from(([0, 0, 0, 0], 42069)),
+            database: PathBuf::from("./file_storage.db"),
+            assets_dir: PathBuf::from("./assets"),
+            stash_dir: PathBuf::from("./stash"),
+            master_key_file: PathBuf::from("./senmon.key"),
+            session_lifetime_minutes: 60,
+            kdf_iterations: crate::container::DEFAULT_KDF_ITERATIONS,
+            accept_legacy_blobs: true,
+            trash_retention_days: 30,
+            shutdown_grace_seconds: 5,
+            shutdown_timeout_seconds: 30,
+            log_format: LogFormat::Text,
+            log_level: "info".to_string(),
+            tls_cert: None,
+            tls_key: None,
+            redirect_listen: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, for log shippers.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env(&'static str, String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, why) => write!(f, "{}: {why}", path.display()),
+            ConfigError::Parse(path, why) => write!(f, "{}: {why}", path.display()),
+            ConfigError::Env(name, value) => write!(f, "{name}: invalid value {value:?}"),
+            ConfigError::Invalid(why) => write!(f, "invalid configuration: {why}"),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file if there is one, applies the environment and
+    /// validates the result. An explicit `path`, from `--config`, wins over
+    /// `SENMON_CONFIG`.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let (path, required) = match path.map(PathBuf::from).or_else(|| std::env::var_os("SENMON_CONFIG").map(PathBuf::from)) {
+            Some(path) => (path, true),
+            None => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                toml::from_str(&contents).map_err(|why| ConfigError::Parse(path.clone(), why))?
+            }
+            Err(why) if why.kind() == std::io::ErrorKind::NotFound && !required => {
+                Config::default()
+            }
+            Err(why) => return Err(ConfigError::Read(path, why)),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        override_from_env("SENMON_LISTEN", &mut self.listen)?;
+        override_from_env("SENMON_DATABASE", &mut self.database)?;
+        override_from_env("SENMON_ASSETS_DIR", &mut self.assets_dir)?;
+        override_from_env("SENMON_STASH_DIR", &mut self.stash_dir)?;
+        override_from_env("SENMON_MASTER_KEY_FILE", &mut self.master_key_file)?;
+        override_from_env(
+            "SENMON_SESSION_LIFETIME_MINUTES",
+            &mut self.session_lifetime_minutes,
+        )?;
+        override_from_env("SENMON_KDF_ITERATIONS", &mut self.kdf_iterations)?;
+        override_from_env("SENMON_ACCEPT_LEGACY_BLOBS", &mut self.accept_legacy_blobs)?;
+        override_from_env("SENMON_TRASH_RETENTION_DAYS", &mut self.trash_retention_days)?;
+        override_from_env(
+            "SENMON_SHUTDOWN_GRACE_SECONDS",
+            &mut self.shutdown_grace_seconds,
+        )?;
+        override_from_env(
+            "SENMON_SHUTDOWN_TIMEOUT_SECONDS",
+            &mut self.shutdown_timeout_seconds,
+        )?;
+        override_from_env("SENMON_LOG_FORMAT", &mut self.log_format)?;
+        override_from_env("SENMON_LOG_LEVEL", &mut self.log_level)?;
+        override_optional_from_env("SENMON_TLS_CERT", &mut self.tls_cert)?;
+        override_optional_from_env("SENMON_TLS_KEY", &mut self.tls_key)?;
+        override_optional_from_env("SENMON_REDIRECT_LISTEN", &mut self.redirect_listen)?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !(1..=MAX_SESSION_LIFETIME_MINUTES).contains(&self.session_lifetime_minutes) {
+            return Err(ConfigError::Invalid(format!(
+                "session_lifetime_minutes must be between 1 and {MAX_SESSION_LIFETIME_MINUTES}"
+            )));
+        }
+        if !(1..=MAX_TRASH_RETENTION_DAYS).contains(&self.trash_retention_days) {
+            return Err(ConfigError::Invalid(format!(
+                "trash_retention_days must be between 1 and {MAX_TRASH_RETENTION_DAYS}"
+            )));
+        }
+        if self.kdf_iterations < MIN_KDF_ITERATIONS {
+            return Err(ConfigError::Invalid(format!(
+                "kdf_iterations must be at least {MIN_KDF_ITERATIONS}"
+            )));
+        }
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                for path in [cert, key] {
+                    if !path.is_file() {
+                        return Err(ConfigError::Invalid(format!(
+                            "{} does not exist",
+                            path.display()
+                        )));
+                    }
+                }
+            }
+            (None, None) if self.redirect_listen.is_some() => {
+                return Err(ConfigError::Invalid(
+                    "redirect_listen needs tls_cert and tls_key".to_string(),
+                ));
+            }
+            (None, None) => {}
+            _ => {
+                return Err(ConfigError::Invalid(
+                    "tls_cert and tls_key must be set together".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks what only serving needs: the assets to serve and a stash to
+    /// store uploads in, which is created if missing. The other commands
+    /// run without either.
+    pub fn prepare_serve(&self) -> Result<(), ConfigError> {
+        if !self.assets_dir.join("html/home.html").is_file() {
+            return Err(ConfigError::Invalid(format!(
+                "assets_dir {} does not contain html/home.html",
+                self.assets_dir.display()
+            )));
+        }
+        std::fs::create_dir_all(&self.stash_dir).map_err(|why| {
+            ConfigError::Invalid(format!("stash_dir {}: {why}", self.stash_dir.display()))
+        })?;
+        Ok(())
+    }
+
+    pub fn session_lifetime(&self) -> chrono::TimeDelta {
+        chrono::Duration::minutes(self.session_lifetime_minutes)
+    }
+
+    pub fn trash_retention(&self) -> chrono::TimeDelta {
+        chrono::Duration::days(self.trash_retention_days)
+    }
+
+    pub fn shutdown_grace(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.shutdown_grace_seconds)
+    }
+
+    pub fn shutdown_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.shutdown_timeout_seconds)
+    }
+
+    /// Path of a blob given its `file_state.blob` value.
+    pub fn blob_path(&self, blob: &str) -> PathBuf {
+        self.stash_dir.join(blob)
+    }
+
+    pub fn asset_path(&self, asset: impl AsRef<Path>) -> PathBuf {
+        self.assets_dir.join(asset)
+    }
+
+    /// The certificate and key paths when TLS is configured.
+    pub fn tls(&self) -> Option<(&Path, &Path)> {
+        Some((self.tls_cert.as_deref()?, self.tls_key.as_deref()?))
+    }
+}
+
+fn override_from_env<T: std::str::FromStr>(
+    name: &'static str,
+    value: &mut T,
+) -> Result<(), ConfigError> {
+    if let Ok(raw) = std::env::var(name) {
+        *value = raw.parse().map_err(|_| ConfigError::Env(name, raw))?;
+    }
+    Ok(())
+}
+
+/// Like [`override_from_env`] for settings that are off by default. An empty
+/// variable turns the setting off again.
+fn override_optional_from_env<T: std::str::FromStr>(
+    name: &'static str,
+    value: &mut Option<T>,
+) -> Result<(), ConfigError> {
+    match std::env::var(name) {
+        Ok(raw) if raw.is_empty() => *value = None,
+        Ok(raw) => *value = Some(raw.parse().map_err(|_| ConfigError::Env(name, raw))?),
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn lifetimes_are_bounded() {
+        for (session_lifetime_minutes, trash_retention_days) in [
+            (0, 30),
+            (MAX_SESSION_LIFETIME_MINUTES + 1, 30),
+            (i64::MAX, 30),
+            (60, 0),
+            (60, MAX_TRASH_RETENTION_DAYS + 1),
+            (60, i64::MAX),
+        ] {
+            let config = Config {
+                session_lifetime_minutes,
+                trash_retention_days,
+                ..Config::default()
+            };
+            assert!(
+                matches!(config.validate(), Err(ConfigError::Invalid(_))),
+                "{session_lifetime_minutes} {trash_retention_days}"
+            );
+        }
+        let longest = Config {
+            session_lifetime_minutes: MAX_SESSION_LIFETIME_MINUTES,
+            trash_retention_days: MAX_TRASH_RETENTION_DAYS,
+            ..Config::default()
+        };
+        assert!(longest.validate().is_ok());
+        assert!(chrono::Utc::now()
+            .checked_add_signed(longest.session_lifetime())
+            .is_some());
+    }
+}
@@ -0,0 +1,615 @@
+//! On-disk format of encrypted blobs.
+//!
+//! ```text
+//! "SNMN" | version: u8 | chunk_size: u32 BE | plaintext_len: u64 BE | nonce: [u8; 12]
+//! chunk 0 ciphertext + tag | chunk 1 ciphertext + tag | ...
+//! ```
+//!
+//! Every chunk holds `chunk_size` bytes of plaintext (the last one may be
+//! shorter) and is sealed with AES-256-GCM on its own, using the base nonce
+//! with the chunk index folded into its last eight bytes. A byte range can
+//! therefore be served by decrypting only the chunks covering it, and chunks
+//! can not be reordered, dropped or moved between blobs. There is always at
+//! least one chunk: an empty file is a single empty chunk, whose tag still
+//! proves the key and the associated data.
+//!
+//! The associated data of every chunk depends on the version:
+//!
+//! - version 2, written by the server: the header followed by the owner's
+//!   user id (u32 BE) and the file id (i64 BE). A blob only opens as the
+//!   file it was written for, so blobs or rows swapped on disk are caught;
+//! - version 1: the header alone. End-to-end encrypted files are sealed in
+//!   this version by the client (`senmon-cli`, or `assets/js/e2e.js` with
+//!   WebCrypto), which does not know the file id in advance, and the server
+//!   wrote it before version 2.
+//!
+//! Blobs written before either are hex encoded `nonce || ciphertext` of the
+//! whole file with no associated data, they are told apart by the magic.
+//! The server keeps opening both older formats while `accept_legacy_blobs`
+//! is on, and rewrites them as version 2 once they are opened.
+//!
+//! Keys are PBKDF2-HMAC-SHA512 of the file password over the file's salt.
+//! The client binary compiles this module too, which is why it has no
+//! dependencies on the rest of the server.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::num::NonZeroU32;
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{AeadCore, Aes256Gcm, KeyInit};
+
+const MAGIC: &[u8; 4] = b"SNMN";
+/// Associated data is the header alone.
+const VERSION_UNBOUND: u8 = 1;
+/// Associated data is the header and the [`Binding`].
+const VERSION_BOUND: u8 = 2;
+pub const HEADER_LEN: usize = 4 + 1 + 4 + 8 + 12;
+pub const CHUNK_SIZE: u32 = 64 * 1024;
+/// Largest chunk accepted in blobs sealed by a client.
+const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+pub const TAG_LEN: u64 = 16;
+pub const DEFAULT_KDF_ITERATIONS: u32 = 600_000;
+
+/// The file a version 2 blob belongs to.
+#[derive(Clone, Copy)]
+pub struct Binding {
+    pub owner: u32,
+    pub file_id: i64,
+}
+
+pub struct Header {
+    raw: [u8; HEADER_LEN],
+    pub chunk_size: u32,
+    pub plaintext_len: u64,
+    nonce: [u8; 12],
+    /// Associated data of every chunk.
+    aad: Vec<u8>,
+}
+
+impl Header {
+    /// A version 1 header, for blobs sealed without knowing their file. Only
+    /// clients seal such blobs.
+    #[allow(dead_code)]
+    pub fn new(plaintext_len: u64) -> Self {
+        Self::with_version(VERSION_UNBOUND, plaintext_len)
+    }
+
+    /// A version 2 header for a blob of `binding`'s file.
+    pub fn new_bound(plaintext_len: u64, binding: Binding) -> Self {
+        Self::with_version(VERSION_BOUND, plaintext_len).bind(binding)
+    }
+
+    fn with_version(version: u8, plaintext_len: u64) -> Self {
+        let nonce: [u8; 12] = Aes256Gcm::generate_nonce(aes_gcm::aead::OsRng).into();
+        let mut raw = [0u8; HEADER_LEN];
+        raw[0..4].copy_from_slice(MAGIC);
+        raw[4] = version;
+        raw[5..9].copy_from_slice(&CHUNK_SIZE.to_be_bytes());
+        raw[9..17].copy_from_slice(&plaintext_len.to_be_bytes());
+        raw[17..29].copy_from_slice(&nonce);
+        Header {
+            raw,
+            chunk_size: CHUNK_SIZE,
+            plaintext_len,
+            nonce,
+            aad: raw.to_vec(),
+        }
+    }
+
+    /// Parses a header of either version. A version 2 header opens nothing
+    /// until it is given its file with [`Header::bind`].
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let raw: [u8; HEADER_LEN] = bytes.get(..HEADER_LEN)?.try_into().ok()?;
+        if &raw[0..4] != MAGIC || !matches!(raw[4], VERSION_UNBOUND | VERSION_BOUND) {
+            return None;
+        }
+        let chunk_size = u32::from_be_bytes(raw[5..9].try_into().ok()?);
+        if chunk_size == 0 {
+            return None;
+        }
+        Some(Header {
+            raw,
+            chunk_size,
+            plaintext_len: u64::from_be_bytes(raw[9..17].try_into().ok()?),
+            nonce: raw[17..29].try_into().ok()?,
+            aad: raw.to_vec(),
+        })
+    }
+
+    /// Whether the chunks authenticate the file they belong to.
+    pub fn is_bound(&self) -> bool {
+        self.raw[4] == VERSION_BOUND
+    }
+
+    /// Names the file the blob is expected to belong to. Version 1 headers
+    /// do not authenticate it and are left as they are.
+    pub fn bind(mut self, binding: Binding) -> Self {
+        if self.is_bound() {
+            self.aad.truncate(HEADER_LEN);
+            self.aad.extend_from_slice(&binding.owner.to_be_bytes());
+            self.aad.extend_from_slice(&binding.file_id.to_be_bytes());
+        }
+        self
+    }
+
+    fn chunk_nonce(&self, index: u64) -> aes_gcm::Nonce<<Aes256Gcm as AeadCore>::NonceSize> {
+        let mut nonce = self.nonce;
+        for (byte, counter) in nonce[4..].iter_mut().zip(index.to_be_bytes()) {
+            *byte ^= counter;
+        }
+        nonce.into()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    pub fn chunk_count(&self) -> u64 {
+        self.plaintext_len.div_ceil(self.chunk_size as u64).max(1)
+    }
+
+    /// Plaintext length of chunk `index`, only the last one can be short.
+    pub fn chunk_len(&self, index: u64) -> u64 {
+        let chunk_size = self.chunk_size as u64;
+        chunk_size.min(self.plaintext_len.saturating_sub(index * chunk_size))
+    }
+}
+
+pub fn is_chunked(blob_prefix: &[u8]) -> bool {
+    blob_prefix.starts_with(MAGIC)
+}
+
+/// Length of the blob that holds `plaintext_len` bytes in chunks of
+/// `chunk_size`.
+pub fn sealed_len(plaintext_len: u64, chunk_size: u32) -> Option<u64> {
+    // Headers of client sealed uploads are untrusted, so a forged length
+    // must not wrap around to one that matches the upload.
+    plaintext_len
+        .div_ceil(chunk_size as u64)
+        .max(1)
+        .checked_mul(TAG_LEN)?
+        .checked_add(plaintext_len)?
+        .checked_add(HEADER_LEN as u64)
+}
+
+/// Parses the header of a blob sealed elsewhere and checks that the blob,
+/// `blob_len` bytes in all, is as long as the header says. Nothing is
+/// decrypted. Clients seal before the file has an id, so only version 1 is
+/// accepted.
+pub fn check_sealed(header: &[u8], blob_len: u64) -> Option<Header> {
+    let header = Header::parse(header)?;
+    (!header.is_bound()
+        && header.chunk_size <= MAX_CHUNK_SIZE
+        && sealed_len(header.plaintext_len, header.chunk_size) == Some(blob_len))
+    .then_some(header)
+}
+
+pub fn derive_key(password: &str, salt: &str, iterations: u32) -> [u8; 32] {
+    let mut key: [u8; 32] = [0; 32];
+    ring::pbkdf2::derive(
+        ring::pbkdf2::PBKDF2_HMAC_SHA512,
+        NonZeroU32::new(iterations).unwrap_or(NonZeroU32::MIN),
+        salt.as_bytes(),
+        password.as_bytes(),
+        &mut key,
+    );
+    key
+}
+
+pub fn cipher(key: &[u8; 32]) -> Aes256Gcm {
+    Aes256Gcm::new(aes_gcm::Key::<Aes256Gcm>::from_slice(key))
+}
+
+/// Seals a whole file as a version 2 blob of `binding`'s file.
+pub fn encrypt(key: &[u8; 32], binding: Binding, plaintext: &[u8]) -> Vec<u8> {
+    let len = plaintext.len() as u64;
+    let mut blob = Vec::with_capacity(sealed_len(len, CHUNK_SIZE).unwrap_or_default() as usize);
+    encrypt_stream(key, binding, len, &mut &plaintext[..], &mut blob)
+        .expect("sealing from and to memory can not fail");
+    blob
+}
+
+/// Seals the `plaintext_len` bytes read from `plaintext` as a version 2
+/// blob of `binding`'s file, holding one chunk in memory at a time.
+pub fn encrypt_stream<R: Read, W: Write>(
+    key: &[u8; 32],
+    binding: Binding,
+    plaintext_len: u64,
+    plaintext: &mut R,
+    blob: &mut W,
+) -> std::io::Result<()> {
+    let cipher = cipher(key);
+    let header = Header::new_bound(plaintext_len, binding);
+    blob.write_all(&header.raw)?;
+    let mut buffer = vec![0u8; CHUNK_SIZE as usize];
+    for index in 0..header.chunk_count() {
+        let chunk = &mut buffer[..header.chunk_len(index) as usize];
+        plaintext.read_exact(chunk)?;
+        blob.write_all(&seal_chunk(&cipher, &header, index, chunk))?;
+    }
+    Ok(())
+}
+
+/// Seals chunk `index` of a blob, for writers that stream.
+pub fn seal_chunk(cipher: &Aes256Gcm, header: &Header, index: u64, chunk: &[u8]) -> Vec<u8> {
+    let payload = Payload {
+        msg: chunk,
+        aad: &header.aad,
+    };
+    cipher
+        .encrypt(&header.chunk_nonce(index), payload)
+        .expect("AES-GCM encryption of a bounded chunk can not fail")
+}
+
+/// Opens chunk `index` of a blob, for readers that stream.
+pub fn open_chunk(
+    cipher: &Aes256Gcm,
+    header: &Header,
+    index: u64,
+    sealed: &[u8],
+) -> Option<Vec<u8>> {
+    let payload = Payload {
+        msg: sealed,
+        aad: &header.aad,
+    };
+    cipher.decrypt(&header.chunk_nonce(index), payload).ok()
+}
+
+/// Decrypts plaintext bytes `start..=end` of a chunked blob, reading and
+/// opening only the chunks that cover them.
+pub fn decrypt_range<R: Read + Seek>(
+    key: &[u8; 32],
+    header: &Header,
+    blob: &mut R,
+    start: u64,
+    end: u64,
+) -> Option<Vec<u8>> {
+    if start > end || end >= header.plaintext_len {
+        return None;
+    }
+    let cipher = cipher(key);
+    let chunk_size = header.chunk_size as u64;
+    let first = start / chunk_size;
+    let last = end / chunk_size;
+
+    let mut plaintext = Vec::new();
+    for index in first..=last {
+        let chunk_len = header.chunk_len(index);
+        let opened = read_chunk(&cipher, header, blob, index)?;
+        if index == first {
+            // The header, and so the range it allowed, is only authentic
+            // once a chunk opened.
+            plaintext.reserve_exact((end - start + 1) as usize);
+        }
+
+        let from = if index == first {
+            start - index * chunk_size
+        } else {
+            0
+        };
+        let to = if index == last {
+            end - index * chunk_size + 1
+        } else {
+            chunk_len
+        };
+        plaintext.extend_from_slice(&opened[from as usize..to as usize]);
+    }
+    Some(plaintext)
+}
+
+/// Reads chunk `index` of a blob and opens it.
+fn read_chunk<R: Read + Seek>(
+    cipher: &Aes256Gcm,
+    header: &Header,
+    blob: &mut R,
+    index: u64,
+) -> Option<Vec<u8>> {
+    let offset = index
+        .checked_mul(header.chunk_size as u64 + TAG_LEN)?
+        .checked_add(HEADER_LEN as u64)?;
+    let sealed_len = header.chunk_len(index) + TAG_LEN;
+    // Lengths come from a header that is not authenticated yet, so they are
+    // checked against the blob before anything is allocated for them.
+    let blob_len = blob.seek(SeekFrom::End(0)).ok()?;
+    if offset.checked_add(sealed_len)? > blob_len {
+        return None;
+    }
+    let mut sealed = vec![0u8; sealed_len as usize];
+    blob.seek(SeekFrom::Start(offset)).ok()?;
+    blob.read_exact(&mut sealed).ok()?;
+    open_chunk(cipher, header, index, &sealed)
+}
+
+/// Opens the first chunk, which every blob has, so that `key` and the
+/// binding are checked even for an empty file.
+pub fn opens<R: Read + Seek>(key: &[u8; 32], header: &Header, blob: &mut R) -> bool {
+    read_chunk(&cipher(key), header, blob, 0).is_some()
+}
+
+/// Checks that `key` opens the blob as `binding`'s file, decrypting as
+/// little as possible.
+pub fn verify_key<R: Read + Seek>(key: &[u8; 32], binding: Binding, blob: &mut R) -> bool {
+    let mut raw = [0u8; HEADER_LEN];
+    let header = blob
+        .read_exact(&mut raw)
+        .ok()
+        .and_then(|_| Header::parse(&raw))
+        .map(|header| header.bind(binding));
+    match header {
+        Some(header) => opens(key, &header, blob),
+        None => {
+            let mut contents = Vec::new();
+            blob.seek(SeekFrom::Start(0)).is_ok()
+                && blob.read_to_end(&mut contents).is_ok()
+                && decrypt_legacy(key, &contents).is_some()
+        }
+    }
+}
+
+/// Decrypts a whole blob of any version.
+pub fn decrypt(key: &[u8; 32], binding: Binding, blob: &[u8]) -> Option<Vec<u8>> {
+    match Header::parse(blob) {
+        Some(header) => {
+            let header = header.bind(binding);
+            let mut blob = std::io::Cursor::new(blob);
+            match header.plaintext_len {
+                0 => opens(key, &header, &mut blob).then(Vec::new),
+                len => decrypt_range(key, &header, &mut blob, 0, len - 1),
+            }
+        }
+        None => decrypt_legacy(key, blob),
+    }
+}
+
+/// Decrypts a blob in the original hex encoded `nonce || ciphertext` format.
+pub fn decrypt_legacy(key: &[u8; 32], blob: &[u8]) -> Option<Vec<u8>> {
+    let bytes = hex::decode(blob).ok()?;
+    if bytes.len() < 12 {
+        return None;
+    }
+    let (nonce, ciphertext) = bytes.split_at(12);
+    cipher(key)
+        .decrypt(aes_gcm::Nonce::from_slice(nonce), ciphertext)
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    /// Seals `plaintext` the way clients do, as a version 1 blob.
+    fn seal_unbound(key: &[u8; 32], plaintext: &[u8]) -> Vec<u8> {
+        let cipher = cipher(key);
+        let header = Header::new(plaintext.len() as u64);
+        let mut blob = header.as_bytes().to_vec();
+        for index in 0..header.chunk_count() {
+            let start = (index * CHUNK_SIZE as u64) as usize;
+            let chunk = &plaintext[start..start + header.chunk_len(index) as usize];
+            blob.extend(seal_chunk(&cipher, &header, index, chunk));
+        }
+        blob
+    }
+
+    fn open_all(key: &[u8; 32], blob: &[u8]) -> Option<Vec<u8>> {
+        let header = Header::parse(blob)?;
+        decrypt_range(
+            key,
+            &header,
+            &mut Cursor::new(blob),
+            0,
+            header.plaintext_len - 1,
+        )
+    }
+
+    fn plaintext() -> Vec<u8> {
+        (0..3 * CHUNK_SIZE as usize + 100)
+            .map(|i| i as u8)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let plaintext = plaintext();
+        let blob = seal_unbound(&KEY, &plaintext);
+        assert_eq!(
+            blob.len() as u64,
+            sealed_len(plaintext.len() as u64, CHUNK_SIZE).unwrap()
+        );
+        assert!(check_sealed(&blob, blob.len() as u64).is_some());
+        assert_eq!(open_all(&KEY, &blob).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn ranges_across_chunks() {
+        let plaintext = plaintext();
+        let blob = seal_unbound(&KEY, &plaintext);
+        let header = Header::parse(&blob).unwrap();
+        let (start, end) = (CHUNK_SIZE as u64 - 3, 2 * CHUNK_SIZE as u64 + 5);
+        let range = decrypt_range(&KEY, &header, &mut Cursor::new(&blob), start, end).unwrap();
+        assert_eq!(range, plaintext[start as usize..=end as usize]);
+        assert!(decrypt_range(
+            &KEY,
+            &header,
+            &mut Cursor::new(&blob),
+            0,
+            header.plaintext_len
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn wrong_key() {
+        let blob = seal_unbound(&KEY, &plaintext());
+        assert!(open_all(&[8; 32], &blob).is_none());
+        assert!(!verify_key(
+            &[8; 32],
+            Binding {
+                owner: 1,
+                file_id: 1
+            },
+            &mut Cursor::new(&blob)
+        ));
+        assert!(verify_key(
+            &KEY,
+            Binding {
+                owner: 1,
+                file_id: 1
+            },
+            &mut Cursor::new(&blob)
+        ));
+    }
+
+    #[test]
+    fn tampered_chunk() {
+        let mut blob = seal_unbound(&KEY, &plaintext());
+        blob[HEADER_LEN + 10] ^= 1;
+        assert!(open_all(&KEY, &blob).is_none());
+    }
+
+    #[test]
+    fn tampered_header() {
+        let mut blob = seal_unbound(&KEY, &plaintext());
+        // Claim one byte less, the chunks were sealed under the old header.
+        let len = u64::from_be_bytes(blob[9..17].try_into().unwrap());
+        blob[9..17].copy_from_slice(&(len - 1).to_be_bytes());
+        assert!(open_all(&KEY, &blob).is_none());
+    }
+
+    #[test]
+    fn reordered_chunks() {
+        let mut blob = seal_unbound(&KEY, &plaintext());
+        let sealed_chunk = (CHUNK_SIZE as u64 + TAG_LEN) as usize;
+        let (first, second) =
+            blob[HEADER_LEN..HEADER_LEN + 2 * sealed_chunk].split_at_mut(sealed_chunk);
+        first.swap_with_slice(second);
+        assert!(open_all(&KEY, &blob).is_none());
+    }
+
+    #[test]
+    fn chunks_of_another_blob() {
+        let plaintext = plaintext();
+        let mut blob = seal_unbound(&KEY, &plaintext);
+        let other = seal_unbound(&KEY, &plaintext);
+        blob[HEADER_LEN..].copy_from_slice(&other[HEADER_LEN..]);
+        assert!(open_all(&KEY, &blob).is_none());
+    }
+
+    #[test]
+    fn truncated() {
+        let blob = seal_unbound(&KEY, &plaintext());
+        let truncated = &blob[..blob.len() - 1];
+        assert!(check_sealed(truncated, truncated.len() as u64).is_none());
+        assert!(open_all(&KEY, truncated).is_none());
+        // Whole chunks missing from the end are caught by the length too.
+        let sealed_chunk = (CHUNK_SIZE as u64 + TAG_LEN) as usize;
+        let short = &blob[..HEADER_LEN + sealed_chunk];
+        assert!(check_sealed(short, short.len() as u64).is_none());
+        assert!(open_all(&KEY, short).is_none());
+        assert!(Header::parse(&blob[..HEADER_LEN - 1]).is_none());
+    }
+
+    #[test]
+    fn legacy_blobs() {
+        let cipher = cipher(&KEY);
+        let nonce = Aes256Gcm::generate_nonce(aes_gcm::aead::OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(cipher.encrypt(&nonce, b"hello".as_slice()).unwrap());
+        let blob = hex::encode(sealed).into_bytes();
+        assert!(!is_chunked(&blob));
+        assert_eq!(decrypt_legacy(&KEY, &blob).unwrap(), b"hello");
+        assert!(decrypt_legacy(&[8; 32], &blob).is_none());
+    }
+
+    #[test]
+    fn forged_lengths() {
+        // 2^63 + 80 bytes in chunks of 16 take 2^64 + 189 bytes sealed, which
+        // wraps around to the 189 bytes actually sent.
+        let forged_len = (1 << 63) + 80;
+        let mut header = Header::new(0).as_bytes().to_vec();
+        header[5..9].copy_from_slice(&16u32.to_be_bytes());
+        header[9..17].copy_from_slice(&u64::to_be_bytes(forged_len));
+        assert_eq!(Header::parse(&header).unwrap().plaintext_len, forged_len);
+        assert!(sealed_len(forged_len, 16).is_none());
+        assert!(check_sealed(&header, 189).is_none());
+        assert!(sealed_len(u64::MAX, 1).is_none());
+    }
+
+    #[test]
+    fn forged_headers_allocate_nothing() {
+        let mut blob = seal_unbound(&KEY, b"hello");
+        blob[5..9].copy_from_slice(&u32::MAX.to_be_bytes());
+        blob[9..17].copy_from_slice(&u64::MAX.to_be_bytes());
+        let header = Header::parse(&blob).unwrap();
+        let end = header.plaintext_len - 1;
+        assert!(decrypt_range(&KEY, &header, &mut Cursor::new(&blob), 0, end).is_none());
+        assert!(decrypt_range(&KEY, &header, &mut Cursor::new(&blob), end, end).is_none());
+        assert!(!verify_key(&KEY, FILE, &mut Cursor::new(&blob)));
+    }
+
+    const FILE: Binding = Binding {
+        owner: 3,
+        file_id: 42,
+    };
+
+    #[test]
+    fn bound_round_trip() {
+        let plaintext = plaintext();
+        let blob = encrypt(&KEY, FILE, &plaintext);
+        assert!(Header::parse(&blob).unwrap().is_bound());
+        assert_eq!(decrypt(&KEY, FILE, &blob).unwrap(), plaintext);
+        assert!(verify_key(&KEY, FILE, &mut Cursor::new(&blob)));
+    }
+
+    #[test]
+    fn bound_to_another_file() {
+        let blob = encrypt(&KEY, FILE, &plaintext());
+        let other_owner = Binding { owner: 4, ..FILE };
+        let other_file = Binding {
+            file_id: 43,
+            ..FILE
+        };
+        assert!(decrypt(&KEY, other_owner, &blob).is_none());
+        assert!(decrypt(&KEY, other_file, &blob).is_none());
+        assert!(!verify_key(&KEY, other_file, &mut Cursor::new(&blob)));
+        // The header alone does not carry the binding.
+        assert!(open_all(&KEY, &blob).is_none());
+    }
+
+    #[test]
+    fn unbound_blobs_ignore_the_binding() {
+        let plaintext = plaintext();
+        let blob = seal_unbound(&KEY, &plaintext);
+        assert_eq!(decrypt(&KEY, FILE, &blob).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn clients_can_not_upload_bound_blobs() {
+        let blob = encrypt(&KEY, FILE, &plaintext());
+        assert!(check_sealed(&blob, blob.len() as u64).is_none());
+    }
+
+    #[test]
+    fn empty_files_are_one_empty_chunk() {
+        let blob = encrypt(&KEY, FILE, b"");
+        assert_eq!(blob.len(), HEADER_LEN + TAG_LEN as usize);
+        assert_eq!(decrypt(&KEY, FILE, &blob).unwrap(), b"");
+        assert!(verify_key(&KEY, FILE, &mut Cursor::new(&blob)));
+
+        let other_file = Binding {
+            file_id: 43,
+            ..FILE
+        };
+        assert!(decrypt(&[8; 32], FILE, &blob).is_none());
+        assert!(decrypt(&KEY, other_file, &blob).is_none());
+        assert!(!verify_key(&[8; 32], FILE, &mut Cursor::new(&blob)));
+        assert!(!verify_key(&KEY, other_file, &mut Cursor::new(&blob)));
+        // Without its chunk the header proves nothing.
+        assert!(decrypt(&KEY, FILE, &blob[..HEADER_LEN]).is_none());
+        let unbound = seal_unbound(&KEY, b"");
+        assert!(check_sealed(&unbound[..HEADER_LEN], HEADER_LEN as u64).is_none());
+        assert!(check_sealed(&unbound, unbound.len() as u64).is_some());
+    }
+}
@@ -1,106 +1,173 @@
-use std::ops::Deref;
-use std::sync::Arc;
-use std::sync::Mutex;
+use std::path::Path;
+use std::time::Duration;
 
+use chrono::Utc;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::TransactionBehavior;
+
+use crate::metrics::{self, METRICS};
+use crate::scrub::{BlobStatus, FileHealth};
 use crate::session::*;
 
+const POOL_SIZE: u32 = 8;
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A pool of SQLite connections in WAL mode, so readers never wait on a
+/// writer. Queries run on tokio's blocking thread pool through [`run`].
+///
+/// [`run`]: DatabaseConnection::run
 #[derive(Clone)]
 pub struct DatabaseConnection {
-    pub ctx: Arc<Mutex<rusqlite::Connection>>,
+    pool: r2d2::Pool<SqliteConnectionManager>,
+}
+
+/// No connection could be taken from the pool in time.
+#[derive(Debug)]
+pub struct PoolError(r2d2::Error);
+
+impl From<PoolError> for rusqlite::Error {
+    fn from(why: PoolError) -> Self {
+        rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            Some(why.0.to_string()),
+        )
+    }
+}
+
+impl std::fmt::Display for PoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
 }
 
 impl DatabaseConnection {
-    pub fn new(ctx: rusqlite::Connection) -> Self {
-        Self {
-            ctx: Arc::new(Mutex::new(ctx)),
-        }
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, r2d2::Error> {
+        let manager = SqliteConnectionManager::file(path).with_init(|cnx| {
+            cnx.busy_timeout(BUSY_TIMEOUT)?;
+            cnx.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))
+        });
+        let pool = r2d2::Pool::builder().max_size(POOL_SIZE).build(manager)?;
+        Ok(Self { pool })
+    }
+
+    /// Takes a connection for code that runs outside the async runtime, such
+    /// as schema setup at startup.
+    pub fn get(&self) -> Result<r2d2::PooledConnection<SqliteConnectionManager>, r2d2::Error> {
+        self.pool.get()
+    }
+
+    /// Runs `f` with a pooled connection on the blocking thread pool.
+    pub async fn run<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: From<PoolError> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut cnx =
+                metrics::time(&METRICS.db_wait_seconds, || pool.get()).map_err(PoolError)?;
+            f(&mut cnx)
+        })
+        .await
+        .unwrap_or_else(|why| std::panic::resume_unwind(why.into_panic()))
     }
 }
 
 pub async fn session_serialize(db: &DatabaseConnection, ssn: &Session) -> Option<rusqlite::Error> {
-    let cnx = db.ctx.deref().lock().unwrap();
-    let mut stmt = cnx
-        .prepare_cached("INSERT INTO sessions(session_id, user_id, expires) VALUES(?1, ?2, ?3);")
-        .unwrap();
-    let result: Result<usize, _> =
-        stmt.execute((ssn.session_id, ssn.user_id, ssn.expires_at.to_rfc2822()));
-    match result {
-        Ok(_) => None,
-        Err(e) => Some(e),
-    }
+    let (session_id, user_id, expires) = (ssn.session_id, ssn.user_id, ssn.expires_at.to_rfc3339());
+    db.run(move |cnx| {
+        let mut stmt = cnx.prepare_cached(
+            "INSERT INTO sessions(session_id, user_id, expires) VALUES(?1, ?2, ?3);",
+        )?;
+        stmt.execute((session_id, user_id, expires))
+    })
+    .await
+    .err()
 }
 
+#[allow(dead_code)]
 pub async fn session_valid(db: &DatabaseConnection, ssn: &Session) -> bool {
-    let cnx = db.ctx.deref().lock().unwrap();
-    let result: Result<u32, _> = cnx.query_row_and_then(
-        "SELECT 1 FROM sessions as s JOIN user_reg as u USING s.user_id = u.user_id WHERE u.user_id=?1 AND s.session_id=?2;",
-        [ssn.user_id, ssn.session_id],
-        |row| row.get(0)
-    );
-    return match result {
-        Ok(1) => true,
-        _ => false,
-    };
+    let (user_id, session_id) = (ssn.user_id, ssn.session_id);
+    let result: Result<u32, rusqlite::Error> = db
+        .run(move |cnx| {
+            cnx.query_row_and_then(
+                "SELECT 1 FROM sessions as s JOIN user_reg as u USING s.user_id = u.user_id WHERE u.user_id=?1 AND s.session_id=?2;",
+                [user_id, session_id],
+                |row| row.get(0),
+            )
+        })
+        .await;
+    matches!(result, Ok(1))
 }
 
+/// Name of the user logged in with `session_id`, unless the session expired.
 pub async fn get_user_from_session_id(db: &DatabaseConnection, session_id: u32) -> Option<String> {
-    let cnx = db.ctx.deref().lock().unwrap();
-    let result: Result<String, _> = cnx.query_row_and_then(
-        "SELECT username FROM sessions s JOIN user_reg u ON s.user_id = u.user_id WHERE s.session_id = ?1;",
-        [session_id],
-        |r| r.get(0)
-    );
-    return match result {
-        Ok(user) => Some(user),
-        _ => None,
-    };
+    let now = Utc::now().to_rfc3339();
+    let result: Result<String, rusqlite::Error> = db
+        .run(move |cnx| {
+            cnx.query_row_and_then(
+                "SELECT username FROM sessions s JOIN user_reg u ON s.user_id = u.user_id WHERE s.session_id = ?1 AND s.expires > ?2;",
+                (session_id, now),
+                |r| r.get(0),
+            )
+        })
+        .await;
+    result.ok()
 }
 
+#[allow(dead_code)]
 pub async fn is_present_session(db: &DatabaseConnection, session_id: u32) -> bool {
-    let cnx = db.ctx.deref().lock().unwrap();
-    let res: Result<u32, _> = cnx.query_row(
-        "SELECT 1 FROM sessions WHERE session_id=?1;",
-        [session_id],
-        |r| r.get(0),
-    );
-    return match res {
-        Ok(_) => true,
-        _ => false,
-    };
+    let res: Result<u32, rusqlite::Error> = db
+        .run(move |cnx| {
+            cnx.query_row(
+                "SELECT 1 FROM sessions WHERE session_id=?1;",
+                [session_id],
+                |r| r.get(0),
+            )
+        })
+        .await;
+    res.is_ok()
 }
 
 pub async fn is_present(db: &DatabaseConnection, user_name: &str) -> bool {
-    let cnx = db.ctx.deref().lock().unwrap();
-    let result: Result<u32, _> = cnx.query_row_and_then(
-        "SELECT * FROM user_reg WHERE username=?1",
-        [user_name],
-        |row| row.get(0),
-    );
+    let user_name = user_name.to_string();
+    let result: Result<u32, rusqlite::Error> = db
+        .run(move |cnx| {
+            cnx.query_row_and_then(
+                "SELECT * FROM user_reg WHERE username=?1",
+                [user_name],
+                |row| row.get(0),
+            )
+        })
+        .await;
     result.is_ok()
 }
 
 pub async fn validate_user(db: &DatabaseConnection, user_name: &str, password: &str) -> bool {
-    let cnx = db.ctx.deref().lock().unwrap();
-    let result: Result<u32, _> = cnx.query_row(
-        "SELECT 1 FROM user_reg WHERE username=?1 AND password=?2;",
-        [user_name, password],
-        |r| r.get(0),
-    );
-    if let Ok(_) = result {
-        return true;
-    } else {
-        return false;
-    }
+    let (user_name, password) = (user_name.to_string(), password.to_string());
+    let result: Result<u32, rusqlite::Error> = db
+        .run(move |cnx| {
+            cnx.query_row(
+                "SELECT 1 FROM user_reg WHERE username=?1 AND password=?2;",
+                [user_name, password],
+                |r| r.get(0),
+            )
+        })
+        .await;
+    result.is_ok()
 }
 
 pub async fn get_user_id(db: &DatabaseConnection, user_name: &str) -> Result<u32, rusqlite::Error> {
-    let cnx = db.ctx.deref().lock().unwrap();
-    let result = cnx.query_row_and_then(
-        "SELECT * FROM user_reg WHERE username=?1",
-        [user_name],
-        |row| row.get(0),
-    );
-    result
+    let user_name = user_name.to_string();
+    db.run(move |cnx| {
+        cnx.query_row_and_then(
+            "SELECT * FROM user_reg WHERE username=?1",
+            [user_name],
+            |row| row.get(0),
+        )
+    })
+    .await
 }
 
 pub async fn add_user(
@@ -108,22 +175,238 @@ pub async fn add_user(
     user_name: &str,
     password: &str,
 ) -> Option<rusqlite::Error> {
-    let cnx = db.ctx.deref().lock().unwrap();
-    let result = cnx.execute(
-        "INSERT INTO user_reg(username, password) VALUES(?1, ?2);",
-        [user_name, password],
-    );
-    return match result {
-        Ok(_) => None,
-        Err(e) => Some(e),
-    };
-}
-
-pub async fn delete_user(db: &DatabaseConnection, user_name: &str) -> Option<rusqlite::Error> {
-    let cnx = db.ctx.deref().lock().unwrap();
-    let result = cnx.execute("DELETE FROM user_reg WHERE username=?1;", [user_name]);
-    return match result {
-        Ok(_) => None,
-        Err(e) => Some(e),
-    };
+    let (user_name, password) = (user_name.to_string(), password.to_string());
+    db.run(move |cnx| {
+        cnx.execute(
+            "INSERT INTO user_reg(username, password) VALUES(?1, ?2);",
+            [user_name, password],
+        )
+    })
+    .await
+    .err()
+}
+
+/// Deletes a user together with everything they own and returns the blobs
+/// that are no longer referenced, for the caller to remove from the stash.
+pub async fn delete_user(
+    db: &DatabaseConnection,
+    user_id: u32,
+) -> Result<Vec<String>, rusqlite::Error> {
+    db.run(move |cnx| {
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let blobs: Vec<String> = {
+            let mut stmt = tx.prepare_cached(
+                "SELECT blob FROM file_state WHERE file_owner=?1 UNION SELECT blob FROM file_trash WHERE file_owner=?1;",
+            )?;
+            let rows = stmt.query_map([user_id], |r| r.get(0))?;
+            rows.collect::<Result<_, _>>()?
+        };
+        for blob in &blobs {
+            tx.execute("DELETE FROM file_health WHERE blob=?1;", [blob])?;
+        }
+        tx.execute("DELETE FROM file_state WHERE file_owner=?1;", [user_id])?;
+        tx.execute("DELETE FROM file_trash WHERE file_owner=?1;", [user_id])?;
+        tx.execute("DELETE FROM folders WHERE owner=?1;", [user_id])?;
+        tx.execute("DELETE FROM sessions WHERE user_id=?1;", [user_id])?;
+        tx.execute("DELETE FROM api_tokens WHERE user_id=?1;", [user_id])?;
+        tx.execute("DELETE FROM admins WHERE user_id=?1;", [user_id])?;
+        tx.execute("DELETE FROM user_keys WHERE user_id=?1;", [user_id])?;
+        tx.execute("DELETE FROM user_reg WHERE user_id=?1;", [user_id])?;
+        tx.commit()?;
+        Ok(blobs)
+    })
+    .await
+}
+
+/// Replaces a user's login password and ends all of their sessions.
+pub async fn set_password(
+    db: &DatabaseConnection,
+    user_id: u32,
+    password: &str,
+) -> Result<(), rusqlite::Error> {
+    let password = password.to_string();
+    db.run(move |cnx| {
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute(
+            "UPDATE user_reg SET password=?1 WHERE user_id=?2;",
+            (password, user_id),
+        )?;
+        tx.execute("DELETE FROM sessions WHERE user_id=?1;", [user_id])?;
+        tx.commit()
+    })
+    .await
+}
+
+pub async fn set_admin(db: &DatabaseConnection, user_id: u32) -> Result<(), rusqlite::Error> {
+    db.run(move |cnx| {
+        cnx.execute(
+            "INSERT OR IGNORE INTO admins(user_id) VALUES(?1);",
+            [user_id],
+        )?;
+        Ok(())
+    })
+    .await
+}
+
+pub struct UserSummary {
+    pub user_id: u32,
+    pub user_name: String,
+    pub is_admin: bool,
+    pub files: u64,
+    /// Files in the user's recycle bin.
+    pub trashed: u64,
+}
+
+pub async fn list_users(db: &DatabaseConnection) -> Result<Vec<UserSummary>, rusqlite::Error> {
+    db.run(|cnx| {
+        let mut stmt = cnx.prepare_cached(
+            "SELECT u.user_id, u.username, a.user_id IS NOT NULL,
+                (SELECT COUNT(*) FROM file_state f WHERE f.file_owner = u.user_id),
+                (SELECT COUNT(*) FROM file_trash t WHERE t.file_owner = u.user_id)
+            FROM user_reg u LEFT JOIN admins a ON a.user_id = u.user_id
+            ORDER BY u.username;",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(UserSummary {
+                user_id: row.get(0)?,
+                user_name: row.get(1)?,
+                is_admin: row.get(2)?,
+                files: row.get(3)?,
+                trashed: row.get(4)?,
+            })
+        })?;
+        rows.collect()
+    })
+    .await
+}
+
+pub async fn is_admin(db: &DatabaseConnection, user_id: u32) -> bool {
+    let result: Result<u32, rusqlite::Error> = db
+        .run(move |cnx| {
+            cnx.query_row(
+                "SELECT 1 FROM admins WHERE user_id=?1;",
+                [user_id],
+                |r| r.get(0),
+            )
+        })
+        .await;
+    result.is_ok()
+}
+
+pub struct StoredBlob {
+    pub blob: String,
+    pub checksum: Option<String>,
+    /// Sealed by the client, which binds nothing to the file.
+    pub end_to_end: bool,
+}
+
+pub async fn get_stored_blobs(db: &DatabaseConnection) -> Result<Vec<StoredBlob>, rusqlite::Error> {
+    db.run(|cnx| {
+        let mut stmt = cnx.prepare_cached(
+            "SELECT f.blob, h.checksum, f.e2e FROM file_state f LEFT JOIN file_health h ON h.blob = f.blob;",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(StoredBlob {
+                blob: row.get(0)?,
+                checksum: row.get(1)?,
+                end_to_end: row.get(2)?,
+            })
+        })?;
+        rows.collect()
+    })
+    .await
+}
+
+/// Hands out the id the next file will be stored under, before its row
+/// exists, so that its blob can be bound to it. Ids are never reused, a
+/// reserved id that ends up unused is simply skipped.
+pub async fn reserve_file_id(db: &DatabaseConnection) -> Result<i64, rusqlite::Error> {
+    db.run(|cnx| {
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // `file_state` gets its row in `sqlite_sequence` with its first file.
+        tx.execute(
+            "INSERT INTO sqlite_sequence(name, seq)
+            SELECT 'file_state', (SELECT COALESCE(MAX(file_id), 0) FROM file_state)
+            WHERE NOT EXISTS (SELECT 1 FROM sqlite_sequence WHERE name = 'file_state');",
+            [],
+        )?;
+        let file_id = tx.query_row(
+            "UPDATE sqlite_sequence SET seq = seq + 1 WHERE name = 'file_state' RETURNING seq;",
+            [],
+            |r| r.get(0),
+        )?;
+        tx.commit()?;
+        Ok(file_id)
+    })
+    .await
+}
+
+pub async fn record_checksum(
+    db: &DatabaseConnection,
+    blob: &str,
+    checksum: &str,
+) -> Result<(), rusqlite::Error> {
+    let (blob, checksum) = (blob.to_string(), checksum.to_string());
+    db.run(move |cnx| {
+        cnx.execute(
+            "INSERT INTO file_health(blob, checksum, status, checked_at) VALUES(?1, ?2, 'ok', ?3)
+            ON CONFLICT(blob) DO UPDATE SET checksum = excluded.checksum, status = excluded.status, checked_at = excluded.checked_at;",
+            (blob, checksum, Utc::now().to_rfc2822()),
+        )?;
+        Ok(())
+    })
+    .await
+}
+
+pub async fn get_checksum(db: &DatabaseConnection, blob: &str) -> Option<String> {
+    let blob = blob.to_string();
+    let result: Result<Option<String>, rusqlite::Error> = db
+        .run(move |cnx| {
+            cnx.query_row(
+                "SELECT checksum FROM file_health WHERE blob=?1;",
+                [blob],
+                |r| r.get(0),
+            )
+        })
+        .await;
+    result.ok().flatten()
+}
+
+pub async fn record_blob_status(
+    db: &DatabaseConnection,
+    blob: &str,
+    status: BlobStatus,
+) -> Result<(), rusqlite::Error> {
+    let blob = blob.to_string();
+    db.run(move |cnx| {
+        cnx.execute(
+            "INSERT INTO file_health(blob, status, checked_at) VALUES(?1, ?2, ?3)
+            ON CONFLICT(blob) DO UPDATE SET status = excluded.status, checked_at = excluded.checked_at;",
+            (blob, status.as_str(), Utc::now().to_rfc2822()),
+        )?;
+        Ok(())
+    })
+    .await
+}
+
+pub async fn get_unhealthy_blobs(db: &DatabaseConnection) -> Result<Vec<FileHealth>, rusqlite::Error> {
+    db.run(|cnx| {
+        let mut stmt = cnx.prepare_cached(
+            "SELECT h.blob, f.file_owner, f.file_id, h.status, h.checked_at FROM file_health h
+            JOIN file_state f ON h.blob = f.blob
+            WHERE h.status != 'ok';",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            let status: String = row.get(3)?;
+            Ok(FileHealth {
+                blob: row.get(0)?,
+                file_owner: row.get(1)?,
+                file_id: row.get(2)?,
+                status: BlobStatus::parse(&status).unwrap_or(BlobStatus::Mismatch),
+                checked_at: row.get(4)?,
+            })
+        })?;
+        rows.collect()
+    })
+    .await
 }
@@ -0,0 +1,591 @@
+use rusqlite::TransactionBehavior;
+
+use crate::db::DatabaseConnection;
+use crate::keyring::{Field, Keyring, UserKey};
+use crate::trash;
+use crate::types::SenmonError;
+
+/// Folder id of a user's top level folder. It has no row in `folders`.
+pub const ROOT_FOLDER: i64 = 0;
+
+pub struct FileEntry {
+    pub file_id: i64,
+    pub file_name: String,
+}
+
+pub struct FolderListing {
+    pub folders: Vec<String>,
+    pub files: Vec<FileEntry>,
+}
+
+/// Splits a folder path such as `reports/2024` into its components. Paths
+/// only ever address rows in `folders`, so anything that could mean something
+/// else to a filesystem is rejected.
+pub fn split_path(path: &str) -> Result<Vec<&str>, SenmonError> {
+    let trimmed = path.trim_matches('/');
+    if trimmed.is_empty() {
+        return Ok(Vec::new());
+    }
+    let components: Vec<&str> = trimmed.split('/').collect();
+    for component in &components {
+        if !is_valid_name(component) {
+            return Err(SenmonError::BadRequest);
+        }
+    }
+    Ok(components)
+}
+
+/// Splits a file path such as `reports/2024/q1.txt` into the folder
+/// components and the file name.
+pub fn split_file_path(path: &str) -> Result<(Vec<&str>, &str), SenmonError> {
+    let mut components = split_path(path)?;
+    let file_name = components.pop().ok_or(SenmonError::BadRequest)?;
+    Ok((components, file_name))
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0'])
+}
+
+/// [`split_path`] for queries that run on the blocking pool and so can not
+/// borrow from the request.
+fn owned_components(path: &str) -> Result<Vec<String>, SenmonError> {
+    Ok(split_path(path)?.into_iter().map(str::to_string).collect())
+}
+
+fn borrowed(components: &[String]) -> Vec<&str> {
+    components.iter().map(String::as_str).collect()
+}
+
+fn resolve_in(
+    cnx: &rusqlite::Connection,
+    key: &UserKey,
+    owner: u32,
+    components: &[&str],
+) -> Result<i64, SenmonError> {
+    let mut folder_id = ROOT_FOLDER;
+    for component in components {
+        folder_id = cnx
+            .query_row(
+                "SELECT folder_id FROM folders WHERE owner=?1 AND parent_id=?2 AND name_tag=?3;",
+                (owner, folder_id, key.tag(Field::FolderName, component)),
+                |r| r.get(0),
+            )
+            .map_err(|_| SenmonError::NotFound)?;
+    }
+    Ok(folder_id)
+}
+
+fn subtree_in(
+    cnx: &rusqlite::Connection,
+    owner: u32,
+    folder_id: i64,
+) -> rusqlite::Result<Vec<i64>> {
+    let mut stmt = cnx.prepare_cached(
+        "WITH RECURSIVE subtree(folder_id) AS (
+            SELECT ?1
+            UNION ALL
+            SELECT f.folder_id FROM folders f JOIN subtree s ON f.parent_id = s.folder_id WHERE f.owner = ?2
+        )
+        SELECT folder_id FROM subtree;",
+    )?;
+    let rows = stmt.query_map((folder_id, owner), |r| r.get(0))?;
+    rows.collect()
+}
+
+fn name_taken(
+    cnx: &rusqlite::Connection,
+    key: &UserKey,
+    owner: u32,
+    parent_id: i64,
+    name: &str,
+) -> bool {
+    let result: Result<u32, _> = cnx.query_row(
+        "SELECT 1 FROM folders WHERE owner=?1 AND parent_id=?2 AND name_tag=?3;",
+        (owner, parent_id, key.tag(Field::FolderName, name)),
+        |r| r.get(0),
+    );
+    result.is_ok()
+}
+
+pub async fn resolve_folder(
+    db: &DatabaseConnection,
+    keyring: &Keyring,
+    owner: u32,
+    path: &str,
+) -> Result<i64, SenmonError> {
+    let components = owned_components(path)?;
+    let keyring = keyring.clone();
+    db.run(move |cnx| {
+        let key = keyring.user_key(cnx, owner)?;
+        resolve_in(cnx, &key, owner, &borrowed(&components))
+    })
+    .await
+}
+
+/// Resolves a file path such as `reports/q1.txt` to the file's id.
+pub async fn resolve_file(
+    db: &DatabaseConnection,
+    keyring: &Keyring,
+    owner: u32,
+    path: &str,
+) -> Result<i64, SenmonError> {
+    let (components, file_name) = split_file_path(path)?;
+    let components: Vec<String> = components.into_iter().map(str::to_string).collect();
+    let file_name = file_name.to_string();
+    let keyring = keyring.clone();
+    db.run(move |cnx| {
+        let key = keyring.user_key(cnx, owner)?;
+        let folder_id = resolve_in(cnx, &key, owner, &borrowed(&components))?;
+        cnx.query_row(
+            "SELECT file_id FROM file_state WHERE file_owner=?1 AND folder_id=?2 AND name_tag=?3;",
+            (owner, folder_id, key.tag(Field::FileName, &file_name)),
+            |r| r.get(0),
+        )
+        .map_err(|_| SenmonError::NotFound)
+    })
+    .await
+}
+
+/// Creates the folder at `path` along with any missing parents and returns
+/// its id.
+pub async fn create_folder(
+    db: &DatabaseConnection,
+    keyring: &Keyring,
+    owner: u32,
+    path: &str,
+) -> Result<i64, SenmonError> {
+    let components = owned_components(path)?;
+    let keyring = keyring.clone();
+    db.run(move |cnx| {
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let key = keyring.user_key(&tx, owner)?;
+
+        let mut folder_id = ROOT_FOLDER;
+        for component in &components {
+            let (tag, sealed) = key.seal_name(Field::FolderName, component);
+            let existing: Result<i64, _> = tx.query_row(
+                "SELECT folder_id FROM folders WHERE owner=?1 AND parent_id=?2 AND name_tag=?3;",
+                (owner, folder_id, &tag),
+                |r| r.get(0),
+            );
+            folder_id = match existing {
+                Ok(id) => id,
+                Err(_) => {
+                    tx.execute(
+                        "INSERT INTO folders(owner, parent_id, name_tag, name_sealed) VALUES(?1, ?2, ?3, ?4);",
+                        (owner, folder_id, &tag, &sealed),
+                    )?;
+                    tx.last_insert_rowid()
+                }
+            };
+        }
+
+        tx.commit()?;
+        Ok(folder_id)
+    })
+    .await
+}
+
+pub async fn rename_folder(
+    db: &DatabaseConnection,
+    keyring: &Keyring,
+    owner: u32,
+    path: &str,
+    new_name: &str,
+) -> Result<(), SenmonError> {
+    if !is_valid_name(new_name) {
+        return Err(SenmonError::BadRequest);
+    }
+    let components = owned_components(path)?;
+    if components.is_empty() {
+        return Err(SenmonError::BadRequest);
+    }
+    let new_name = new_name.to_string();
+    let keyring = keyring.clone();
+    db.run(move |cnx| {
+        let components = borrowed(&components);
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let key = keyring.user_key(&tx, owner)?;
+        let folder_id = resolve_in(&tx, &key, owner, &components)?;
+        let parent_id = resolve_in(&tx, &key, owner, &components[..components.len() - 1])?;
+        if name_taken(&tx, &key, owner, parent_id, &new_name) {
+            return Err(SenmonError::Conflict);
+        }
+        let (tag, sealed) = key.seal_name(Field::FolderName, &new_name);
+        tx.execute(
+            "UPDATE folders SET name_tag=?1, name_sealed=?2 WHERE folder_id=?3;",
+            (&tag, &sealed, folder_id),
+        )
+        .and_then(|_| tx.commit())
+        .map_err(SenmonError::from)
+    })
+    .await
+}
+
+/// Moves the folder at `path` underneath `new_parent`. A folder can not be
+/// moved into itself or any of its descendants.
+pub async fn move_folder(
+    db: &DatabaseConnection,
+    keyring: &Keyring,
+    owner: u32,
+    path: &str,
+    new_parent: &str,
+) -> Result<(), SenmonError> {
+    let components = owned_components(path)?;
+    if components.is_empty() {
+        return Err(SenmonError::BadRequest);
+    }
+    let parent_components = owned_components(new_parent)?;
+    let keyring = keyring.clone();
+    db.run(move |cnx| {
+        let components = borrowed(&components);
+        let name = components[components.len() - 1];
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let key = keyring.user_key(&tx, owner)?;
+        let folder_id = resolve_in(&tx, &key, owner, &components)?;
+        let parent_id = resolve_in(&tx, &key, owner, &borrowed(&parent_components))?;
+
+        let subtree = subtree_in(&tx, owner, folder_id)?;
+        if subtree.contains(&parent_id) {
+            return Err(SenmonError::BadRequest);
+        }
+        if name_taken(&tx, &key, owner, parent_id, name) {
+            return Err(SenmonError::Conflict);
+        }
+        tx.execute(
+            "UPDATE folders SET parent_id=?1 WHERE folder_id=?2;",
+            (parent_id, folder_id),
+        )
+        .and_then(|_| tx.commit())
+        .map_err(SenmonError::from)
+    })
+    .await
+}
+
+/// Renames and/or moves the file at `from` to `to`. Only the `file_state`
+/// row changes, the blob keeps its path.
+pub async fn move_file(
+    db: &DatabaseConnection,
+    keyring: &Keyring,
+    owner: u32,
+    from: &str,
+    to: &str,
+) -> Result<(), SenmonError> {
+    let mut from_components = owned_components(from)?;
+    let from_name = from_components.pop().ok_or(SenmonError::BadRequest)?;
+    let mut to_components = owned_components(to)?;
+    let to_name = to_components.pop().ok_or(SenmonError::BadRequest)?;
+    let keyring = keyring.clone();
+    db.run(move |cnx| {
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let key = keyring.user_key(&tx, owner)?;
+        let from_folder = resolve_in(&tx, &key, owner, &borrowed(&from_components))?;
+        let to_folder = resolve_in(&tx, &key, owner, &borrowed(&to_components))?;
+
+        let (to_tag, to_sealed) = key.seal_name(Field::FileName, &to_name);
+        let result = tx.execute(
+            "UPDATE file_state SET folder_id=?1, name_tag=?2, name_sealed=?3 WHERE file_owner=?4 AND folder_id=?5 AND name_tag=?6;",
+            (to_folder, &to_tag, &to_sealed, owner, from_folder, key.tag(Field::FileName, &from_name)),
+        );
+        // A constraint violation means the target name is taken, which
+        // `SenmonError::from` reports as a conflict.
+        match result? {
+            0 => Err(SenmonError::NotFound),
+            _ => Ok(tx.commit()?),
+        }
+    })
+    .await
+}
+
+/// Deletes the folder at `path` and everything below it. Files inside the
+/// subtree go to the recycle bin, the folders themselves are removed.
+pub async fn delete_folder(
+    db: &DatabaseConnection,
+    keyring: &Keyring,
+    owner: u32,
+    path: &str,
+) -> Result<(), SenmonError> {
+    let components = owned_components(path)?;
+    if components.is_empty() {
+        return Err(SenmonError::BadRequest);
+    }
+    let keyring = keyring.clone();
+    db.run(move |cnx| {
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let key = keyring.user_key(&tx, owner)?;
+        let folder_id = resolve_in(&tx, &key, owner, &borrowed(&components))?;
+
+        let result = (|| {
+            let subtree = subtree_in(&tx, owner, folder_id)?;
+            for folder in subtree {
+                let file_ids: Vec<i64> = {
+                    let mut stmt = tx.prepare_cached(
+                        "SELECT file_id FROM file_state WHERE file_owner=?1 AND folder_id=?2;",
+                    )?;
+                    let rows = stmt.query_map((owner, folder), |r| r.get(0))?;
+                    rows.collect::<Result<_, _>>()?
+                };
+                for file_id in file_ids {
+                    trash::trash_in_tx(&tx, file_id)?;
+                }
+                tx.execute("DELETE FROM folders WHERE folder_id=?1;", [folder])?;
+            }
+            Ok::<_, rusqlite::Error>(())
+        })();
+
+        result.and_then(|_| tx.commit()).map_err(SenmonError::from)
+    })
+    .await
+}
+
+/// Lists a folder, names in order. The database only holds sealed names,
+/// so the sorting happens here.
+pub async fn list_folder(
+    db: &DatabaseConnection,
+    keyring: &Keyring,
+    owner: u32,
+    path: &str,
+) -> Result<FolderListing, SenmonError> {
+    let components = owned_components(path)?;
+    let keyring = keyring.clone();
+    db.run(move |cnx| {
+        let key = keyring.user_key(cnx, owner)?;
+        let folder_id = resolve_in(cnx, &key, owner, &borrowed(&components))?;
+
+        let listing = (|| {
+            let mut stmt = cnx.prepare_cached(
+                "SELECT name_sealed FROM folders WHERE owner=?1 AND parent_id=?2;",
+            )?;
+            let mut folders: Vec<String> = stmt
+                .query_map((owner, folder_id), |r| {
+                    key.open_name(Field::FolderName, &r.get::<_, String>(0)?)
+                })?
+                .collect::<Result<_, _>>()?;
+            folders.sort();
+            let mut stmt = cnx.prepare_cached(
+                "SELECT file_id, name_sealed FROM file_state WHERE file_owner=?1 AND folder_id=?2;",
+            )?;
+            let mut files: Vec<FileEntry> = stmt
+                .query_map((owner, folder_id), |r| {
+                    Ok(FileEntry {
+                        file_id: r.get(0)?,
+                        file_name: key.open_name(Field::FileName, &r.get::<_, String>(1)?)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            files.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+            Ok::<_, rusqlite::Error>(FolderListing { folders, files })
+        })();
+        listing.map_err(SenmonError::from)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::testing::TestDb;
+
+    #[test]
+    fn paths() {
+        assert!(split_path("").unwrap().is_empty());
+        assert!(split_path("/").unwrap().is_empty());
+        assert_eq!(split_path("/reports/2024/").unwrap(), ["reports", "2024"]);
+        assert_eq!(
+            split_file_path("reports/q1.txt").unwrap(),
+            (vec!["reports"], "q1.txt")
+        );
+        assert!(matches!(split_file_path("/"), Err(SenmonError::BadRequest)));
+    }
+
+    #[test]
+    fn invalid_paths() {
+        for path in [
+            "reports//2024",
+            "reports/../etc",
+            "./reports",
+            "a\\b",
+            "a\0b",
+        ] {
+            assert!(
+                matches!(split_path(path), Err(SenmonError::BadRequest)),
+                "{path:?}"
+            );
+        }
+        for name in ["", ".", "..", "a/b"] {
+            assert!(!is_valid_name(name), "{name:?}");
+        }
+        assert!(is_valid_name("...") && is_valid_name(".hidden"));
+    }
+
+    #[tokio::test]
+    async fn creates_missing_parents() {
+        let test = TestDb::open().await;
+        let id = create_folder(&test.db, &test.keyring, test.user_id, "reports/2024")
+            .await
+            .unwrap();
+        assert_eq!(
+            resolve_folder(&test.db, &test.keyring, test.user_id, "reports/2024")
+                .await
+                .unwrap(),
+            id
+        );
+        assert_eq!(
+            create_folder(&test.db, &test.keyring, test.user_id, "reports/2024")
+                .await
+                .unwrap(),
+            id
+        );
+        let listing = list_folder(&test.db, &test.keyring, test.user_id, "reports")
+            .await
+            .unwrap();
+        assert_eq!(listing.folders, ["2024"]);
+    }
+
+    #[tokio::test]
+    async fn folders_are_per_user() {
+        let test = TestDb::open().await;
+        create_folder(&test.db, &test.keyring, test.user_id, "reports")
+            .await
+            .unwrap();
+        assert!(db::add_user(&test.db, "bob", "x").await.is_none());
+        let bob = db::get_user_id(&test.db, "bob").await.unwrap();
+        let other = resolve_folder(&test.db, &test.keyring, bob, "reports").await;
+        assert!(matches!(other, Err(SenmonError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn rename_onto_a_sibling() {
+        let test = TestDb::open().await;
+        create_folder(&test.db, &test.keyring, test.user_id, "a")
+            .await
+            .unwrap();
+        create_folder(&test.db, &test.keyring, test.user_id, "b")
+            .await
+            .unwrap();
+        let renamed = rename_folder(&test.db, &test.keyring, test.user_id, "a", "b").await;
+        assert!(matches!(renamed, Err(SenmonError::Conflict)));
+        let renamed = rename_folder(&test.db, &test.keyring, test.user_id, "a", "..").await;
+        assert!(matches!(renamed, Err(SenmonError::BadRequest)));
+        rename_folder(&test.db, &test.keyring, test.user_id, "a", "c")
+            .await
+            .unwrap();
+        assert!(resolve_folder(&test.db, &test.keyring, test.user_id, "c")
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn move_into_own_subtree() {
+        let test = TestDb::open().await;
+        create_folder(&test.db, &test.keyring, test.user_id, "a/b/c")
+            .await
+            .unwrap();
+        for target in ["a", "a/b", "a/b/c"] {
+            let moved = move_folder(&test.db, &test.keyring, test.user_id, "a", target).await;
+            assert!(matches!(moved, Err(SenmonError::BadRequest)), "{target}");
+        }
+        move_folder(&test.db, &test.keyring, test.user_id, "a/b/c", "")
+            .await
+            .unwrap();
+        assert!(resolve_folder(&test.db, &test.keyring, test.user_id, "c")
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn move_onto_a_taken_name() {
+        let test = TestDb::open().await;
+        create_folder(&test.db, &test.keyring, test.user_id, "a/docs")
+            .await
+            .unwrap();
+        create_folder(&test.db, &test.keyring, test.user_id, "docs")
+            .await
+            .unwrap();
+        let moved = move_folder(&test.db, &test.keyring, test.user_id, "a/docs", "").await;
+        assert!(matches!(moved, Err(SenmonError::Conflict)));
+    }
+
+    #[tokio::test]
+    async fn delete_trashes_the_subtree() {
+        let test = TestDb::open().await;
+        let a = create_folder(&test.db, &test.keyring, test.user_id, "a")
+            .await
+            .unwrap();
+        let b = create_folder(&test.db, &test.keyring, test.user_id, "a/b")
+            .await
+            .unwrap();
+        test.add_file(a, "one.txt");
+        test.add_file(b, "two.txt");
+        test.add_file(ROOT_FOLDER, "three.txt");
+
+        delete_folder(&test.db, &test.keyring, test.user_id, "a")
+            .await
+            .unwrap();
+        assert!(matches!(
+            resolve_folder(&test.db, &test.keyring, test.user_id, "a/b").await,
+            Err(SenmonError::NotFound)
+        ));
+        assert_eq!(test.file_names(ROOT_FOLDER), ["three.txt"]);
+        let trashed = trash::list_trash(&test.db, &test.keyring, test.user_id)
+            .await
+            .unwrap();
+        assert_eq!(trashed.len(), 2);
+
+        // The folder is gone, so the file comes back in the root folder.
+        trash::restore(&test.db, test.user_id, trashed[0].trash_id)
+            .await
+            .unwrap();
+        assert_eq!(test.file_names(ROOT_FOLDER).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn moves_and_renames_files() {
+        let test = TestDb::open().await;
+        let docs = create_folder(&test.db, &test.keyring, test.user_id, "docs")
+            .await
+            .unwrap();
+        test.add_file(ROOT_FOLDER, "notes.txt");
+
+        move_file(
+            &test.db,
+            &test.keyring,
+            test.user_id,
+            "notes.txt",
+            "docs/todo.txt",
+        )
+        .await
+        .unwrap();
+        assert!(test.file_names(ROOT_FOLDER).is_empty());
+        assert_eq!(test.file_names(docs), ["todo.txt"]);
+    }
+
+    #[tokio::test]
+    async fn move_file_onto_a_taken_name() {
+        let test = TestDb::open().await;
+        test.add_file(ROOT_FOLDER, "a.txt");
+        test.add_file(ROOT_FOLDER, "b.txt");
+        let moved = move_file(&test.db, &test.keyring, test.user_id, "a.txt", "b.txt").await;
+        assert!(matches!(moved, Err(SenmonError::Conflict)));
+        assert_eq!(test.file_names(ROOT_FOLDER), ["a.txt", "b.txt"]);
+    }
+
+    #[tokio::test]
+    async fn move_missing_files() {
+        let test = TestDb::open().await;
+        test.add_file(ROOT_FOLDER, "a.txt");
+        let moved = move_file(&test.db, &test.keyring, test.user_id, "b.txt", "c.txt").await;
+        assert!(matches!(moved, Err(SenmonError::NotFound)));
+        let moved = move_file(
+            &test.db,
+            &test.keyring,
+            test.user_id,
+            "a.txt",
+            "nowhere/a.txt",
+        )
+        .await;
+        assert!(matches!(moved, Err(SenmonError::NotFound)));
+    }
+}
@@ -0,0 +1,140 @@
+//! Offline garbage collection for `senmon gc`.
+//!
+//! Uploads put their blob in place before committing the `file_state` row,
+//! so a blob without a row may belong to an upload that is just finishing.
+//! Only unreferenced blobs older than [`ORPHAN_GRACE`] are removed, which
+//! keeps this safe to run next to a live server.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::Utc;
+use rusqlite::TransactionBehavior;
+
+use crate::config::Config;
+use crate::db::DatabaseConnection;
+use crate::{tokens, trash};
+
+/// How old an unreferenced blob has to be before it counts as orphaned.
+const ORPHAN_GRACE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Directories in the stash that do not hold blobs.
+const NOT_BLOBS: &[&str] = &[".uploads"];
+
+#[derive(Default)]
+pub struct GcReport {
+    pub expired_trash: usize,
+    pub orphan_blobs: Vec<PathBuf>,
+    pub stale_health: usize,
+    pub expired_sessions: usize,
+    pub expired_tokens: usize,
+}
+
+/// Removes expired recycle bin entries, blobs that no file or trash entry
+/// refers to, health records of such blobs, expired sessions and expired
+/// API tokens. With
+/// `dry_run` nothing is changed and the report says what would go.
+pub async fn collect(
+    db: &DatabaseConnection,
+    config: Arc<Config>,
+    dry_run: bool,
+) -> Result<GcReport, rusqlite::Error> {
+    let mut report = GcReport::default();
+
+    if dry_run {
+        let cutoff = (Utc::now() - config.trash_retention()).to_rfc3339();
+        report.expired_trash = db
+            .run(move |cnx| {
+                cnx.query_row(
+                    "SELECT COUNT(*) FROM file_trash WHERE deleted_at < ?1;",
+                    [cutoff],
+                    |r| r.get(0),
+                )
+            })
+            .await?;
+    } else {
+        report.expired_trash = trash::purge_expired(db, config.clone()).await?;
+    }
+
+    let referenced: HashSet<String> = db
+        .run(|cnx| {
+            let mut stmt = cnx.prepare_cached(
+                "SELECT blob FROM file_state UNION SELECT blob FROM file_trash;",
+            )?;
+            let rows = stmt.query_map([], |r| r.get(0))?;
+            rows.collect()
+        })
+        .await?;
+
+    let mut stored = Vec::new();
+    if let Err(why) = list_blobs(&config.stash_dir, &config.stash_dir, &mut stored) {
+        tracing::warn!("listing {}: {why}", config.stash_dir.display());
+    }
+    for (path, blob) in stored {
+        if referenced.contains(&blob) || !is_older_than(&path, ORPHAN_GRACE) {
+            continue;
+        }
+        if !dry_run {
+            if let Err(why) = std::fs::remove_file(&path) {
+                tracing::warn!("removing {}: {why}", path.display());
+                continue;
+            }
+        }
+        report.orphan_blobs.push(path);
+    }
+
+    let now = Utc::now().to_rfc3339();
+    let (stale_health, expired_sessions) = db
+        .run(move |cnx| {
+            let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let stale_health = tx.execute(
+                "DELETE FROM file_health WHERE blob NOT IN (SELECT blob FROM file_state UNION SELECT blob FROM file_trash);",
+                [],
+            )?;
+            let expired_sessions =
+                tx.execute("DELETE FROM sessions WHERE expires <= ?1;", [now])?;
+            if dry_run {
+                tx.rollback()?;
+            } else {
+                tx.commit()?;
+            }
+            Ok::<_, rusqlite::Error>((stale_health, expired_sessions))
+        })
+        .await?;
+    report.stale_health = stale_health;
+    report.expired_sessions = expired_sessions;
+    report.expired_tokens = tokens::purge_expired(db, dry_run).await?;
+    Ok(report)
+}
+
+fn is_older_than(path: &Path, age: std::time::Duration) -> bool {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|elapsed| elapsed > age)
+}
+
+/// Collects every file below `dir` with its path relative to the stash, in
+/// the form `file_state.blob` uses.
+fn list_blobs(stash: &Path, dir: &Path, blobs: &mut Vec<(PathBuf, String)>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            if dir == stash && NOT_BLOBS.iter().any(|name| entry.file_name() == *name) {
+                continue;
+            }
+            list_blobs(stash, &path, blobs)?;
+        } else if let Ok(relative) = path.strip_prefix(stash) {
+            let blob = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            blobs.push((path, blob));
+        }
+    }
+    Ok(())
+}
@@ -1,20 +1,34 @@
-use crate::db::{self, is_present_session};
+use crate::config::Config;
+use crate::db;
 use crate::db::get_user_from_session_id;
-use aes_gcm::aead::Aead;
-use aes_gcm::{AeadCore, KeyInit};
+use crate::container;
+use crate::folders;
+use crate::keyring::{Field, FileMeta, Keyring};
+use crate::logging;
+use crate::metrics::{self, METRICS};
+use crate::scrub;
+use crate::tokens::{self, Scope};
+use crate::trash;
+use crate::tus;
+use crate::types::SenmonError;
+use crate::unlock;
+use askama::Template;
 use axum::body::Body;
-use axum::http::header;
-use axum::response::Response;
-use axum::{http::StatusCode, response::Html, Form};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap};
+use axum::response::{IntoResponse, Response};
+use axum::{http::StatusCode, response::Html, Form, Json};
 use axum_extra::extract::CookieJar;
-use axum_extra::headers::ContentType;
+use axum_extra::headers::{ContentType, ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, LastModified, Range};
 use axum_extra::TypedHeader;
 use rand::Rng;
-use std::io::Read;
-use std::num::NonZeroU32;
-use std::ops::Deref;
+use std::io::{Read, Seek, Write};
+use std::ops::Bound;
+use std::sync::Arc;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use zeroize::Zeroizing;
 
 #[derive(Deserialize)]
 pub struct DownloadReq {
@@ -24,161 +38,843 @@ pub struct DownloadReq {
 
 pub struct UploadFile {
     pub file_name: String,
-    pub file_contents: String,
-    pub password: String,
+    pub folder: String,
+    pub file_contents: UploadBody,
+    pub content_type: Option<String>,
+    pub sealing: Sealing,
     pub salt: String,
 }
 
+/// Where the bytes of an upload are.
+pub enum UploadBody {
+    /// Read into memory, from a form or JSON request.
+    Memory(Vec<u8>),
+    /// Staged on disk by a resumable upload, and streamed from there.
+    Staged(std::path::PathBuf),
+}
+
+/// Who encrypts an upload.
+pub enum Sealing {
+    /// The server does, with a key derived from this file password.
+    Password(String),
+    /// The client already did, end to end, with a key derived from the
+    /// upload's salt with this many iterations. The contents are stored as
+    /// they arrive and the server can never decrypt them.
+    Client { kdf_iterations: u32 },
+}
+
+#[derive(Deserialize)]
+pub struct DeleteReq {
+    file_name: String,
+}
+
+#[derive(Deserialize)]
+pub struct RestoreReq {
+    trash_id: i64,
+}
+
+#[derive(Template)]
+#[template(path = "trash.html")]
+pub struct TrashTemplate {
+    entries: Vec<trash::TrashEntry>,
+}
+
+#[derive(Template)]
+#[template(path = "tokens.html")]
+pub struct TokensTemplate {
+    tokens: Vec<tokens::ApiToken>,
+}
+
+#[derive(Template)]
+#[template(path = "token_created.html")]
+pub struct TokenCreatedTemplate {
+    token: String,
+}
+
+/// The token form sends a checkbox per scope.
+#[derive(Deserialize)]
+pub struct CreateTokenReq {
+    name: String,
+    read: Option<String>,
+    write: Option<String>,
+    expires_in_days: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeTokenReq {
+    token_id: i64,
+}
+
+#[derive(Deserialize)]
+pub struct MoveFileReq {
+    new_path: String,
+}
+
+#[derive(Deserialize)]
+pub struct FolderReq {
+    #[serde(default)]
+    path: String,
+}
+
+#[derive(Deserialize)]
+pub struct RenameFolderReq {
+    path: String,
+    new_name: String,
+}
+
+#[derive(Deserialize)]
+pub struct MoveFolderReq {
+    path: String,
+    #[serde(default)]
+    new_parent: String,
+}
+
+#[derive(Template)]
+#[template(path = "folder.html")]
+pub struct FolderTemplate {
+    current: FolderLink,
+    parent: Option<FolderLink>,
+    folders: Vec<FolderLink>,
+    files: Vec<folders::FileEntry>,
+}
+
+/// A subfolder entry, `vals` is the `hx-vals` payload that opens it.
+pub struct FolderLink {
+    name: String,
+    vals: String,
+}
+
+impl FolderLink {
+    fn new(name: &str, components: &[&str]) -> Self {
+        FolderLink {
+            name: name.to_string(),
+            vals: serde_json::json!({ "path": components.join("/") }).to_string(),
+        }
+    }
+}
+
 pub struct DatabaseRow {
+    pub file_id: i64,
+    pub owner: u32,
     pub file_name: String,
+    pub meta: FileMeta,
     pub salt: String,
+    pub kdf_iterations: u32,
+    pub blob: String,
+    /// Sealed by the client, see [`Sealing::Client`].
+    pub end_to_end: bool,
 }
 
-pub async fn home() -> Html<String> {
-    let mut handle = std::fs::File::open("./assets/html/home.html").unwrap();
-    let mut buffer = String::new();
-    let _ = handle.read_to_string(&mut buffer).unwrap();
-    axum::response::Html(buffer)
+impl DatabaseRow {
+    /// The server never decrypts end-to-end encrypted files, not even when
+    /// handed the right password.
+    fn require_server_side(&self) -> Result<(), SenmonError> {
+        match self.end_to_end {
+            true => Err(SenmonError::EndToEnd),
+            false => Ok(()),
+        }
+    }
+
+    /// What the file's blob is bound to.
+    fn binding(&self) -> container::Binding {
+        container::Binding {
+            owner: self.owner,
+            file_id: self.file_id,
+        }
+    }
+}
+
+pub async fn home(State(config): State<Arc<Config>>) -> Result<Html<String>, SenmonError> {
+    Ok(Html(std::fs::read_to_string(
+        config.asset_path("html/home.html"),
+    )?))
 }
 
-pub async fn icon() -> (TypedHeader<ContentType>, Vec<u8>) {
-    let mut handle = std::fs::File::open("./assets/icons/favicon.ico").unwrap();
-    let mut buffer = Vec::new();
-    let _ = handle.read_to_end(&mut buffer).unwrap();
-    (
+pub async fn icon(
+    State(config): State<Arc<Config>>,
+) -> Result<(TypedHeader<ContentType>, Vec<u8>), SenmonError> {
+    Ok((
         TypedHeader(ContentType::png()),
-        buffer
-    )
+        std::fs::read(config.asset_path("icons/favicon.ico"))?,
+    ))
+}
+
+pub fn session_id(jar: &CookieJar) -> Option<u32> {
+    jar.get("session")?.value().parse().ok()
+}
+
+/// Resolves the `session` cookie to the id and name of the logged in user.
+pub async fn session_user(db: &db::DatabaseConnection, jar: &CookieJar) -> Option<(u32, String)> {
+    let session_id = self::session_id(jar)?;
+    let user_name = get_user_from_session_id(db, session_id).await?;
+    let user_id = db::get_user_id(db, &user_name).await.ok()?;
+    logging::record_user(user_id);
+    Some((user_id, user_name))
+}
+
+pub async fn session_user_id(db: &db::DatabaseConnection, jar: &CookieJar) -> Option<u32> {
+    session_user(db, jar).await.map(|(user_id, _)| user_id)
+}
+
+/// Like [`session_user_id`], for handlers that need a logged in user.
+pub async fn require_user(db: &db::DatabaseConnection, jar: &CookieJar) -> Result<u32, SenmonError> {
+    session_user_id(db, jar)
+        .await
+        .ok_or(SenmonError::Unauthenticated)
+}
+
+fn hx_redirect(location: &str) -> Response {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("HX-Redirect", location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Tells the folder views to reload.
+fn folders_changed() -> Response {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("HX-Trigger", "folders-changed")
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Tells the token list to reload.
+fn tokens_changed(response: impl IntoResponse) -> Response {
+    let mut response = response.into_response();
+    response
+        .headers_mut()
+        .insert("HX-Trigger", "tokens-changed".parse().unwrap());
+    response
+}
+
+pub async fn file_health(
+    State(db): State<db::DatabaseConnection>,
+    jar: CookieJar,
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    if !db::is_admin(&db, user_id).await {
+        return Err(SenmonError::Forbidden);
+    }
+    Ok(Json(db::get_unhealthy_blobs(&db).await?).into_response())
+}
+
+pub async fn delete_file(
+    State(db): State<db::DatabaseConnection>,
+    State(keyring): State<Keyring>,
+    jar: CookieJar,
+    Form(req): Form<DeleteReq>,
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    let file_id = folders::resolve_file(&db, &keyring, user_id, &req.file_name).await?;
+    trash::trash_file(&db, user_id, file_id).await?;
+    Ok(hx_redirect("/assets/html/land.html"))
+}
+
+pub async fn list_trash(
+    State(db): State<db::DatabaseConnection>,
+    State(keyring): State<Keyring>,
+    jar: CookieJar,
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    let entries = trash::list_trash(&db, &keyring, user_id).await?;
+    Ok(TrashTemplate { entries }.into_response())
+}
+
+pub async fn restore_file(
+    State(db): State<db::DatabaseConnection>,
+    jar: CookieJar,
+    Form(req): Form<RestoreReq>,
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    trash::restore(&db, user_id, req.trash_id).await?;
+    Ok(hx_redirect("/assets/html/land.html"))
+}
+
+pub async fn list_tokens(
+    State(db): State<db::DatabaseConnection>,
+    jar: CookieJar,
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    let tokens = tokens::list(&db, user_id).await?;
+    Ok(TokensTemplate { tokens }.into_response())
+}
+
+pub async fn create_token(
+    State(db): State<db::DatabaseConnection>,
+    jar: CookieJar,
+    Form(req): Form<CreateTokenReq>,
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    let scopes: Vec<Scope> = [(req.read, Scope::Read), (req.write, Scope::Write)]
+        .into_iter()
+        .filter_map(|(checked, scope)| checked.map(|_| scope))
+        .collect();
+    let lifetime = req.expires_in_days.unwrap_or(tokens::DEFAULT_LIFETIME_DAYS);
+    let (token, _) = tokens::create(&db, user_id, &req.name, &scopes, lifetime).await?;
+    Ok(tokens_changed(TokenCreatedTemplate { token }))
+}
+
+pub async fn revoke_token(
+    State(db): State<db::DatabaseConnection>,
+    jar: CookieJar,
+    Form(req): Form<RevokeTokenReq>,
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    tokens::revoke(&db, user_id, req.token_id).await?;
+    Ok(tokens_changed(StatusCode::OK))
+}
+
+pub async fn move_file(
+    State(db): State<db::DatabaseConnection>,
+    State(keyring): State<Keyring>,
+    jar: CookieJar,
+    Path(path): Path<String>,
+    Form(req): Form<MoveFileReq>,
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    folders::move_file(&db, &keyring, user_id, &path, &req.new_path).await?;
+    Ok(folders_changed())
+}
+
+pub async fn list_folder(
+    State(db): State<db::DatabaseConnection>,
+    State(keyring): State<Keyring>,
+    jar: CookieJar,
+    Query(req): Query<FolderReq>,
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    let components = folders::split_path(&req.path)?;
+    let listing = folders::list_folder(&db, &keyring, user_id, &req.path).await?;
+    Ok(FolderTemplate {
+        current: FolderLink::new(&components.join("/"), &components),
+        parent: components
+            .split_last()
+            .map(|(_, parent)| FolderLink::new("..", parent)),
+        folders: listing
+            .folders
+            .iter()
+            .map(|name| FolderLink::new(name, &[components.as_slice(), &[name.as_str()]].concat()))
+            .collect(),
+        files: listing.files,
+    }
+    .into_response())
+}
+
+pub async fn create_folder(
+    State(db): State<db::DatabaseConnection>,
+    State(keyring): State<Keyring>,
+    jar: CookieJar,
+    Form(req): Form<FolderReq>,
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    folders::create_folder(&db, &keyring, user_id, &req.path).await?;
+    Ok(folders_changed())
+}
+
+pub async fn rename_folder(
+    State(db): State<db::DatabaseConnection>,
+    State(keyring): State<Keyring>,
+    jar: CookieJar,
+    Form(req): Form<RenameFolderReq>,
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    folders::rename_folder(&db, &keyring, user_id, &req.path, &req.new_name).await?;
+    Ok(folders_changed())
+}
+
+pub async fn move_folder(
+    State(db): State<db::DatabaseConnection>,
+    State(keyring): State<Keyring>,
+    jar: CookieJar,
+    Form(req): Form<MoveFolderReq>,
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    folders::move_folder(&db, &keyring, user_id, &req.path, &req.new_parent).await?;
+    Ok(folders_changed())
+}
+
+pub async fn delete_folder(
+    State(db): State<db::DatabaseConnection>,
+    State(keyring): State<Keyring>,
+    jar: CookieJar,
+    Form(req): Form<FolderReq>,
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    folders::delete_folder(&db, &keyring, user_id, &req.path).await?;
+    Ok(folders_changed())
 }
 
 pub async fn download_file(
-    axum::extract::State(state): axum::extract::State<db::DatabaseConnection>,
+    State(state): State<db::DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    State(keyring): State<Keyring>,
     jar: CookieJar,
+    headers: HeaderMap,
     Form(download_request): Form<DownloadReq>,
-) -> axum::response::Response<Body> {
-    let session_id: u32;
-    if let Some(cookie) = jar.get("session") {
-        session_id = cookie.value().parse().unwrap();
-        if !is_present_session(&state, session_id).await {
-            return axum::response::Response::builder()
-                .status(StatusCode::UNAUTHORIZED)
-                .header("HX-Redirect", "/assets/html/home.html")
-                .body(Body::empty())
-                .unwrap();
-        }
-    } else {
-        return axum::response::Response::builder()
-            .status(StatusCode::UNAUTHORIZED)
-            .header("HX-Redirect", "/assets/html/home.html")
-            .body(Body::empty())
-            .unwrap();
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&state, &jar).await?;
+    let file_id =
+        folders::resolve_file(&state, &keyring, user_id, &download_request.file_name).await?;
+    let db_row = file_row(&state, &keyring, user_id, file_id).await?;
+
+    let secret = FileSecret::Password(&download_request.password);
+    serve_file(&state, &config, &headers, db_row, secret).await
+}
+
+#[derive(Deserialize)]
+pub struct ContentQuery {
+    unlock: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UnlockReq {
+    pub password: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UnlockResponse {
+    token: String,
+    expires_at: String,
+}
+
+/// What the client handed over to open a file: the file password, or the
+/// key cached behind an unlock token.
+pub enum FileSecret<'a> {
+    Password(&'a str),
+    Key(Zeroizing<[u8; 32]>),
+}
+
+/// Extracts the file id from `{id}/{action}`, the tail of the
+/// `/api/files/*path` wildcard that the id based routes share with
+/// `PATCH /api/files/{path}`.
+fn file_action(path: &str, action: &str) -> Result<i64, SenmonError> {
+    path.strip_suffix(action)
+        .and_then(|rest| rest.strip_suffix('/'))
+        .and_then(|id| id.parse().ok())
+        .ok_or(SenmonError::NotFound)
+}
+
+pub async fn file_row(
+    state: &db::DatabaseConnection,
+    keyring: &Keyring,
+    user_id: u32,
+    file_id: i64,
+) -> Result<DatabaseRow, SenmonError> {
+    let keyring = keyring.clone();
+    state
+        .run(move |cnx| {
+            let key = keyring.user_key(cnx, user_id)?;
+            cnx.query_row(
+                "SELECT name_sealed, meta_sealed, salt, kdf_iterations, blob, e2e FROM file_state WHERE file_owner = ?1 AND file_id = ?2;",
+                (user_id, file_id),
+                |row| {
+                    Ok(DatabaseRow {
+                        file_id,
+                        owner: user_id,
+                        file_name: key.open_name(Field::FileName, &row.get::<_, String>(0)?)?,
+                        meta: key.open_meta(row.get::<_, Option<String>>(1)?.as_deref())?,
+                        salt: row.get(2)?,
+                        kdf_iterations: row.get(3)?,
+                        blob: row.get(4)?,
+                        end_to_end: row.get(5)?,
+                    })
+                },
+            )
+            .map_err(SenmonError::from)
+        })
+        .await
+}
+
+/// Runs the deliberately slow key derivation on the blocking thread pool.
+pub async fn derive_key(password: &str, db_row: &DatabaseRow) -> Zeroizing<[u8; 32]> {
+    let (password, salt, iterations) = (
+        Zeroizing::new(password.to_string()),
+        db_row.salt.clone(),
+        db_row.kdf_iterations,
+    );
+    tokio::task::spawn_blocking(move || {
+        Zeroizing::new(metrics::time(&METRICS.kdf_seconds, || {
+            container::derive_key(&password, &salt, iterations)
+        }))
+    })
+    .await
+    .unwrap()
+}
+
+/// Picks the secret a download request carries: an unlock token from
+/// `X-Unlock-Token` or the query, else the password in `X-Unlock-Password`.
+/// `None` when the request carries neither.
+pub fn request_secret<'a>(
+    keys: &unlock::UnlockKeys,
+    headers: &'a HeaderMap,
+    query_token: Option<String>,
+    holder: unlock::Holder,
+    file_id: i64,
+) -> Result<Option<FileSecret<'a>>, SenmonError> {
+    let token = headers
+        .get("X-Unlock-Token")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or(query_token);
+    let password = headers
+        .get("X-Unlock-Password")
+        .and_then(|v| std::str::from_utf8(v.as_bytes()).ok());
+    match (token, password) {
+        (Some(token), _) => Ok(Some(FileSecret::Key(
+            keys.get(&token, holder, file_id)
+                .ok_or(SenmonError::WrongFileKey)?,
+        ))),
+        (None, Some(password)) => Ok(Some(FileSecret::Password(password))),
+        (None, None) => Ok(None),
     }
+}
 
-    // TODO: combine both get_user_from_session_id and get_user_id;
-    let user_name: String;
-    if let Some(s) = db::get_user_from_session_id(&state, session_id).await {
-        user_name = s;
-    } else {
-        return axum::response::Response::builder()
+/// `GET /api/files/{id}/content` downloads a file by id. The file is opened
+/// with an unlock token from `X-Unlock-Token` or the `unlock` query
+/// parameter, or with the file password in `X-Unlock-Password`, so the URL
+/// can be used from tools such as curl or media players.
+#[allow(clippy::too_many_arguments)]
+pub async fn file_content(
+    State(state): State<db::DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    State(keys): State<unlock::UnlockKeys>,
+    State(keyring): State<Keyring>,
+    jar: CookieJar,
+    headers: HeaderMap,
+    Path(path): Path<String>,
+    Query(query): Query<ContentQuery>,
+) -> Result<Response, SenmonError> {
+    let file_id = file_action(&path, "content")?;
+    let session_id = session_id(&jar).ok_or(SenmonError::Unauthenticated)?;
+    let user_id = require_user(&state, &jar).await?;
+
+    let holder = unlock::Holder::Session(session_id);
+    let Some(secret) = request_secret(&keys, &headers, query.unlock, holder, file_id)? else {
+        return Ok(Response::builder()
             .status(StatusCode::UNAUTHORIZED)
-            .header("HX-Redirect", "/assets/html/home.html")
+            .header(header::WWW_AUTHENTICATE, "X-Unlock-Token")
             .body(Body::empty())
-            .unwrap();
+            .unwrap());
+    };
+
+    let db_row = file_row(&state, &keyring, user_id, file_id).await?;
+    serve_file(&state, &config, &headers, db_row, secret).await
+}
+
+/// `POST /api/files/{id}/unlock` derives the file key once and returns a
+/// token that opens the file for this session until it expires.
+pub async fn unlock_file(
+    State(state): State<db::DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    State(keys): State<unlock::UnlockKeys>,
+    State(keyring): State<Keyring>,
+    jar: CookieJar,
+    Path(path): Path<String>,
+    Form(req): Form<UnlockReq>,
+) -> Result<Response, SenmonError> {
+    let file_id = file_action(&path, "unlock")?;
+    let session_id = session_id(&jar).ok_or(SenmonError::Unauthenticated)?;
+    let user_id = require_user(&state, &jar).await?;
+    let holder = unlock::Holder::Session(session_id);
+    let unlocked = unlock_with_password(
+        &state,
+        &config,
+        &keys,
+        &keyring,
+        holder,
+        user_id,
+        file_id,
+        &req.password,
+    )
+    .await?;
+    Ok(Json(unlocked).into_response())
+}
+
+/// Checks `password` against a file and caches the derived key behind a new
+/// unlock token bound to `holder`. Shared with
+/// `POST /api/v1/files/{id}/unlock`.
+#[allow(clippy::too_many_arguments)]
+pub async fn unlock_with_password(
+    state: &db::DatabaseConnection,
+    config: &Arc<Config>,
+    keys: &unlock::UnlockKeys,
+    keyring: &Keyring,
+    holder: unlock::Holder,
+    user_id: u32,
+    file_id: i64,
+    password: &str,
+) -> Result<UnlockResponse, SenmonError> {
+    let db_row = file_row(state, keyring, user_id, file_id).await?;
+    db_row.require_server_side()?;
+
+    let mut blob = std::fs::File::open(config.blob_path(&db_row.blob))?;
+    check_format(config, &mut blob)?;
+
+    let key = derive_key(password, &db_row).await;
+    let binding = db_row.binding();
+    let (key, opened) = tokio::task::spawn_blocking(move || {
+        let opened = container::verify_key(&key, binding, &mut blob);
+        (key, opened)
+    })
+    .await
+    .unwrap();
+    if !opened {
+        return Err(SenmonError::WrongFileKey);
     }
 
-    let user_id: u32;
-    if let Ok(id) = db::get_user_id(&state, &user_name).await {
-        user_id = id;
-    } else {
-        return axum::response::Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .header("HX-Redirect", "/assets/html/home.html")
-            .body(Body::empty())
-            .unwrap();
+    let (token, expires_at) = keys.insert(holder, file_id, *key);
+    Ok(UnlockResponse {
+        token,
+        expires_at: expires_at.to_rfc3339(),
+    })
+}
+
+/// Whether a blob is bound to its file, see [`container`]. Unbound blobs are
+/// refused unless `accept_legacy_blobs` is on. Leaves `blob` at its start.
+fn check_format(config: &Config, blob: &mut std::fs::File) -> Result<bool, SenmonError> {
+    let mut header = [0u8; container::HEADER_LEN];
+    let bound = blob.read_exact(&mut header).is_ok()
+        && container::Header::parse(&header).is_some_and(|header| header.is_bound());
+    blob.rewind()?;
+    if !bound && !config.accept_legacy_blobs {
+        return Err(SenmonError::LegacyFormat);
     }
+    Ok(bound)
+}
 
-    let cnx = state.ctx.deref().lock().unwrap();
-    let db_row = cnx.query_row(
-        r#"SELECT file_name, salt FROM file_state WHERE file_owner = ?1 AND file_name=(?2);"#,
-        (user_id, &download_request.file_name),
-        |row| {
-            Ok(DatabaseRow {
-                file_name: row.get(0).unwrap(),
-                salt: row.get(1).unwrap(),
-            })
+/// Decrypts and sends a stored file, honouring `Range`, `If-None-Match` and
+/// `If-Modified-Since`. Files whose blob is not bound to them yet have it
+/// rewritten in the background once it opened.
+pub async fn serve_file(
+    state: &db::DatabaseConnection,
+    config: &Arc<Config>,
+    headers: &HeaderMap,
+    db_row: DatabaseRow,
+    secret: FileSecret<'_>,
+) -> Result<Response, SenmonError> {
+    db_row.require_server_side()?;
+    let mut blob = std::fs::File::open(config.blob_path(&db_row.blob))?;
+    let bound = check_format(config, &mut blob)?;
+
+    // A blob is never changed in place. Resealing a legacy blob writes a
+    // new one with a checksum of its own, so the ETag changes once then and
+    // the client fetches the same contents again. The stored checksum and
+    // the modification time describe exactly one blob.
+    let etag: Option<ETag> = db::get_checksum(state, &db_row.blob)
+        .await
+        .and_then(|checksum| format!("\"{}\"", checksum).parse().ok());
+    let modified = blob.metadata().and_then(|m| m.modified()).ok();
+    let not_modified = match (headers.typed_get::<IfNoneMatch>(), &etag) {
+        (Some(if_none_match), Some(etag)) => !if_none_match.precondition_passes(etag),
+        (Some(_), None) => false,
+        (None, _) => match (headers.typed_get::<IfModifiedSince>(), modified) {
+            (Some(since), Some(modified)) => !since.is_modified(modified),
+            _ => false,
         },
-    );
-    let db_row = match db_row {
-        Ok(x) => x,
-        Err(_) => {
-            return axum::response::Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .header("HX-Redirect", "/assets/html/home.html")
-                .body(Body::empty())
-                .unwrap();
-        }
     };
 
-    let mut root = std::path::PathBuf::from("./stash");
+    let mut response = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(
+            header::CONTENT_DISPOSITION,
+            content_disposition(&db_row.file_name),
+        );
+    let response_headers = response.headers_mut().unwrap();
+    if let Some(etag) = etag {
+        response_headers.typed_insert(etag);
+    }
+    if let Some(modified) = modified {
+        response_headers.typed_insert(LastModified::from(modified));
+    }
 
-    let path = std::path::PathBuf::from(&db_row.file_name);
-    let count = path.components().count();
-    if count > 1 {
-        return axum::response::Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .header("HX-Redirect", "/assets/html/home.html")
+    let key = match secret {
+        FileSecret::Password(password) => derive_key(password, &db_row).await,
+        FileSecret::Key(key) => key,
+    };
+    let binding = db_row.binding();
+    // Whether the file changed is only told to whoever can open it.
+    if not_modified {
+        let mut probe = blob.try_clone()?;
+        let opened =
+            tokio::task::spawn_blocking(move || container::verify_key(&key, binding, &mut probe))
+                .await
+                .unwrap();
+        if !opened {
+            return Err(SenmonError::WrongFileKey);
+        }
+        return Ok(response
+            .status(StatusCode::NOT_MODIFIED)
             .body(Body::empty())
-            .unwrap();
+            .unwrap());
     }
 
-    root = root.join(user_name).join(&path);
-    if !root.exists() {
-        return axum::response::Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .header("HX-Redirect", "/assets/html/home.html")
-            .body(Body::empty())
-            .unwrap();
+    let request_headers = headers.clone();
+    let (key, decrypted) = tokio::task::spawn_blocking(move || {
+        let decrypted = decrypt_file(&key, binding, blob, &request_headers);
+        (key, decrypted)
+    })
+    .await
+    .unwrap();
+    let (contents, range, total_len) = decrypted?;
+    if !bound {
+        spawn_reseal(state.clone(), config.clone(), key, binding, db_row.blob);
     }
 
-    let encrypted_string = std::fs::read_to_string(root).unwrap();
-    let encrypted_bytes_with_nonce = hex::decode(encrypted_string).unwrap();
-    let (nonce, encrypted_bytes) = encrypted_bytes_with_nonce.split_at(12);
-    let nonce = aes_gcm::Nonce::from_slice(nonce);
+    if let Some((start, end)) = range {
+        response = response.status(StatusCode::PARTIAL_CONTENT).header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end, total_len),
+        );
+    } else {
+        response = response.status(StatusCode::OK);
+    }
+    METRICS.downloads.inc();
+    METRICS.bytes_out.inc_by(contents.len() as u64);
+    Ok(response
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .body(Body::from(contents))
+        .unwrap())
+}
 
-    let mut password_hash: [u8; 32] = [0; 32];
-    ring::pbkdf2::derive(
-        ring::pbkdf2::PBKDF2_HMAC_SHA512,
-        NonZeroU32::new(600_000).unwrap(),
-        db_row.salt.as_bytes(),
-        download_request.password.as_bytes(),
-        &mut password_hash,
-    );
-    let key = aes_gcm::Key::<aes_gcm::Aes256Gcm>::from_slice(&password_hash);
-    let cipher = aes_gcm::Aes256Gcm::new(key);
-    let text = cipher.decrypt(nonce, encrypted_bytes).unwrap();
-
-    match String::from_utf8(text) {
-        Ok(x) => {
-            return axum::response::Response::builder()
-                .status(StatusCode::OK)
-                .header(header::CONTENT_TYPE, "text/plain")
-                .header(
-                    header::CONTENT_DISPOSITION,
-                    format!("attachment; filename={}", &db_row.file_name),
-                )
-                .body(Body::new(x))
-                .unwrap();
+/// Decrypts the part of `blob` that the request asked for, returning it with
+/// the range it covers and the length of the whole file. Chunked blobs only
+/// have the chunks covering the range opened, legacy blobs are always
+/// decrypted whole.
+#[allow(clippy::type_complexity)]
+fn decrypt_file(
+    key: &[u8; 32],
+    binding: container::Binding,
+    mut blob: std::fs::File,
+    headers: &HeaderMap,
+) -> Result<(Vec<u8>, Option<(u64, u64)>, u64), SenmonError> {
+    let mut magic = [0u8; container::HEADER_LEN];
+    let header_read = blob.read_exact(&mut magic).is_ok();
+    let chunked = header_read
+        .then(|| container::Header::parse(&magic))
+        .flatten()
+        .map(|header| header.bind(binding));
+
+    let mut legacy_plaintext = Vec::new();
+    let total_len = match &chunked {
+        Some(chunked) => chunked.plaintext_len,
+        None => {
+            let mut contents = Vec::new();
+            blob.seek(std::io::SeekFrom::Start(0))?;
+            blob.read_to_end(&mut contents)?;
+            legacy_plaintext = Some(contents)
+                .filter(|contents| !container::is_chunked(contents))
+                .and_then(|contents| container::decrypt_legacy(key, &contents))
+                .ok_or(SenmonError::WrongFileKey)?;
+            legacy_plaintext.len() as u64
         }
-        Err(_) => {
-            return axum::response::Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .header("HX-Redirect", "/assets/html/home.html")
-                .body(Body::empty())
-                .unwrap();
+    };
+
+    let range = requested_range(headers, total_len)
+        .map_err(|()| SenmonError::RangeNotSatisfiable(total_len))?;
+
+    let (start, end) = range.unwrap_or((0, total_len.saturating_sub(1)));
+    let contents = match &chunked {
+        Some(chunked) if total_len == 0 => container::opens(key, chunked, &mut blob).then(Vec::new),
+        Some(chunked) => container::decrypt_range(key, chunked, &mut blob, start, end),
+        None if total_len == 0 => Some(Vec::new()),
+        None => Some(legacy_plaintext[start as usize..=end as usize].to_vec()),
+    };
+    let contents = contents.ok_or(SenmonError::WrongFileKey)?;
+    Ok((contents, range, total_len))
+}
+
+/// Builds an RFC 6266 `Content-Disposition` value. The quoted `filename` is
+/// an ASCII fallback, `filename*` carries the real name percent-encoded as
+/// RFC 8187 describes.
+pub fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::new();
+    for byte in file_name.bytes() {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
         }
     }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+/// Resolves a `Range` header against a file of `len` bytes into an inclusive
+/// byte range. Only single ranges are honoured, anything else is answered
+/// with the whole file as RFC 9110 allows. `Err` means the range can not be
+/// satisfied.
+pub fn requested_range(headers: &HeaderMap, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(range) = headers.typed_get::<Range>() else {
+        return Ok(None);
+    };
+    let mut ranges = range.satisfiable_ranges(len);
+    let (Some((start, end)), None) = (ranges.next(), ranges.next()) else {
+        return Ok(None);
+    };
+    let start = match start {
+        Bound::Included(start) => start,
+        Bound::Excluded(start) => start + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match end {
+        Bound::Included(end) => end.min(len.saturating_sub(1)),
+        Bound::Excluded(end) => end.saturating_sub(1).min(len.saturating_sub(1)),
+        Bound::Unbounded => len.saturating_sub(1),
+    };
+    if start >= len || start > end {
+        return Err(());
+    }
+    Ok(Some((start, end)))
 }
 
+/// Picks a fresh path for a blob relative to `./stash`. Blob names are random
+/// so that renaming, moving or trashing a file never touches the filesystem,
+/// and say nothing about the file or its owner. The first byte of the id
+/// names a directory, which keeps directories small.
+pub fn new_blob_name() -> String {
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill(&mut id);
+    let id = hex::encode(id);
+    format!("{}/{}", &id[..2], id)
+}
+
+/// Whether `blob` is a path [`new_blob_name`] would pick. Older blobs live
+/// under their owner's name.
+pub fn is_opaque_blob_name(blob: &str) -> bool {
+    let Some((dir, id)) = blob.split_once('/') else {
+        return false;
+    };
+    let is_hex = id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    id.len() == 32 && is_hex && dir.len() == 2 && id.starts_with(dir)
+}
+
+/// Client chosen salts are stored as they are, so keep them reasonable.
+const MAX_SALT_LEN: usize = 128;
+
 pub fn generate_salt() -> String {
     let salt: String = rand::thread_rng()
         .sample_iter(&rand::distributions::Alphanumeric)
@@ -188,165 +884,506 @@ pub fn generate_salt() -> String {
     salt
 }
 
-#[axum::debug_handler]
 pub async fn upload_file(
-    axum::extract::State(db): axum::extract::State<db::DatabaseConnection>,
+    State(db): State<db::DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    State(keyring): State<Keyring>,
     jar: CookieJar,
     form_input: axum::extract::Multipart,
-) -> axum::response::Response {
-    let user_name: String;
-    let ssn_id: u32;
-
-    if let Some(cookie) = jar.get("session") {
-        let session_id = cookie.value();
-        ssn_id = session_id.parse().unwrap();
-        if !is_present_session(&db, ssn_id).await {
-            return axum::response::Response::builder()
-                .status(StatusCode::UNAUTHORIZED)
-                .header("HX-Redirect", "/assets/html/home.html")
-                .body(Body::empty())
-                .unwrap();
-        } else {
-            if let Some(x) = get_user_from_session_id(&db, ssn_id).await {
-                user_name = x;
-            } else {
-                return axum::response::Response::builder()
-                    .status(StatusCode::UNAUTHORIZED)
-                    .header("HX-Redirect", "/assets/html/home.html")
-                    .body(Body::empty())
-                    .unwrap();
-            }
-        }
-    } else {
-        return axum::response::Response::builder()
-            .status(StatusCode::UNAUTHORIZED)
-            .header("HX-Redirect", "/assets/html/home.html")
-            .body(Body::empty())
-            .unwrap();
+) -> Result<Response, SenmonError> {
+    let user_id = require_user(&db, &jar).await?;
+    let req = parse_multipart(form_input).await?;
+    store_upload(&db, &config, &keyring, user_id, req).await?;
+    Ok(hx_redirect("/assets/html/land.html"))
+}
+
+/// Encrypts an upload, or checks the framing of one the client sealed,
+/// writes its blob and registers it in `file_state` with its name and
+/// metadata sealed, returning the new file id. The id is reserved up front
+/// so that the blob can be bound to it. Shared by the multipart form,
+/// resumable uploads and the JSON API.
+pub async fn store_upload(
+    db: &db::DatabaseConnection,
+    config: &Config,
+    keyring: &Keyring,
+    user_id: u32,
+    req: UploadFile,
+) -> Result<i64, SenmonError> {
+    if !folders::is_valid_name(&req.file_name) {
+        return Err(SenmonError::BadRequest);
     }
+    let folder_id = folders::resolve_folder(db, keyring, user_id, &req.folder).await?;
 
-    let req = match parse_multipart(form_input).await {
-        Ok(r) => r,
-        Err(_) => {
-            return axum::response::Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .header("HX-Redirect", "/assets/html/home.html")
-                .body(Body::empty())
-                .unwrap();
+    let blob = new_blob_name();
+    let file_id = db::reserve_file_id(db).await?;
+    let binding = container::Binding {
+        owner: user_id,
+        file_id,
+    };
+    let UploadFile {
+        file_name,
+        salt,
+        file_contents,
+        content_type,
+        sealing,
+        ..
+    } = req;
+    let (iterations, end_to_end) = match &sealing {
+        Sealing::Password(_) => (config.kdf_iterations, false),
+        Sealing::Client { kdf_iterations } => (*kdf_iterations, true),
+    };
+
+    // The blob is durable before its row is committed. A crash in between
+    // leaves an unreferenced blob for `senmon gc`, never a row without data.
+    let staged = new_staging_path(config);
+    let path = config.blob_path(&blob);
+    let (plaintext_len, checksum) = tokio::task::spawn_blocking({
+        let (path, salt) = (path.clone(), salt.clone());
+        move || {
+            persist_blob(&staged, &path, |out| {
+                write_upload(file_contents, &sealing, &salt, iterations, binding, out)
+            })
         }
+    })
+    .await
+    .unwrap()?;
+    let meta = FileMeta {
+        size: Some(plaintext_len),
+        content_type,
     };
 
-    let user_id: u32;
-    if let Ok(id) = db::get_user_id(&db, &user_name).await {
-        user_id = id;
-    } else {
-        return axum::response::Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .header("HX-Redirect", "/assets/html/home.html")
-            .body(Body::empty())
-            .unwrap();
+    let keyring = keyring.clone();
+    let result = db
+        .run(move |cnx| {
+            let tx = cnx.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+            let key = keyring.user_key(&tx, user_id)?;
+            let (name_tag, name_sealed) = key.seal_name(Field::FileName, &file_name);
+            tx.execute(
+                "INSERT INTO file_state(file_id, file_owner, folder_id, name_tag, name_sealed, meta_sealed, salt, kdf_iterations, blob, e2e) VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                (file_id, user_id, folder_id, name_tag, name_sealed, key.seal_meta(&meta), salt, iterations, &blob, end_to_end),
+            )?;
+            tx.execute(
+                "INSERT INTO file_health(blob, checksum, status, checked_at) VALUES(?1, ?2, 'ok', ?3)
+                ON CONFLICT(blob) DO UPDATE SET checksum = excluded.checksum, status = excluded.status, checked_at = excluded.checked_at;",
+                (&blob, checksum, chrono::Utc::now().to_rfc2822()),
+            )?;
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(file_id)
+        })
+        .await
+        .map_err(SenmonError::from);
+    match result {
+        Ok(_) => {
+            METRICS.uploads.inc();
+            METRICS.bytes_in.inc_by(plaintext_len);
+        }
+        Err(_) => {
+            let _ = std::fs::remove_file(&path);
+        }
     }
+    result
+}
 
-    let ctx = db.ctx.deref().lock().unwrap();
-    let res = encrypt_contents(req);
-    let _ = ctx
-        .execute(
-            "INSERT INTO file_state(file_owner, file_name, salt) VALUES(?1, ?2, ?3)",
-            (user_id, &res.file_name, &res.salt),
-        )
-        .unwrap();
-
-    let path = std::path::PathBuf::from(&res.file_name);
+/// A fresh path in the staging directory to write a blob to before it is
+/// moved into the stash.
+fn new_staging_path(config: &Config) -> std::path::PathBuf {
+    let mut part = [0u8; 16];
+    rand::thread_rng().fill(&mut part);
+    tus::staging_path(config, &format!("{}.part", hex::encode(part)))
+}
 
-    let count = path.components().count();
-    if count > 1 {
-        return axum::response::Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .header("HX-Redirect", "/assets/html/home.html")
-            .body(Body::empty())
-            .unwrap();
+/// Has `write` fill `staged`, flushes it to disk and moves it to `path`, so
+/// that `path` either does not exist or holds the whole blob.
+fn persist_blob<T, E: From<std::io::Error>>(
+    staged: &std::path::Path,
+    path: &std::path::Path,
+    write: impl FnOnce(&mut std::fs::File) -> Result<T, E>,
+) -> Result<T, E> {
+    let result = (|| {
+        let mut file = std::fs::File::create(staged)?;
+        let written = write(&mut file)?;
+        file.sync_all()?;
+        let parent = path.parent().unwrap_or(std::path::Path::new("."));
+        std::fs::create_dir_all(parent)?;
+        std::fs::rename(staged, path)?;
+        std::fs::File::open(parent)?.sync_all()?;
+        Ok(written)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(staged);
     }
+    result
+}
 
-    let mut root = std::path::PathBuf::from("./stash").join(&user_name);
-    match std::fs::exists(&root) {
-        Ok(true) => {}
-        Ok(false) => {
-            std::fs::create_dir(&root).unwrap();
+/// Writes the blob of an upload to `out`, encrypting it on the way unless
+/// the client sealed it, one chunk at a time. Returns the plaintext length
+/// and the blob's checksum.
+fn write_upload(
+    body: UploadBody,
+    sealing: &Sealing,
+    salt: &str,
+    iterations: u32,
+    binding: container::Binding,
+    out: &mut std::fs::File,
+) -> Result<(u64, String), SenmonError> {
+    let (mut source, len): (Box<dyn Read>, u64) = match body {
+        UploadBody::Memory(contents) => {
+            let len = contents.len() as u64;
+            (Box::new(std::io::Cursor::new(contents)), len)
         }
-        Err(_) => {
-            return axum::response::Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .header("HX-Redirect", "/assets/html/home.html")
-                .body(Body::empty())
-                .unwrap();
+        UploadBody::Staged(path) => {
+            let file = std::fs::File::open(path)?;
+            let len = file.metadata()?.len();
+            (Box::new(file), len)
         }
-    }
+    };
+    let mut out = scrub::ChecksumWriter::new(std::io::BufWriter::new(out));
+    let plaintext_len = match sealing {
+        Sealing::Password(password) => {
+            let key = Zeroizing::new(metrics::time(&METRICS.kdf_seconds, || {
+                container::derive_key(password, salt, iterations)
+            }));
+            container::encrypt_stream(&key, binding, len, &mut source, &mut out)?;
+            len
+        }
+        Sealing::Client { .. } => {
+            let mut header = [0u8; container::HEADER_LEN];
+            source
+                .read_exact(&mut header)
+                .map_err(|_| SenmonError::BadRequest)?;
+            let header =
+                container::check_sealed(&header, len).ok_or(SenmonError::BadRequest)?;
+            out.write_all(header.as_bytes())?;
+            std::io::copy(&mut source, &mut out)?;
+            header.plaintext_len
+        }
+    };
+    let (mut out, checksum) = out.finish();
+    out.flush()?;
+    Ok((plaintext_len, checksum))
+}
 
-    root = root.join(&path);
-    if let Err(_) = std::fs::write(root, &res.file_contents) {
-        return axum::response::Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .header("HX-Redirect", "/assets/html/home.html")
-            .body(Body::empty())
-            .unwrap();
-    }
+/// Rewrites a blob that is not bound to its file in the bound format, now
+/// that a download showed `key` opens it. The file only moves to the new
+/// blob if it still has the old one, so a file renamed, trashed or resealed
+/// by a concurrent download in the meantime is left alone.
+fn spawn_reseal(
+    db: db::DatabaseConnection,
+    config: Arc<Config>,
+    key: Zeroizing<[u8; 32]>,
+    binding: container::Binding,
+    old: String,
+) {
+    tokio::spawn(async move {
+        let file_id = binding.file_id;
+        match reseal(&db, &config, &key, binding, &old).await {
+            Ok(true) => tracing::info!(file_id, "bound blob to its file"),
+            Ok(false) => {}
+            Err(why) => tracing::warn!(file_id, "reseal: {why}"),
+        }
+    });
+}
 
-    return axum::response::Response::builder()
-        .status(StatusCode::OK)
-        .header("HX-Redirect", "/assets/html/land.html")
-        .body(Body::empty())
-        .unwrap();
+async fn reseal(
+    db: &db::DatabaseConnection,
+    config: &Config,
+    key: &Zeroizing<[u8; 32]>,
+    binding: container::Binding,
+    old: &str,
+) -> Result<bool, SenmonError> {
+    let new = new_blob_name();
+    let (old_path, new_path) = (config.blob_path(old), config.blob_path(&new));
+    let staged = new_staging_path(config);
+    let key = key.clone();
+    let checksum = tokio::task::spawn_blocking({
+        let new_path = new_path.clone();
+        move || {
+            let sealed = match std::fs::read(&old_path) {
+                Ok(sealed) => sealed,
+                // Resealed by a concurrent download.
+                Err(why) if why.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(why) => return Err(SenmonError::from(why)),
+            };
+            let plaintext = container::decrypt(&key, binding, &sealed)
+                .map(Zeroizing::new)
+                .ok_or(SenmonError::WrongFileKey)?;
+            let resealed = container::encrypt(&key, binding, &plaintext);
+            persist_blob(&staged, &new_path, |file| file.write_all(&resealed))?;
+            Ok(Some(scrub::blob_checksum(&resealed)))
+        }
+    })
+    .await
+    .unwrap()?;
+    let Some(checksum) = checksum else {
+        return Ok(false);
+    };
+
+    let swapped = db
+        .run({
+            let (old, new) = (old.to_string(), new.clone());
+            move |cnx| {
+                let tx = cnx.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+                let updated = tx.execute(
+                    "UPDATE file_state SET blob=?1 WHERE file_id=?2 AND blob=?3;",
+                    (&new, binding.file_id, &old),
+                )?;
+                if updated == 1 {
+                    tx.execute("DELETE FROM file_health WHERE blob=?1;", [&old])?;
+                    tx.execute(
+                        "INSERT INTO file_health(blob, checksum, status, checked_at) VALUES(?1, ?2, 'ok', ?3);",
+                        (&new, checksum, chrono::Utc::now().to_rfc2822()),
+                    )?;
+                }
+                tx.commit()?;
+                Ok::<_, rusqlite::Error>(updated == 1)
+            }
+        })
+        .await;
+    let blob_path = match swapped {
+        Ok(true) => config.blob_path(old),
+        Ok(false) | Err(_) => new_path,
+    };
+    let _ = std::fs::remove_file(blob_path);
+    Ok(swapped?)
 }
 
-pub fn encrypt_contents(mut request: UploadFile) -> UploadFile {
-    let mut password_hash: [u8; 32] = [0; 32];
-    ring::pbkdf2::derive(
-        ring::pbkdf2::PBKDF2_HMAC_SHA512,
-        NonZeroU32::new(600_000).unwrap(),
-        request.salt.as_bytes(),
-        request.password.as_bytes(),
-        &mut password_hash,
-    );
-    let key = aes_gcm::Key::<aes_gcm::Aes256Gcm>::from_slice(&password_hash);
-    let cipher = aes_gcm::Aes256Gcm::new(key);
-    let nonce = aes_gcm::Aes256Gcm::generate_nonce(aes_gcm::aead::OsRng);
-    let encrypted_contents = cipher
-        .encrypt(&nonce, request.file_contents.as_bytes())
-        .unwrap();
-    let mut encrypted_contents_with_nonce = nonce.to_vec();
-    encrypted_contents_with_nonce.extend(encrypted_contents);
-    request.file_contents = hex::encode(encrypted_contents_with_nonce);
-    request
+/// A body cut off by the size limit is reported as such, anything else is
+/// a malformed form.
+fn multipart_error(why: axum::extract::multipart::MultipartError) -> SenmonError {
+    match why.status() {
+        StatusCode::PAYLOAD_TOO_LARGE => SenmonError::TooLarge,
+        _ => SenmonError::BadRequest,
+    }
 }
 
+/// Reads the upload form: `file`, optionally `folder`, and either `pwd` for
+/// the server to encrypt with, or `salt` and `kdf_iterations` when `file` is
+/// already sealed by the client.
 pub async fn parse_multipart(
     mut form_response: axum::extract::Multipart,
-) -> Result<UploadFile, StatusCode> {
+) -> Result<UploadFile, SenmonError> {
     let mut file_name: String = String::new();
-    let mut file_contents: String = String::new();
-    let mut password: String = String::new();
-    while let Ok(Some(field)) = form_response.next_field().await {
+    let mut file_contents: Vec<u8> = Vec::new();
+    let mut content_type: Option<String> = None;
+    let mut password: Option<String> = None;
+    let mut folder: String = String::new();
+    let mut salt: Option<String> = None;
+    let mut kdf_iterations: Option<String> = None;
+    while let Some(field) = form_response.next_field().await.map_err(multipart_error)? {
         let field_name = field.name();
         match field_name {
             Some("file") => {
                 file_name = field.file_name().unwrap_or("default_file_name").to_string();
-                file_contents = field.text().await.unwrap_or("".to_string());
+                content_type = field.content_type().map(str::to_string);
+                file_contents = field.bytes().await.map_err(multipart_error)?.to_vec();
             }
             Some("pwd") => {
-                password = field.text().await.unwrap_or("default".to_string());
+                password = Some(field.text().await.map_err(multipart_error)?);
+            }
+            Some("folder") => {
+                folder = field.text().await.map_err(multipart_error)?;
+            }
+            Some("salt") => {
+                salt = Some(field.text().await.map_err(multipart_error)?);
             }
-            Some(_) => {
-                return Err(StatusCode::BAD_REQUEST);
+            Some("kdf_iterations") => {
+                kdf_iterations = Some(field.text().await.map_err(multipart_error)?);
             }
-            None => {
-                return Err(StatusCode::BAD_REQUEST);
+            Some(_) | None => {
+                return Err(SenmonError::BadRequest);
             }
         }
     }
+    let (sealing, salt) = upload_sealing(password, salt, kdf_iterations)?;
     Ok(UploadFile {
         file_name,
-        file_contents,
-        password,
-        salt: generate_salt(),
+        folder,
+        file_contents: UploadBody::Memory(file_contents),
+        content_type,
+        sealing,
+        salt,
     })
 }
+
+/// Works out who encrypts an upload from the fields it came with, which
+/// the multipart form and tus metadata share. A password and client side
+/// KDF parameters together are refused, so that a client can not believe
+/// it sealed a file the server could in fact read.
+pub fn upload_sealing(
+    password: Option<String>,
+    salt: Option<String>,
+    kdf_iterations: Option<String>,
+) -> Result<(Sealing, String), SenmonError> {
+    match (password, salt, kdf_iterations) {
+        (Some(password), None, None) => Ok((Sealing::Password(password), generate_salt())),
+        (None, Some(salt), Some(kdf_iterations)) => {
+            let kdf_iterations: u32 = kdf_iterations.parse().map_err(|_| SenmonError::BadRequest)?;
+            if kdf_iterations == 0 || salt.is_empty() || salt.len() > MAX_SALT_LEN {
+                return Err(SenmonError::BadRequest);
+            }
+            Ok((Sealing::Client { kdf_iterations }, salt))
+        }
+        _ => Err(SenmonError::BadRequest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDb;
+
+    fn range(value: &'static str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, value.parse().unwrap());
+        requested_range(&headers, len)
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(requested_range(&HeaderMap::new(), 100), Ok(None));
+        assert_eq!(range("bytes=0-9", 100), Ok(Some((0, 9))));
+        assert_eq!(range("bytes=90-", 100), Ok(Some((90, 99))));
+        assert_eq!(range("bytes=-10", 100), Ok(Some((90, 99))));
+        assert_eq!(range("bytes=50-500", 100), Ok(Some((50, 99))));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(range("bytes=100-", 100), Err(()));
+        assert_eq!(range("bytes=200-300", 100), Err(()));
+        assert_eq!(range("bytes=0-0", 0), Err(()));
+    }
+
+    #[test]
+    fn other_ranges_get_the_whole_file() {
+        assert_eq!(range("bytes=0-9,20-29", 100), Ok(None));
+        assert_eq!(range("lines=0-9", 100), Ok(None));
+    }
+
+    fn disposition(fallback: &str, encoded: &str) -> String {
+        format!("attachment; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+    }
+
+    #[test]
+    fn plain_names() {
+        assert_eq!(
+            content_disposition("report-2024_v1.0.pdf"),
+            disposition("report-2024_v1.0.pdf", "report-2024_v1.0.pdf")
+        );
+        assert_eq!(
+            content_disposition("a+b&c!#$^`|~"),
+            disposition("a+b&c!#$^`|~", "a+b&c!#$^`|~")
+        );
+    }
+
+    #[test]
+    fn quotes_and_backslashes() {
+        assert_eq!(
+            content_disposition("say \"hi\".txt"),
+            disposition("say _hi_.txt", "say%20%22hi%22.txt")
+        );
+        assert_eq!(
+            content_disposition("a\\b.txt"),
+            disposition("a_b.txt", "a%5Cb.txt")
+        );
+    }
+
+    #[test]
+    fn control_characters() {
+        assert_eq!(
+            content_disposition("a\r\nSet-Cookie: x\t\u{7f}"),
+            disposition("a__Set-Cookie: x__", "a%0D%0ASet-Cookie%3A%20x%09%7F")
+        );
+    }
+
+    #[test]
+    fn non_ascii_names() {
+        assert_eq!(
+            content_disposition("résumé.pdf"),
+            disposition("r_sum_.pdf", "r%C3%A9sum%C3%A9.pdf")
+        );
+        assert_eq!(
+            content_disposition("日本.txt"),
+            disposition("__.txt", "%E6%97%A5%E6%9C%AC.txt")
+        );
+        assert_eq!(content_disposition("😀"), disposition("_", "%F0%9F%98%80"));
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_are_unauthenticated() {
+        let test = TestDb::open().await;
+        let jar = test.login().await;
+        let list = || {
+            list_trash(
+                State(test.db.clone()),
+                State(test.keyring.clone()),
+                jar.clone(),
+            )
+        };
+        assert_eq!(list().await.into_response().status(), StatusCode::OK);
+
+        let past = (chrono::Utc::now() - chrono::Duration::seconds(1)).to_rfc3339();
+        {
+            let cnx = test.db.get().unwrap();
+            cnx.execute("UPDATE sessions SET expires=?1;", [past])
+                .unwrap();
+        }
+        assert_eq!(
+            list().await.into_response().status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    async fn store(test: &TestDb, contents: &[u8]) -> i64 {
+        crate::tus::clear_staging(&test.config).unwrap();
+        let upload = UploadFile {
+            file_name: "notes.txt".to_string(),
+            folder: String::new(),
+            file_contents: UploadBody::Memory(contents.to_vec()),
+            content_type: None,
+            sealing: Sealing::Password("pw".to_string()),
+            salt: generate_salt(),
+        };
+        store_upload(&test.db, &test.config, &test.keyring, test.user_id, upload)
+            .await
+            .unwrap()
+    }
+
+    async fn serve(
+        test: &TestDb,
+        file_id: i64,
+        password: &str,
+        headers: &HeaderMap,
+    ) -> Result<Response, SenmonError> {
+        let db_row = file_row(&test.db, &test.keyring, test.user_id, file_id).await?;
+        serve_file(
+            &test.db,
+            &test.config,
+            headers,
+            db_row,
+            FileSecret::Password(password),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn empty_files_check_the_password() {
+        let test = TestDb::open().await;
+        let file_id = store(&test, b"").await;
+        let mut cached = HeaderMap::new();
+        cached.insert(header::IF_NONE_MATCH, "*".parse().unwrap());
+
+        for headers in [HeaderMap::new(), cached.clone()] {
+            assert!(matches!(
+                serve(&test, file_id, "wrong", &headers).await,
+                Err(SenmonError::WrongFileKey)
+            ));
+        }
+        let response = serve(&test, file_id, "pw", &HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = serve(&test, file_id, "pw", &cached).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+}
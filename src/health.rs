@@ -0,0 +1,128 @@
+//! Probes for load balancers and orchestrators. `/healthz` only says the
+//! process is answering; `/readyz` says it can serve requests right now.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use rusqlite::TransactionBehavior;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::config::Config;
+use crate::db::DatabaseConnection;
+use crate::{migrations, tus};
+
+/// Cleared when shutdown starts so that traffic moves elsewhere while the
+/// in-flight requests drain.
+#[derive(Clone)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Readiness(Arc::new(AtomicBool::new(true)))
+    }
+}
+
+impl Readiness {
+    pub fn shutting_down(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ReadyReport {
+    ready: bool,
+    checks: BTreeMap<&'static str, String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "probes",
+    responses((status = 200, description = "The process is up", body = String))
+)]
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "probes",
+    responses(
+        (status = 200, description = "Ready to serve", body = ReadyReport),
+        (status = 503, description = "A check failed or shutdown has started", body = ReadyReport),
+    )
+)]
+pub async fn readyz(
+    State(db): State<DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    State(readiness): State<Readiness>,
+) -> (StatusCode, Json<ReadyReport>) {
+    let mut checks = BTreeMap::new();
+    checks.insert(
+        "shutdown",
+        outcome(if readiness.is_ready() { Ok(()) } else { Err("draining".to_string()) }),
+    );
+    checks.insert("database", outcome(database_writable(&db).await));
+    checks.insert("migrations", outcome(migrations_current(&db).await));
+    checks.insert("storage", outcome(storage_writable(config).await));
+
+    let ready = checks.values().all(|check| check == "ok");
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(ReadyReport { ready, checks }))
+}
+
+fn outcome(result: Result<(), String>) -> String {
+    match result {
+        Ok(()) => "ok".to_string(),
+        Err(why) => why,
+    }
+}
+
+/// Takes the write lock and lets go of it again without changing anything.
+async fn database_writable(db: &DatabaseConnection) -> Result<(), String> {
+    db.run(|cnx| {
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.rollback()
+    })
+    .await
+    .map_err(|why: rusqlite::Error| why.to_string())
+}
+
+async fn migrations_current(db: &DatabaseConnection) -> Result<(), String> {
+    let version = db
+        .run(|cnx| migrations::schema_version(cnx))
+        .await
+        .map_err(|why: rusqlite::Error| why.to_string())?;
+    match version == migrations::latest_version() {
+        true => Ok(()),
+        false => Err(format!(
+            "schema is at version {version}, expected {}",
+            migrations::latest_version()
+        )),
+    }
+}
+
+/// Writes and removes a probe file where uploads are staged, which is on
+/// the same file system as the blobs. Every probe has a name of its own, so
+/// concurrent probes do not remove each other's file.
+async fn storage_writable(config: Arc<Config>) -> Result<(), String> {
+    tokio::task::spawn_blocking(move || {
+        let suffix: u64 = rand::random();
+        let probe = tus::staging_path(&config, &format!(".readyz-{suffix:016x}"));
+        std::fs::write(&probe, b"")?;
+        std::fs::remove_file(&probe)
+    })
+    .await
+    .unwrap()
+    .map_err(|why| why.to_string())
+}
@@ -0,0 +1,365 @@
+//! Keys that file and folder names and file metadata are encrypted under at
+//! rest.
+//!
+//! Every user has a random key of their own, stored in `user_keys` wrapped
+//! with AES-256-GCM under the server's master key from `master_key_file`.
+//! Two subkeys are derived from it with HMAC-SHA256:
+//!
+//! - the seal key encrypts names (`name_sealed`) and metadata
+//!   (`meta_sealed`) with AES-256-GCM and a random nonce, the field as
+//!   associated data so that a name can not be passed off as metadata;
+//! - the index key gives every name a deterministic tag (`name_tag`), which
+//!   is what lookups and the uniqueness constraints compare. Tags reveal
+//!   that two of a user's names are equal, nothing else.
+//!
+//! The server still reads names for whoever is logged in, so this protects
+//! a copy of the database and stash, not a running server.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{AeadCore, Aes256Gcm};
+use rand::Rng;
+use ring::hmac;
+use rusqlite::types::Type;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::config::Config;
+use crate::container;
+
+const NONCE_LEN: usize = 12;
+
+/// What a sealed value or tag is, so that values of one kind can not be
+/// passed off as another.
+#[derive(Clone, Copy)]
+pub enum Field {
+    FileName,
+    FolderName,
+    FileMeta,
+}
+
+impl Field {
+    fn label(self) -> &'static [u8] {
+        match self {
+            Field::FileName => b"file name",
+            Field::FolderName => b"folder name",
+            Field::FileMeta => b"file metadata",
+        }
+    }
+}
+
+/// What is known about a file besides its name, sealed as JSON in
+/// `meta_sealed`. Files stored before it was recorded may lack either.
+#[derive(Serialize, Deserialize, Default)]
+pub struct FileMeta {
+    /// Plaintext size in bytes.
+    pub size: Option<u64>,
+    /// Content type the client uploaded the file with.
+    pub content_type: Option<String>,
+}
+
+/// A sealed value did not open: the master key changed, or the row was
+/// tampered with.
+#[derive(Debug)]
+struct Unsealable;
+
+impl std::fmt::Display for Unsealable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("sealed value does not open with this key")
+    }
+}
+
+impl std::error::Error for Unsealable {}
+
+fn unsealable() -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(Unsealable))
+}
+
+/// Encrypts `plaintext` as hex `nonce || ciphertext`.
+fn seal(cipher: &Aes256Gcm, aad: &[u8], plaintext: &[u8]) -> String {
+    let nonce = Aes256Gcm::generate_nonce(aes_gcm::aead::OsRng);
+    let payload = Payload {
+        msg: plaintext,
+        aad,
+    };
+    let mut sealed = nonce.to_vec();
+    sealed.extend(cipher.encrypt(&nonce, payload).unwrap());
+    hex::encode(sealed)
+}
+
+fn open(cipher: &Aes256Gcm, aad: &[u8], sealed: &str) -> rusqlite::Result<Vec<u8>> {
+    let sealed = hex::decode(sealed).map_err(|_| unsealable())?;
+    if sealed.len() < NONCE_LEN {
+        return Err(unsealable());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let payload = Payload {
+        msg: ciphertext,
+        aad,
+    };
+    cipher
+        .decrypt(aes_gcm::Nonce::from_slice(nonce), payload)
+        .map_err(|_| unsealable())
+}
+
+/// A user's name and metadata keys.
+#[derive(Clone)]
+pub struct UserKey {
+    inner: Arc<UserKeyInner>,
+}
+
+struct UserKeyInner {
+    index: hmac::Key,
+    seal: Aes256Gcm,
+}
+
+impl UserKey {
+    fn new(raw: &[u8; 32]) -> Self {
+        let root = hmac::Key::new(hmac::HMAC_SHA256, raw);
+        let index = hmac::sign(&root, b"senmon name index");
+        let seal: Zeroizing<[u8; 32]> =
+            Zeroizing::new(hmac::sign(&root, b"senmon seal").as_ref().try_into().unwrap());
+        UserKey {
+            inner: Arc::new(UserKeyInner {
+                index: hmac::Key::new(hmac::HMAC_SHA256, index.as_ref()),
+                seal: container::cipher(&seal),
+            }),
+        }
+    }
+
+    /// The tag `name` is looked up by.
+    pub fn tag(&self, field: Field, name: &str) -> String {
+        let mut context = hmac::Context::with_key(&self.inner.index);
+        context.update(field.label());
+        context.update(&[0]);
+        context.update(name.as_bytes());
+        hex::encode(context.sign())
+    }
+
+    /// Returns the tag and the sealed form of `name`.
+    pub fn seal_name(&self, field: Field, name: &str) -> (String, String) {
+        (
+            self.tag(field, name),
+            seal(&self.inner.seal, field.label(), name.as_bytes()),
+        )
+    }
+
+    /// Opens a `name_sealed` value. Meant for row mappers, a value that
+    /// does not open fails the query.
+    pub fn open_name(&self, field: Field, sealed: &str) -> rusqlite::Result<String> {
+        let name = open(&self.inner.seal, field.label(), sealed)?;
+        String::from_utf8(name).map_err(|_| unsealable())
+    }
+
+    pub fn seal_meta(&self, meta: &FileMeta) -> String {
+        let json = serde_json::to_vec(meta).unwrap();
+        seal(&self.inner.seal, Field::FileMeta.label(), &json)
+    }
+
+    pub fn open_meta(&self, sealed: Option<&str>) -> rusqlite::Result<FileMeta> {
+        let Some(sealed) = sealed else {
+            return Ok(FileMeta::default());
+        };
+        let json = open(&self.inner.seal, Field::FileMeta.label(), sealed)?;
+        serde_json::from_slice(&json).map_err(|_| unsealable())
+    }
+}
+
+/// The master key and the user keys unwrapped so far.
+#[derive(Clone)]
+pub struct Keyring {
+    master: Arc<Aes256Gcm>,
+    users: Arc<Mutex<HashMap<u32, UserKey>>>,
+}
+
+impl Keyring {
+    /// Reads the master key from `master_key_file`, creating the file with
+    /// a fresh key, readable by its owner only, if there is none.
+    pub fn load(config: &Config) -> Result<Self, String> {
+        let path = &config.master_key_file;
+        let describe = |why: &dyn std::fmt::Display| format!("{}: {why}", path.display());
+        let key: Zeroizing<[u8; 32]> = match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                let contents = Zeroizing::new(contents);
+                let mut key = Zeroizing::new([0u8; 32]);
+                hex::decode_to_slice(contents.trim(), key.as_mut())
+                    .map_err(|_| describe(&"not a hex encoded 256 bit key"))?;
+                key
+            }
+            Err(why) if why.kind() == std::io::ErrorKind::NotFound => {
+                let mut key = Zeroizing::new([0u8; 32]);
+                rand::thread_rng().fill(key.as_mut());
+                write_key_file(path, &key).map_err(|why| describe(&why))?;
+                tracing::warn!(
+                    "created a new master key in {}, back it up: without it no file or folder name can be read",
+                    path.display()
+                );
+                key
+            }
+            Err(why) => return Err(describe(&why)),
+        };
+        Ok(Keyring {
+            master: Arc::new(container::cipher(&key)),
+            users: Arc::default(),
+        })
+    }
+
+    /// Returns `user_id`'s key, creating it on first use.
+    pub fn user_key(&self, cnx: &rusqlite::Connection, user_id: u32) -> rusqlite::Result<UserKey> {
+        if let Some(key) = self.users.lock().unwrap().get(&user_id) {
+            return Ok(key.clone());
+        }
+
+        let mut raw = Zeroizing::new([0u8; 32]);
+        rand::thread_rng().fill(raw.as_mut());
+        let aad = wrap_aad(user_id);
+        // Two requests may race to create the key, the first one wins.
+        cnx.execute(
+            "INSERT OR IGNORE INTO user_keys(user_id, wrapped_key) VALUES(?1, ?2);",
+            (user_id, seal(&self.master, &aad, raw.as_ref())),
+        )?;
+        let wrapped: String = cnx.query_row(
+            "SELECT wrapped_key FROM user_keys WHERE user_id=?1;",
+            [user_id],
+            |r| r.get(0),
+        )?;
+        let unwrapped = Zeroizing::new(open(&self.master, &aad, &wrapped)?);
+        let raw: &[u8; 32] = unwrapped.as_slice().try_into().map_err(|_| unsealable())?;
+
+        let key = UserKey::new(raw);
+        self.users.lock().unwrap().insert(user_id, key.clone());
+        Ok(key)
+    }
+}
+
+/// Binds a wrapped key to its user, so that keys can not be swapped
+/// between rows.
+fn wrap_aad(user_id: u32) -> Vec<u8> {
+    [b"senmon user key".as_slice(), &user_id.to_be_bytes()].concat()
+}
+
+fn write_key_file(path: &std::path::Path, key: &[u8; 32]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(Zeroizing::new(hex::encode(key) + "\n").as_bytes())?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyring(master: [u8; 32]) -> Keyring {
+        Keyring {
+            master: Arc::new(container::cipher(&master)),
+            users: Arc::default(),
+        }
+    }
+
+    fn user_keys() -> rusqlite::Connection {
+        let cnx = rusqlite::Connection::open_in_memory().unwrap();
+        cnx.execute_batch(
+            "CREATE TABLE user_keys(user_id INTEGER PRIMARY KEY, wrapped_key VARCHAR NOT NULL);",
+        )
+        .unwrap();
+        cnx
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let key = UserKey::new(&[1; 32]);
+        let (tag, sealed) = key.seal_name(Field::FileName, "notes.txt");
+        assert_eq!(tag, key.tag(Field::FileName, "notes.txt"));
+        assert!(!sealed.contains(&hex::encode("notes.txt")));
+        assert_eq!(
+            key.open_name(Field::FileName, &sealed).unwrap(),
+            "notes.txt"
+        );
+    }
+
+    #[test]
+    fn sealed_names_are_bound_to_their_field() {
+        let key = UserKey::new(&[1; 32]);
+        let (_, sealed) = key.seal_name(Field::FileName, "notes.txt");
+        assert!(key.open_name(Field::FolderName, &sealed).is_err());
+        assert!(key.open_meta(Some(&sealed)).is_err());
+    }
+
+    #[test]
+    fn tampered_names() {
+        let key = UserKey::new(&[1; 32]);
+        let (_, sealed) = key.seal_name(Field::FileName, "notes.txt");
+        let mut bytes = hex::decode(&sealed).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        assert!(key.open_name(Field::FileName, &hex::encode(bytes)).is_err());
+        assert!(key.open_name(Field::FileName, "not hex").is_err());
+        assert!(key.open_name(Field::FileName, "00").is_err());
+        assert!(UserKey::new(&[2; 32])
+            .open_name(Field::FileName, &sealed)
+            .is_err());
+    }
+
+    #[test]
+    fn tags() {
+        let (alice, bob) = (UserKey::new(&[1; 32]), UserKey::new(&[2; 32]));
+        let tag = alice.tag(Field::FileName, "notes.txt");
+        assert_ne!(tag, alice.tag(Field::FileName, "Notes.txt"));
+        assert_ne!(tag, alice.tag(Field::FolderName, "notes.txt"));
+        assert_ne!(tag, bob.tag(Field::FileName, "notes.txt"));
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let key = UserKey::new(&[1; 32]);
+        let meta = FileMeta {
+            size: Some(42),
+            content_type: Some("text/plain".to_string()),
+        };
+        let opened = key.open_meta(Some(&key.seal_meta(&meta))).unwrap();
+        assert_eq!(
+            (opened.size, opened.content_type),
+            (meta.size, meta.content_type)
+        );
+        assert!(key.open_meta(None).unwrap().size.is_none());
+    }
+
+    #[test]
+    fn user_keys_survive_a_restart() {
+        let cnx = user_keys();
+        let (_, sealed) = keyring([9; 32])
+            .user_key(&cnx, 1)
+            .unwrap()
+            .seal_name(Field::FileName, "notes.txt");
+        let key = keyring([9; 32]).user_key(&cnx, 1).unwrap();
+        assert_eq!(
+            key.open_name(Field::FileName, &sealed).unwrap(),
+            "notes.txt"
+        );
+        assert!(keyring([8; 32]).user_key(&cnx, 1).is_err());
+    }
+
+    #[test]
+    fn wrapped_keys_are_bound_to_their_user() {
+        let cnx = user_keys();
+        let keyring = keyring([9; 32]);
+        keyring.user_key(&cnx, 1).unwrap();
+        cnx.execute(
+            "INSERT INTO user_keys(user_id, wrapped_key) SELECT 2, wrapped_key FROM user_keys WHERE user_id=1;",
+            [],
+        )
+        .unwrap();
+        assert!(keyring.user_key(&cnx, 2).is_err());
+    }
+}
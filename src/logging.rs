@@ -0,0 +1,111 @@
+//! Diagnostics go through `tracing`. Every request gets a span carrying its
+//! request id, method, matched route, user id, status and latency, and all
+//! events logged while handling it inherit those fields.
+//!
+//! Nothing here logs bodies, query strings or headers: passwords travel in
+//! forms, tus `Upload-Metadata` and cookies, and unlock tokens in the query
+//! string. The route is the matched pattern, so file names stay out of the
+//! log too.
+
+use std::io::IsTerminal;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::MatchedPath;
+use axum::http::{header, HeaderName, Request, Response};
+use tower_http::classify::{ServerErrorsAsFailures, SharedClassifier};
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::sensitive_headers::SetSensitiveRequestHeadersLayer;
+use tower_http::trace::{DefaultOnFailure, DefaultOnRequest, TraceLayer};
+use tracing::{field, Span};
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{Config, LogFormat};
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Headers that may carry credentials. They are marked sensitive so that
+/// any layer that does print headers shows them redacted.
+const SENSITIVE_HEADERS: [HeaderName; 5] = [
+    header::AUTHORIZATION,
+    header::COOKIE,
+    HeaderName::from_static("upload-metadata"),
+    HeaderName::from_static("x-unlock-token"),
+    HeaderName::from_static("x-unlock-password"),
+];
+
+pub fn init(config: &Config) -> Result<(), String> {
+    let filter = EnvFilter::try_new(&config.log_level)
+        .map_err(|why| format!("log_level {:?}: {why}", config.log_level))?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    let result = match config.log_format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).with_span_list(false).try_init(),
+    };
+    result.map_err(|why| why.to_string())
+}
+
+/// Records the logged in user on the current request span.
+pub fn record_user(user_id: u32) {
+    Span::current().record("user_id", user_id);
+}
+
+fn make_span(request: &Request<Body>) -> Span {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("<unmatched>", |path| path.as_str());
+    tracing::info_span!(
+        "request",
+        request_id,
+        method = %request.method(),
+        route,
+        user_id = field::Empty,
+        status = field::Empty,
+        latency_ms = field::Empty,
+    )
+}
+
+fn on_response(response: &Response<Body>, latency: Duration, span: &Span) {
+    span.record("status", response.status().as_u16());
+    span.record("latency_ms", latency.as_millis() as u64);
+    tracing::info!("finished");
+}
+
+pub type HttpTraceLayer = TraceLayer<
+    SharedClassifier<ServerErrorsAsFailures>,
+    fn(&Request<Body>) -> Span,
+    DefaultOnRequest,
+    fn(&Response<Body>, Duration, &Span),
+>;
+
+pub fn trace_layer() -> HttpTraceLayer {
+    TraceLayer::new_for_http()
+        .make_span_with(make_span as fn(&Request<Body>) -> Span)
+        .on_request(DefaultOnRequest::new())
+        .on_response(on_response as fn(&Response<Body>, Duration, &Span))
+        .on_failure(DefaultOnFailure::new())
+}
+
+/// Gives requests without an `X-Request-Id` a fresh one. It has to sit
+/// outside [`trace_layer`] for the span to pick it up.
+pub fn set_request_id_layer() -> SetRequestIdLayer<MakeRequestUuid> {
+    SetRequestIdLayer::new(REQUEST_ID, MakeRequestUuid)
+}
+
+/// Echoes the request id in the response so clients can quote it.
+pub fn propagate_request_id_layer() -> PropagateRequestIdLayer {
+    PropagateRequestIdLayer::new(REQUEST_ID)
+}
+
+pub fn sensitive_headers_layer() -> SetSensitiveRequestHeadersLayer {
+    SetSensitiveRequestHeadersLayer::new(SENSITIVE_HEADERS)
+}
@@ -1,63 +1,298 @@
+mod api;
 mod auth;
+mod cli;
+mod config;
+mod container;
 mod db;
+mod folders;
+mod gc;
 mod handlers;
+mod health;
+mod keyring;
+mod logging;
+mod metrics;
+mod migrations;
+mod openapi;
+mod scrub;
 mod session;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod tokens;
+mod trash;
+mod tus;
 mod types;
+mod unlock;
+mod upgrade;
 
-use std::ops::Deref;
+use std::process::ExitCode;
+use std::sync::Arc;
 
 use axum::{
+    extract::FromRef,
     routing::{get, post},
     Router,
 };
+use clap::Parser;
+use cli::{Cli, Command};
 use handlers::*;
 use tower_http::services::ServeDir;
 
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pub db: db::DatabaseConnection,
+    pub uploads: tus::TusUploads,
+    pub unlock_keys: unlock::UnlockKeys,
+    pub keyring: keyring::Keyring,
+    pub config: Arc<config::Config>,
+    pub readiness: health::Readiness,
+}
+
 #[tokio::main]
-async fn main() {
-    let conn = rusqlite::Connection::open("./file_storage.db").unwrap();
-    let application_state = db::DatabaseConnection::new(conn);
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let config = match config::Config::load(cli.config.as_deref()) {
+        Ok(config) => Arc::new(config),
+        Err(why) => {
+            eprintln!("{why}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(why) = logging::init(&config) {
+        eprintln!("{why}");
+        return ExitCode::FAILURE;
+    }
+
+    let application_state = match db::DatabaseConnection::open(&config.database) {
+        Ok(db) => db,
+        Err(why) => {
+            tracing::error!("{}: {why}", config.database.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Some(Command::Migrate { dry_run }) = cli.command {
+        if !migrate_db(&application_state, dry_run) {
+            return ExitCode::FAILURE;
+        }
+        let version = application_state
+            .get()
+            .ok()
+            .and_then(|cnx| migrations::schema_version(&cnx).ok());
+        if let Some(version) = version {
+            println!("schema is at version {version}");
+        }
+        return ExitCode::SUCCESS;
+    }
 
-    if !init_db(&application_state) {
-        eprintln!("FAILED TO INITIALIZE DATABASE");
-        return;
+    if !migrate_db(&application_state, false) {
+        tracing::error!("failed to initialize the database");
+        return ExitCode::FAILURE;
     }
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:42069")
-        .await
-        .unwrap();
+    let keyring = match keyring::Keyring::load(&config) {
+        Ok(keyring) => keyring,
+        Err(why) => {
+            tracing::error!("{why}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(why) = upgrade::run(&application_state, &config, &keyring).await {
+        tracing::error!("upgrading stored data: {why}");
+        return ExitCode::FAILURE;
+    }
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(application_state, config, keyring).await,
+        Command::User(command) => cli::user(&application_state, &config, command).await,
+        Command::Migrate { .. } => unreachable!("handled before migrating"),
+        Command::Gc(args) => cli::gc(&application_state, config, args).await,
+        Command::Scrub => cli::scrub(&application_state, &config).await,
+    }
+}
+
+async fn serve(
+    application_state: db::DatabaseConnection,
+    config: Arc<config::Config>,
+    keyring: keyring::Keyring,
+) -> ExitCode {
+    if let Err(why) = config.prepare_serve() {
+        tracing::error!("{why}");
+        return ExitCode::FAILURE;
+    }
+    if let Err(why) = tus::clear_staging(&config) {
+        tracing::error!("clearing the upload staging directory: {why}");
+        return ExitCode::FAILURE;
+    }
+
+    let unlock_keys = unlock::UnlockKeys::default();
+    let uploads = tus::TusUploads::default();
+    let readiness = health::Readiness::default();
+
+    scrub::spawn_scrubber(application_state.clone(), config.clone());
+    unlock::spawn_sweeper(unlock_keys.clone());
+    tus::spawn_sweeper(uploads.clone(), config.clone());
+    trash::spawn_purger(application_state.clone(), config.clone());
+
     let router = Router::new()
         .route("/", get(home))
         .route("/favicon.ico", get(icon))
-        .nest_service("/assets", ServeDir::new("./assets"))
+        .nest_service("/assets", ServeDir::new(&config.assets_dir))
         .route("/api/auth", post(auth::auth))
         .route("/api/login", post(auth::login))
         .route("/api/upload_file", post(upload_file))
         .route("/api/download_file", post(download_file))
-        .with_state(application_state);
-
-    axum::serve(listener, router).await.unwrap();
-}
+        .route("/api/delete_file", post(delete_file))
+        .route("/api/trash", get(list_trash))
+        .route("/api/restore_file", post(restore_file))
+        .route(
+            "/api/files/*path",
+            get(file_content).post(unlock_file).patch(move_file),
+        )
+        .route("/api/folders", get(list_folder))
+        .route("/api/folders/create", post(create_folder))
+        .route("/api/folders/rename", post(rename_folder))
+        .route("/api/folders/move", post(move_folder))
+        .route("/api/folders/delete", post(delete_folder))
+        .route("/api/admin/file_health", get(file_health))
+        .route("/api/tokens", get(list_tokens))
+        .route("/api/tokens/create", post(create_token))
+        .route("/api/tokens/revoke", post(revoke_token))
+        .nest("/api/v1", api::router())
+        .route("/api/openapi.json", get(openapi::openapi_json))
+        .route("/api/docs", get(openapi::docs))
+        .route("/metrics", get(metrics::metrics))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/api/tus", post(tus::create).options(tus::options))
+        .route(
+            "/api/tus/:upload_id",
+            axum::routing::head(tus::status)
+                .patch(tus::append)
+                .delete(tus::terminate)
+                .options(tus::options),
+        )
+        .layer(logging::trace_layer())
+        .layer(logging::propagate_request_id_layer())
+        .layer(logging::set_request_id_layer())
+        .layer(logging::sensitive_headers_layer())
+        .with_state(AppState {
+            db: application_state,
+            uploads,
+            unlock_keys,
+            keyring,
+            config: config.clone(),
+            readiness: readiness.clone(),
+        });
 
-pub fn init_db(db: &db::DatabaseConnection) -> bool {
-    let cnx = db.ctx.deref().lock().unwrap();
+    if let Some((cert, key)) = config.tls() {
+        let tls = match tls::load(cert, key).await {
+            Ok(tls) => tls,
+            Err(why) => {
+                tracing::error!("{}: {why}", cert.display());
+                return ExitCode::FAILURE;
+            }
+        };
+        tls::spawn_reloader(tls.clone(), cert.to_path_buf(), key.to_path_buf());
+        if let Some(redirect_listen) = config.redirect_listen {
+            tokio::spawn(tls::serve_redirect(redirect_listen, config.listen.port()));
+        }
+        let handle = axum_server::Handle::new();
+        tokio::spawn({
+            let (handle, timeout) = (handle.clone(), config.shutdown_timeout());
+            let (readiness, grace) = (readiness.clone(), config.shutdown_grace());
+            async move {
+                shutdown_signal(readiness, grace).await;
+                handle.graceful_shutdown(Some(timeout));
+            }
+        });
+        if let Err(why) = axum_server::bind_rustls(config.listen, tls)
+            .handle(handle)
+            .serve(router.layer(tls::hsts_layer()).into_make_service())
+            .await
+        {
+            tracing::error!("{}: {why}", config.listen);
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
 
-    let result = cnx.execute_batch(
-        "BEGIN;
-        CREATE TABLE IF NOT EXISTS file_state(file_owner INTEGER REFERENCES user_reg(user_id), file_name VARCHAR, salt VARCHAR, PRIMARY KEY (file_owner, file_name));
-        CREATE INDEX IF NOT EXISTS file_state_file_owner_file_name ON file_state(file_owner, file_name);
+    let listener = match tokio::net::TcpListener::bind(config.listen).await {
+        Ok(listener) => listener,
+        Err(why) => {
+            tracing::error!("{}: {why}", config.listen);
+            return ExitCode::FAILURE;
+        }
+    };
+    let draining = Arc::new(tokio::sync::Notify::new());
+    let server = axum::serve(listener, router).with_graceful_shutdown({
+        let draining = draining.clone();
+        let grace = config.shutdown_grace();
+        async move {
+            shutdown_signal(readiness, grace).await;
+            draining.notify_one();
+        }
+    });
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = async {
+            draining.notified().await;
+            tokio::time::sleep(config.shutdown_timeout()).await;
+        } => tracing::warn!(
+            timeout = ?config.shutdown_timeout(),
+            "requests still running at the end of the shutdown timeout, dropping them"
+        ),
+    }
+    ExitCode::SUCCESS
+}
 
-        CREATE TABLE IF NOT EXISTS user_reg(user_id INTEGER PRIMARY KEY AUTOINCREMENT, username VARCHAR UNIQUE, password VARCHAR);
-        CREATE INDEX IF NOT EXISTS user_reg_user_id_username ON user_reg(user_id, username);
+/// Resolves on SIGTERM or SIGINT. The server then stops accepting
+/// connections and waits for the ones it has.
+///
+/// On SIGTERM, which is what orchestrators send, `/readyz` fails for
+/// `grace` first while new connections are still accepted, so that load
+/// balancers stop routing here before connections get refused.
+async fn shutdown_signal(readiness: health::Readiness, grace: std::time::Duration) {
+    let mut terminate =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).ok();
+    let terminated = tokio::select! {
+        _ = tokio::signal::ctrl_c() => false,
+        Some(()) = async { terminate.as_mut()?.recv().await } => true,
+    };
+    readiness.shutting_down();
+    if terminated && !grace.is_zero() {
+        tracing::info!(?grace, "not ready, shutting down after the grace period");
+        tokio::time::sleep(grace).await;
+    }
+    tracing::info!("shutting down, draining in-flight requests");
+}
 
-        CREATE TABLE IF NOT EXISTS sessions(session_id UNSIGNED BIG INT PRIMARY KEY, user_id INTEGER REFERENCES user_reg(user_id), expires TEXT);
-        CREATE INDEX IF NOT EXISTS sessions_session_id_user_id ON sessions(session_id, user_id);
-        COMMIT;"
-    );
+/// Applies pending migrations, or with `dry_run` only reports them. Refuses
+/// a database whose schema is newer than this build.
+pub fn migrate_db(db: &db::DatabaseConnection, dry_run: bool) -> bool {
+    let mut cnx = match db.get() {
+        Ok(cnx) => cnx,
+        Err(why) => {
+            tracing::error!("{why}");
+            return false;
+        }
+    };
 
-    if let Err(why) = result {
-        eprintln!("{:?}", why);
-        return false;
+    match migrations::migrate(&mut cnx, dry_run) {
+        Ok(applied) => {
+            for migration in applied {
+                println!(
+                    "{} migration {}: {}",
+                    if dry_run { "would apply" } else { "applied" },
+                    migration.version,
+                    migration.description
+                );
+            }
+            true
+        }
+        Err(why) => {
+            tracing::error!("{why}");
+            false
+        }
     }
-    return true;
 }
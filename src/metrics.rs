@@ -0,0 +1,116 @@
+//! Prometheus metrics, served in the text format at `/metrics`.
+//!
+//! The counters are process wide, so code that has no access to the router
+//! state (key derivation, the connection pool) can record into them too.
+
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+
+use crate::db::DatabaseConnection;
+use crate::types::SenmonError;
+
+pub struct Metrics {
+    registry: Registry,
+    pub uploads: IntCounter,
+    pub downloads: IntCounter,
+    pub bytes_in: IntCounter,
+    pub bytes_out: IntCounter,
+    /// Labelled by `kind` (login, signup, token) and `result` (success, failure).
+    pub auth: IntCounterVec,
+    pub kdf_seconds: Histogram,
+    /// Time spent waiting for a pooled SQLite connection.
+    pub db_wait_seconds: Histogram,
+    active_sessions: IntGauge,
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("senmon".to_string()), None).unwrap();
+        let metrics = Metrics {
+            uploads: IntCounter::new("uploads_total", "Files stored").unwrap(),
+            downloads: IntCounter::new("downloads_total", "Files or ranges served").unwrap(),
+            bytes_in: IntCounter::new("bytes_in_total", "Plaintext bytes stored").unwrap(),
+            bytes_out: IntCounter::new("bytes_out_total", "Plaintext bytes served").unwrap(),
+            auth: IntCounterVec::new(
+                Opts::new("auth_total", "Signups and logins by outcome"),
+                &["kind", "result"],
+            )
+            .unwrap(),
+            kdf_seconds: Histogram::with_opts(
+                HistogramOpts::new("kdf_duration_seconds", "PBKDF2 key derivation time")
+                    .buckets(exponential_buckets(0.01, 2.0, 10).unwrap()),
+            )
+            .unwrap(),
+            db_wait_seconds: Histogram::with_opts(
+                HistogramOpts::new(
+                    "db_wait_seconds",
+                    "Time spent waiting for a free SQLite connection",
+                )
+                .buckets(exponential_buckets(0.0001, 4.0, 10).unwrap()),
+            )
+            .unwrap(),
+            active_sessions: IntGauge::new("active_sessions", "Sessions that have not expired")
+                .unwrap(),
+            registry,
+        };
+        let registry = &metrics.registry;
+        registry.register(Box::new(metrics.uploads.clone())).unwrap();
+        registry.register(Box::new(metrics.downloads.clone())).unwrap();
+        registry.register(Box::new(metrics.bytes_in.clone())).unwrap();
+        registry.register(Box::new(metrics.bytes_out.clone())).unwrap();
+        registry.register(Box::new(metrics.auth.clone())).unwrap();
+        registry.register(Box::new(metrics.kdf_seconds.clone())).unwrap();
+        registry.register(Box::new(metrics.db_wait_seconds.clone())).unwrap();
+        registry.register(Box::new(metrics.active_sessions.clone())).unwrap();
+        metrics
+    }
+
+    pub fn auth(&self, kind: &str, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        self.auth.with_label_values(&[kind, result]).inc();
+    }
+}
+
+/// Runs `f` and records how long it took in `histogram`.
+pub fn time<T>(histogram: &Histogram, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = f();
+    histogram.observe(started.elapsed().as_secs_f64());
+    result
+}
+
+pub async fn metrics(State(db): State<DatabaseConnection>) -> Result<Response, SenmonError> {
+    let now = Utc::now().to_rfc3339();
+    let active: i64 = db
+        .run(move |cnx| {
+            cnx.query_row(
+                "SELECT COUNT(*) FROM sessions WHERE expires > ?1;",
+                [now],
+                |r| r.get(0),
+            )
+        })
+        .await?;
+    METRICS.active_sessions.set(active);
+
+    let mut body = Vec::new();
+    TextEncoder::new()
+        .encode(&METRICS.registry.gather(), &mut body)
+        .map_err(|why| SenmonError::Storage(std::io::Error::other(why)))?;
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        body,
+    )
+        .into_response())
+}
@@ -0,0 +1,385 @@
+//! Schema migrations, tracked with `PRAGMA user_version`.
+//!
+//! Every migration runs in its own transaction together with the bump of
+//! `user_version`, so a failed migration leaves the database at the previous
+//! version. New migrations are appended to [`MIGRATIONS`] and never edited
+//! once released.
+
+use std::fmt;
+
+use rusqlite::{Connection, Transaction, TransactionBehavior};
+
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    up: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        up: initial_schema,
+    },
+    Migration {
+        version: 2,
+        description: "record the KDF cost of every file",
+        up: kdf_iterations,
+    },
+    Migration {
+        version: 3,
+        description: "personal API tokens",
+        up: api_tokens,
+    },
+    Migration {
+        version: 4,
+        description: "flag end-to-end encrypted files",
+        up: end_to_end,
+    },
+    Migration {
+        version: 5,
+        description: "encrypt names and metadata at rest",
+        up: sealed_names,
+    },
+    Migration {
+        version: 6,
+        description: "keep the file id of trashed files",
+        up: trashed_file_ids,
+    },
+    Migration {
+        version: 7,
+        description: "store session expiry as RFC 3339",
+        up: session_expiry_rfc3339,
+    },
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sqlite(rusqlite::Error),
+    /// The database was migrated by a newer build than this one.
+    SchemaTooNew { found: u32, supported: u32 },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Sqlite(why) => write!(f, "migration failed: {why}"),
+            MigrationError::SchemaTooNew { found, supported } => write!(
+                f,
+                "database schema version {found} is newer than the latest version this build supports ({supported})"
+            ),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(why: rusqlite::Error) -> Self {
+        MigrationError::Sqlite(why)
+    }
+}
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+pub fn schema_version(cnx: &Connection) -> rusqlite::Result<u32> {
+    cnx.pragma_query_value(None, "user_version", |r| r.get(0))
+}
+
+/// Brings the schema up to [`latest_version`] and returns the migrations
+/// that were applied. With `dry_run` every pending migration is still run,
+/// so that a failing one is reported, but rolled back afterwards.
+pub fn migrate(
+    cnx: &mut Connection,
+    dry_run: bool,
+) -> Result<Vec<&'static Migration>, MigrationError> {
+    let current = schema_version(cnx)?;
+    if current > latest_version() {
+        return Err(MigrationError::SchemaTooNew {
+            found: current,
+            supported: latest_version(),
+        });
+    }
+
+    let pending: Vec<&Migration> = MIGRATIONS.iter().filter(|m| m.version > current).collect();
+    if dry_run {
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        for migration in &pending {
+            (migration.up)(&tx)?;
+        }
+        tx.rollback()?;
+        return Ok(pending);
+    }
+
+    for migration in &pending {
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        (migration.up)(&tx)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+    Ok(pending)
+}
+
+/// Everything the unversioned `init_db` used to create. Databases from
+/// before migrations existed may already have any part of it, so this one
+/// has to be idempotent.
+fn initial_schema(tx: &Transaction) -> rusqlite::Result<()> {
+    upgrade_flat_file_state(tx)?;
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS file_state(file_id INTEGER PRIMARY KEY AUTOINCREMENT, file_owner INTEGER REFERENCES user_reg(user_id), folder_id INTEGER NOT NULL DEFAULT 0, file_name VARCHAR, salt VARCHAR, blob VARCHAR, UNIQUE (file_owner, folder_id, file_name));
+
+        CREATE TABLE IF NOT EXISTS folders(folder_id INTEGER PRIMARY KEY AUTOINCREMENT, owner INTEGER REFERENCES user_reg(user_id), parent_id INTEGER NOT NULL DEFAULT 0, name VARCHAR, UNIQUE (owner, parent_id, name));
+
+        CREATE TABLE IF NOT EXISTS user_reg(user_id INTEGER PRIMARY KEY AUTOINCREMENT, username VARCHAR UNIQUE, password VARCHAR);
+        CREATE INDEX IF NOT EXISTS user_reg_user_id_username ON user_reg(user_id, username);
+
+        CREATE TABLE IF NOT EXISTS sessions(session_id UNSIGNED BIG INT PRIMARY KEY, user_id INTEGER REFERENCES user_reg(user_id), expires TEXT);
+        CREATE INDEX IF NOT EXISTS sessions_session_id_user_id ON sessions(session_id, user_id);
+
+        CREATE TABLE IF NOT EXISTS admins(user_id INTEGER PRIMARY KEY REFERENCES user_reg(user_id));
+
+        CREATE TABLE IF NOT EXISTS file_health(blob VARCHAR PRIMARY KEY, checksum VARCHAR, status VARCHAR, checked_at TEXT);
+
+        CREATE TABLE IF NOT EXISTS file_trash(trash_id INTEGER PRIMARY KEY AUTOINCREMENT, file_owner INTEGER REFERENCES user_reg(user_id), file_name VARCHAR, salt VARCHAR, blob VARCHAR, deleted_at TEXT, folder_id INTEGER NOT NULL DEFAULT 0);
+        CREATE INDEX IF NOT EXISTS file_trash_file_owner ON file_trash(file_owner);",
+    )
+}
+
+/// Files stored so far were all derived with the cost that used to be
+/// hard-coded.
+fn kdf_iterations(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE file_state ADD COLUMN kdf_iterations INTEGER NOT NULL DEFAULT 600000;
+        ALTER TABLE file_trash ADD COLUMN kdf_iterations INTEGER NOT NULL DEFAULT 600000;",
+    )
+}
+
+/// Only a hash of each token is kept, the token itself is shown once.
+fn api_tokens(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE api_tokens(token_id INTEGER PRIMARY KEY AUTOINCREMENT, user_id INTEGER NOT NULL REFERENCES user_reg(user_id), name VARCHAR NOT NULL, token_hash VARCHAR NOT NULL UNIQUE, scopes VARCHAR NOT NULL, created_at TEXT NOT NULL, expires_at TEXT NOT NULL, last_used_at TEXT);
+        CREATE INDEX api_tokens_user_id ON api_tokens(user_id);",
+    )
+}
+
+/// Every file stored so far was encrypted by the server.
+fn end_to_end(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE file_state ADD COLUMN e2e INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE file_trash ADD COLUMN e2e INTEGER NOT NULL DEFAULT 0;",
+    )
+}
+
+/// Names become a lookup tag plus the name sealed under the owner's key,
+/// see [`crate::keyring`]. SQL alone can not compute either, so existing
+/// names stay in `name_tag` in plaintext, with `name_sealed` NULL, until
+/// [`crate::upgrade::seal_names`] runs with the master key at startup.
+fn sealed_names(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE user_keys(user_id INTEGER PRIMARY KEY REFERENCES user_reg(user_id), wrapped_key VARCHAR NOT NULL);
+
+        ALTER TABLE file_state RENAME COLUMN file_name TO name_tag;
+        ALTER TABLE file_state ADD COLUMN name_sealed VARCHAR;
+        ALTER TABLE file_state ADD COLUMN meta_sealed VARCHAR;
+
+        ALTER TABLE file_trash RENAME COLUMN file_name TO name_tag;
+        ALTER TABLE file_trash ADD COLUMN name_sealed VARCHAR;
+        ALTER TABLE file_trash ADD COLUMN meta_sealed VARCHAR;
+
+        ALTER TABLE folders RENAME COLUMN name TO name_tag;
+        ALTER TABLE folders ADD COLUMN name_sealed VARCHAR;",
+    )
+}
+
+/// Blobs are bound to their file id, so a restored file must get its id
+/// back. Files trashed before this have none recorded, their blobs predate
+/// binding and open under any id.
+fn trashed_file_ids(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE file_trash ADD COLUMN file_id INTEGER;")
+}
+
+/// RFC 3339 timestamps in UTC sort as text, so the session lookup can
+/// compare `expires` in SQL. Sessions whose expiry can not be read are
+/// dropped, their users log in again.
+fn session_expiry_rfc3339(tx: &Transaction) -> rusqlite::Result<()> {
+    let sessions: Vec<(u32, String)> = {
+        let mut stmt = tx.prepare("SELECT session_id, expires FROM sessions;")?;
+        let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+        rows.collect::<rusqlite::Result<_>>()?
+    };
+    for (session_id, expires) in sessions {
+        match chrono::DateTime::parse_from_rfc2822(&expires) {
+            Ok(expires) => tx.execute(
+                "UPDATE sessions SET expires=?1 WHERE session_id=?2;",
+                (expires.to_utc().to_rfc3339(), session_id),
+            )?,
+            Err(_) => tx.execute("DELETE FROM sessions WHERE session_id=?1;", [session_id])?,
+        };
+    }
+    Ok(())
+}
+
+fn table_has_column(cnx: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    cnx.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name=?2;",
+        [table, column],
+        |r| r.get::<_, u32>(0),
+    )
+    .map(|count| count > 0)
+}
+
+fn table_exists(cnx: &Connection, table: &str) -> rusqlite::Result<bool> {
+    cnx.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name=?1;",
+        [table],
+        |r| r.get::<_, u32>(0),
+    )
+    .map(|count| count > 0)
+}
+
+/// Databases created before folders existed keyed `file_state` by
+/// `(file_owner, file_name)` and kept every blob at `<username>/<file_name>`.
+/// Rebuild the table with a file id, a folder and an explicit blob path, and
+/// put all existing files in the root folder.
+fn upgrade_flat_file_state(cnx: &Connection) -> rusqlite::Result<()> {
+    if table_exists(cnx, "file_state")? && !table_has_column(cnx, "file_state", "file_id")? {
+        cnx.execute_batch(
+            "ALTER TABLE file_state RENAME TO file_state_flat;
+            CREATE TABLE file_state(file_id INTEGER PRIMARY KEY AUTOINCREMENT, file_owner INTEGER REFERENCES user_reg(user_id), folder_id INTEGER NOT NULL DEFAULT 0, file_name VARCHAR, salt VARCHAR, blob VARCHAR, UNIQUE (file_owner, folder_id, file_name));
+            INSERT INTO file_state(file_owner, folder_id, file_name, salt, blob)
+                SELECT f.file_owner, 0, f.file_name, f.salt, u.username || '/' || f.file_name
+                FROM file_state_flat f JOIN user_reg u ON f.file_owner = u.user_id;
+            DROP TABLE file_state_flat;",
+        )?;
+    }
+    if table_exists(cnx, "file_trash")? && !table_has_column(cnx, "file_trash", "folder_id")? {
+        cnx.execute(
+            "ALTER TABLE file_trash ADD COLUMN folder_id INTEGER NOT NULL DEFAULT 0;",
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_increase_by_one() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as u32 + 1);
+        }
+    }
+
+    #[test]
+    fn migrate_from_empty() {
+        let mut cnx = Connection::open_in_memory().unwrap();
+        let applied = migrate(&mut cnx, false).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(schema_version(&cnx).unwrap(), latest_version());
+        for table in [
+            "user_reg",
+            "sessions",
+            "folders",
+            "file_state",
+            "file_trash",
+        ] {
+            assert!(table_exists(&cnx, table).unwrap(), "{table}");
+        }
+        assert!(table_has_column(&cnx, "file_state", "file_id").unwrap());
+        assert!(table_has_column(&cnx, "file_trash", "folder_id").unwrap());
+
+        assert!(migrate(&mut cnx, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn dry_run_rolls_back() {
+        let mut cnx = Connection::open_in_memory().unwrap();
+        assert_eq!(migrate(&mut cnx, true).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(schema_version(&cnx).unwrap(), 0);
+        assert!(!table_exists(&cnx, "file_state").unwrap());
+    }
+
+    #[test]
+    fn schema_too_new() {
+        let mut cnx = Connection::open_in_memory().unwrap();
+        cnx.pragma_update(None, "user_version", latest_version() + 1)
+            .unwrap();
+        assert!(matches!(
+            migrate(&mut cnx, false),
+            Err(MigrationError::SchemaTooNew { found, supported })
+                if found == latest_version() + 1 && supported == latest_version()
+        ));
+    }
+
+    #[test]
+    fn adopt_unversioned_databases() {
+        // What `init_db` left behind: the full schema at user_version 0.
+        let mut cnx = Connection::open_in_memory().unwrap();
+        let tx = cnx.transaction().unwrap();
+        initial_schema(&tx).unwrap();
+        tx.commit().unwrap();
+        cnx.execute(
+            "INSERT INTO user_reg(username, password) VALUES ('alice', 'x');",
+            [],
+        )
+        .unwrap();
+
+        assert_eq!(migrate(&mut cnx, false).unwrap().len(), MIGRATIONS.len());
+        let users: u32 = cnx
+            .query_row("SELECT COUNT(*) FROM user_reg;", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(users, 1);
+    }
+
+    #[test]
+    fn upgrade_unversioned_flat_files() {
+        let mut cnx = Connection::open_in_memory().unwrap();
+        cnx.execute_batch(
+            "CREATE TABLE user_reg(user_id INTEGER PRIMARY KEY AUTOINCREMENT, username VARCHAR UNIQUE, password VARCHAR);
+            CREATE TABLE file_state(file_owner INTEGER, file_name VARCHAR, salt VARCHAR);
+            INSERT INTO user_reg(username, password) VALUES ('alice', 'x');
+            INSERT INTO file_state VALUES (1, 'notes.txt', 'salt');",
+        )
+        .unwrap();
+        migrate(&mut cnx, false).unwrap();
+        // The name is only sealed once the master key is at hand.
+        let (folder, name, blob): (i64, String, String) = cnx
+            .query_row(
+                "SELECT folder_id, name_tag, blob FROM file_state WHERE file_owner=1;",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (folder, name.as_str(), blob.as_str()),
+            (0, "notes.txt", "alice/notes.txt")
+        );
+    }
+
+    #[test]
+    fn session_expiry_becomes_rfc3339() {
+        let mut cnx = Connection::open_in_memory().unwrap();
+        let tx = cnx.transaction().unwrap();
+        initial_schema(&tx).unwrap();
+        tx.commit().unwrap();
+        cnx.execute_batch(
+            "INSERT INTO user_reg(username, password) VALUES ('alice', 'x');
+            INSERT INTO sessions(session_id, user_id, expires) VALUES (1, 1, 'Tue, 20 Oct 2026 10:00:00 +0200');
+            INSERT INTO sessions(session_id, user_id, expires) VALUES (2, 1, 'soon');",
+        )
+        .unwrap();
+        cnx.pragma_update(None, "user_version", 1).unwrap();
+
+        migrate(&mut cnx, false).unwrap();
+        let expires: Vec<String> = cnx
+            .prepare("SELECT expires FROM sessions;")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(expires, ["2026-10-20T08:00:00+00:00"]);
+    }
+}
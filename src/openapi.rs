@@ -0,0 +1,89 @@
+//! OpenAPI 3 document of the JSON API, generated from the handler
+//! annotations, and a page that renders it.
+
+use axum::http::header;
+use axum::response::{Html, IntoResponse, Response};
+use axum::Json;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use crate::{api, auth, handlers, health, tokens};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "senmon", description = "Encrypted file storage"),
+    paths(
+        api::signup,
+        api::login,
+        api::list_folder,
+        api::create_folder,
+        api::delete_folder,
+        api::upload_file,
+        api::file_info,
+        api::file_blob,
+        api::delete_file,
+        api::unlock_file,
+        api::file_content,
+        api::list_trash,
+        api::restore_file,
+        api::list_tokens,
+        api::create_token,
+        api::revoke_token,
+        health::healthz,
+        health::readyz,
+    ),
+    components(schemas(
+        auth::AuthRequest,
+        handlers::UnlockReq,
+        handlers::UnlockResponse,
+        api::ErrorBody,
+        api::SessionBody,
+        api::FolderPath,
+        api::FolderBody,
+        api::FileBody,
+        api::FileInfo,
+        api::UploadForm,
+        api::TrashBody,
+        api::NewToken,
+        api::CreatedToken,
+        tokens::ApiToken,
+        tokens::Scope,
+        health::ReadyReport,
+    )),
+    modifiers(&SecuritySchemes)
+)]
+pub struct ApiDoc;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("session"))),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some("Personal API token, `snm_` followed by 64 hex digits"))
+                    .build(),
+            ),
+        );
+    }
+}
+
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Swagger UI, loaded from a CDN, pointed at `/api/openapi.json`.
+pub async fn docs() -> Response {
+    (
+        [(header::CACHE_CONTROL, "no-cache")],
+        Html(include_str!("openapi.html")),
+    )
+        .into_response()
+}
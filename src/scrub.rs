@@ -0,0 +1,155 @@
+use std::sync::Arc;
+
+use chrono::Duration;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::container;
+use crate::db::{self, DatabaseConnection};
+
+const SCRUB_INTERVAL: chrono::TimeDelta = Duration::hours(6);
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobStatus {
+    Ok,
+    Mismatch,
+    Missing,
+}
+
+impl BlobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlobStatus::Ok => "ok",
+            BlobStatus::Mismatch => "mismatch",
+            BlobStatus::Missing => "missing",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "ok" => Some(BlobStatus::Ok),
+            "mismatch" => Some(BlobStatus::Mismatch),
+            "missing" => Some(BlobStatus::Missing),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct FileHealth {
+    pub blob: String,
+    pub file_owner: u32,
+    /// Names are sealed under their owner's key, so reports carry the id.
+    pub file_id: i64,
+    pub status: BlobStatus,
+    pub checked_at: String,
+}
+
+pub fn blob_checksum(contents: &[u8]) -> String {
+    hex::encode(ring::digest::digest(&ring::digest::SHA256, contents))
+}
+
+/// Computes [`blob_checksum`] of whatever is written through it, for blobs
+/// that are written as a stream.
+pub struct ChecksumWriter<W> {
+    inner: W,
+    digest: ring::digest::Context,
+}
+
+impl<W: std::io::Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChecksumWriter {
+            inner,
+            digest: ring::digest::Context::new(&ring::digest::SHA256),
+        }
+    }
+
+    pub fn finish(self) -> (W, String) {
+        (self.inner, hex::encode(self.digest.finish()))
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.digest.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct ScrubReport {
+    pub unhealthy: Vec<FileHealth>,
+    /// Server side blobs in a format that does not bind them to their file,
+    /// see `accept_legacy_blobs`.
+    pub unbound: usize,
+}
+
+/// Re-hashes every blob referenced by `file_state` and records the outcome in
+/// `file_health`. Blobs that were stored before checksums existed get their
+/// current hash recorded as the reference value. Reports the entries that
+/// are not healthy and how many blobs are not bound to their file yet.
+pub async fn scrub_once(
+    db: &DatabaseConnection,
+    config: &Config,
+) -> Result<ScrubReport, rusqlite::Error> {
+    let blobs = db::get_stored_blobs(db).await?;
+    let mut unbound = 0;
+    for stored in blobs {
+        let path = config.blob_path(&stored.blob);
+        let status = match tokio::fs::read(&path).await {
+            Ok(contents) => {
+                let bound = container::Header::parse(&contents).is_some_and(|h| h.is_bound());
+                if !bound && !stored.end_to_end {
+                    unbound += 1;
+                }
+                let checksum = blob_checksum(&contents);
+                match stored.checksum {
+                    Some(expected) if expected != checksum => BlobStatus::Mismatch,
+                    Some(_) => BlobStatus::Ok,
+                    None => {
+                        db::record_checksum(db, &stored.blob, &checksum).await?;
+                        BlobStatus::Ok
+                    }
+                }
+            }
+            Err(_) => BlobStatus::Missing,
+        };
+        db::record_blob_status(db, &stored.blob, status).await?;
+    }
+    Ok(ScrubReport {
+        unhealthy: db::get_unhealthy_blobs(db).await?,
+        unbound,
+    })
+}
+
+pub fn spawn_scrubber(db: DatabaseConnection, config: Arc<Config>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SCRUB_INTERVAL.to_std().unwrap());
+        loop {
+            interval.tick().await;
+            match scrub_once(&db, &config).await {
+                Ok(report) => {
+                    for entry in report.unhealthy {
+                        tracing::warn!(
+                            blob = %entry.blob,
+                            status = entry.status.as_str(),
+                            "scrub: blob is unhealthy"
+                        );
+                    }
+                    if report.unbound > 0 {
+                        tracing::info!(
+                            "scrub: {} blob(s) are not bound to their file yet",
+                            report.unbound
+                        );
+                    }
+                }
+                Err(why) => tracing::error!("scrub: {why}"),
+            }
+        }
+    });
+}
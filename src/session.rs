@@ -1,17 +1,16 @@
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use rand::Rng;
 
 pub struct Session {
     pub user_id: u32,
     pub session_id: u32,
+    #[allow(dead_code)]
     pub created_at: DateTime<Utc>,
     pub expires_at: DateTime<Utc>,
 }
 
-const SESSION_LIFETIME: chrono::TimeDelta = Duration::hours(1);
-
 impl Session {
-    pub fn new(user_id: u32) -> Self {
+    pub fn new(user_id: u32, lifetime: TimeDelta) -> Self {
         let mut random_number = rand::thread_rng();
         let session_id: u32 = random_number.gen();
         let now = Utc::now();
@@ -19,7 +18,7 @@ impl Session {
             user_id,
             session_id, 
             created_at: now,
-            expires_at: now + SESSION_LIFETIME
+            expires_at: now + lifetime
         }
     }
 }
@@ -0,0 +1,92 @@
+//! Fixtures shared by the unit tests.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum_extra::extract::cookie::Cookie;
+use axum_extra::extract::CookieJar;
+
+use crate::config::Config;
+use crate::db::{self, DatabaseConnection};
+use crate::keyring::{Field, Keyring};
+use crate::migrations;
+use crate::session::Session;
+
+/// A fresh database and stash with one user, `alice`, in a directory of
+/// their own that is removed again on drop.
+pub struct TestDb {
+    pub dir: PathBuf,
+    pub config: Arc<Config>,
+    pub db: DatabaseConnection,
+    pub keyring: Keyring,
+    pub user_id: u32,
+}
+
+impl TestDb {
+    pub async fn open() -> Self {
+        let dir = std::env::temp_dir().join(format!("senmon-test-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            database: dir.join("senmon.db"),
+            stash_dir: dir.join("stash"),
+            master_key_file: dir.join("senmon.key"),
+            // Keeps tests that store files fast, the cost is recorded per file.
+            kdf_iterations: 1_000,
+            ..Config::default()
+        };
+        let db = DatabaseConnection::open(&config.database).unwrap();
+        migrations::migrate(&mut db.get().unwrap(), false).unwrap();
+        assert!(db::add_user(&db, "alice", "x").await.is_none());
+        let user_id = db::get_user_id(&db, "alice").await.unwrap();
+        TestDb {
+            dir,
+            keyring: Keyring::load(&config).unwrap(),
+            config: Arc::new(config),
+            db,
+            user_id,
+        }
+    }
+
+    /// Records a file of alice's the way an upload does, without a blob.
+    pub fn add_file(&self, folder_id: i64, file_name: &str) -> i64 {
+        let cnx = self.db.get().unwrap();
+        let key = self.keyring.user_key(&cnx, self.user_id).unwrap();
+        let (name_tag, name_sealed) = key.seal_name(Field::FileName, file_name);
+        cnx.execute(
+            "INSERT INTO file_state(file_owner, folder_id, name_tag, name_sealed, salt, blob) VALUES(?1, ?2, ?3, ?4, 'salt', ?3);",
+            (self.user_id, folder_id, name_tag, name_sealed),
+        )
+        .unwrap();
+        cnx.last_insert_rowid()
+    }
+
+    /// Names of alice's files in `folder_id`, sorted.
+    pub fn file_names(&self, folder_id: i64) -> Vec<String> {
+        let cnx = self.db.get().unwrap();
+        let key = self.keyring.user_key(&cnx, self.user_id).unwrap();
+        let mut stmt = cnx
+            .prepare("SELECT name_sealed FROM file_state WHERE file_owner=?1 AND folder_id=?2;")
+            .unwrap();
+        let rows = stmt
+            .query_map((self.user_id, folder_id), |r| r.get::<_, String>(0))
+            .unwrap();
+        let mut names: Vec<String> = rows
+            .map(|sealed| key.open_name(Field::FileName, &sealed.unwrap()).unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Logs alice in and returns the session cookie a browser would send.
+    pub async fn login(&self) -> CookieJar {
+        let session = Session::new(self.user_id, self.config.session_lifetime());
+        assert!(db::session_serialize(&self.db, &session).await.is_none());
+        CookieJar::new().add(Cookie::new("session", session.session_id.to_string()))
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
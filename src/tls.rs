@@ -0,0 +1,102 @@
+//! HTTPS termination. The certificate is loaded with rustls and swapped in
+//! place when the process gets SIGHUP or either PEM file changes on disk, so
+//! renewals need no restart. Connections that are already open keep the
+//! certificate they were accepted with.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use axum::extract::Host;
+use axum::http::{header, HeaderValue, StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::signal::unix::{signal, SignalKind};
+use tower_http::set_header::SetResponseHeaderLayer;
+
+const WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+pub async fn load(cert: &Path, key: &Path) -> std::io::Result<RustlsConfig> {
+    // Both the ring and aws-lc providers end up linked in, so rustls can not
+    // pick one on its own. Installing fails harmlessly if one already is.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    RustlsConfig::from_pem_file(cert, key).await
+}
+
+/// Reloads the certificate on SIGHUP and whenever the modification time of
+/// the certificate or key changes. A reload that fails keeps serving the old
+/// certificate.
+pub fn spawn_reloader(tls: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => Some(hangup),
+            Err(why) => {
+                tracing::warn!("tls: can not listen for SIGHUP: {why}");
+                None
+            }
+        };
+        let mut seen = modified(&cert, &key);
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let now = modified(&cert, &key);
+                    if now == seen {
+                        continue;
+                    }
+                    seen = now;
+                }
+                Some(()) = async { hangup.as_mut()?.recv().await } => {}
+            }
+            match tls.reload_from_pem_file(&cert, &key).await {
+                Ok(()) => tracing::info!("tls: reloaded {}", cert.display()),
+                Err(why) => tracing::error!("tls: keeping the old certificate: {why}"),
+            }
+        }
+    });
+}
+
+fn modified(cert: &Path, key: &Path) -> Option<(SystemTime, SystemTime)> {
+    let mtime = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    Some((mtime(cert)?, mtime(key)?))
+}
+
+/// Tells browsers to only ever reach the server over HTTPS from now on, so
+/// that they stop trying the redirect port first.
+pub fn hsts_layer() -> SetResponseHeaderLayer<HeaderValue> {
+    SetResponseHeaderLayer::overriding(
+        header::STRICT_TRANSPORT_SECURITY,
+        HeaderValue::from_static("max-age=31536000"),
+    )
+}
+
+/// Answers every plain HTTP request on `listen` with a permanent redirect to
+/// the same path on the HTTPS port.
+pub async fn serve_redirect(listen: SocketAddr, https_port: u16) {
+    let listener = match tokio::net::TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(why) => {
+            tracing::error!("{listen}: {why}");
+            return;
+        }
+    };
+    let router = Router::new().fallback(move |Host(host): Host, uri: Uri| async move {
+        redirect(&host, https_port, &uri)
+    });
+    if let Err(why) = axum::serve(listener, router).await {
+        tracing::error!("{listen}: {why}");
+    }
+}
+
+fn redirect(host: &str, https_port: u16, uri: &Uri) -> Response {
+    let Ok(authority) = host.parse::<axum::http::uri::Authority>() else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    let location = match https_port {
+        443 => format!("https://{}{path}", authority.host()),
+        port => format!("https://{}:{port}{path}", authority.host()),
+    };
+    Redirect::permanent(&location).into_response()
+}
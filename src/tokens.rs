@@ -0,0 +1,374 @@
+//! Personal API tokens. A token is `snm_` followed by 64 hex digits; only
+//! its SHA-256 is stored, which is enough for a random 256 bit secret. Tokens
+//! carry scopes and always expire.
+
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::db::DatabaseConnection;
+use crate::types::SenmonError;
+
+const TOKEN_PREFIX: &str = "snm_";
+pub const DEFAULT_LIFETIME_DAYS: u32 = 30;
+pub const MAX_LIFETIME_DAYS: u32 = 365;
+const MAX_NAME_LEN: usize = 64;
+/// How stale `last_used_at` may get before a request updates it.
+const LAST_USED_RESOLUTION: chrono::TimeDelta = Duration::minutes(1);
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// List folders and trash, download files.
+    Read,
+    /// Upload, delete, restore and manage folders.
+    Write,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read" => Some(Scope::Read),
+            "write" => Some(Scope::Write),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiToken {
+    pub token_id: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: String,
+    pub expires_at: String,
+    pub last_used_at: Option<String>,
+}
+
+impl ApiToken {
+    /// Scopes joined for display, e.g. `read, write`.
+    pub fn scope_list(&self) -> String {
+        let scopes: Vec<_> = self.scopes.iter().map(|s| s.as_str()).collect();
+        scopes.join(", ")
+    }
+}
+
+/// Who a valid bearer token belongs to.
+pub struct TokenOwner {
+    pub token_id: i64,
+    pub user_id: u32,
+    pub scopes: Vec<Scope>,
+}
+
+fn hash(token: &str) -> String {
+    hex::encode(ring::digest::digest(
+        &ring::digest::SHA256,
+        token.as_bytes(),
+    ))
+}
+
+fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes.split(' ').filter_map(Scope::parse).collect()
+}
+
+fn join_scopes(scopes: &[Scope]) -> String {
+    let scopes: Vec<_> = scopes.iter().map(|s| s.as_str()).collect();
+    scopes.join(" ")
+}
+
+/// Creates a token and returns it in plain text together with its record.
+/// This is the only time the plain text exists on the server.
+pub async fn create(
+    db: &DatabaseConnection,
+    user_id: u32,
+    name: &str,
+    scopes: &[Scope],
+    lifetime_days: u32,
+) -> Result<(String, ApiToken), SenmonError> {
+    let name = name.trim().to_string();
+    if name.is_empty()
+        || name.chars().count() > MAX_NAME_LEN
+        || scopes.is_empty()
+        || !(1..=MAX_LIFETIME_DAYS).contains(&lifetime_days)
+    {
+        return Err(SenmonError::BadRequest);
+    }
+    let mut scopes = scopes.to_vec();
+    scopes.sort_by_key(|s| s.as_str());
+    scopes.dedup();
+
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill(&mut secret);
+    let token = format!("{TOKEN_PREFIX}{}", hex::encode(secret));
+    let now = Utc::now();
+    let mut record = ApiToken {
+        token_id: 0,
+        name,
+        scopes,
+        created_at: now.to_rfc3339(),
+        expires_at: (now + Duration::days(lifetime_days.into())).to_rfc3339(),
+        last_used_at: None,
+    };
+
+    let (token_hash, name, scopes) = (
+        hash(&token),
+        record.name.clone(),
+        join_scopes(&record.scopes),
+    );
+    let (created_at, expires_at) = (record.created_at.clone(), record.expires_at.clone());
+    record.token_id = db
+        .run(move |cnx| {
+            cnx.execute(
+                "INSERT INTO api_tokens(user_id, name, token_hash, scopes, created_at, expires_at) VALUES(?1, ?2, ?3, ?4, ?5, ?6);",
+                (user_id, name, token_hash, scopes, created_at, expires_at),
+            )?;
+            Ok::<_, rusqlite::Error>(cnx.last_insert_rowid())
+        })
+        .await?;
+    Ok((token, record))
+}
+
+pub async fn list(db: &DatabaseConnection, user_id: u32) -> Result<Vec<ApiToken>, rusqlite::Error> {
+    db.run(move |cnx| {
+        let mut stmt = cnx.prepare_cached(
+            "SELECT token_id, name, scopes, created_at, expires_at, last_used_at FROM api_tokens WHERE user_id=?1 ORDER BY created_at DESC;",
+        )?;
+        let rows = stmt.query_map([user_id], |row| {
+            Ok(ApiToken {
+                token_id: row.get(0)?,
+                name: row.get(1)?,
+                scopes: parse_scopes(&row.get::<_, String>(2)?),
+                created_at: row.get(3)?,
+                expires_at: row.get(4)?,
+                last_used_at: row.get(5)?,
+            })
+        })?;
+        rows.collect()
+    })
+    .await
+}
+
+pub async fn revoke(
+    db: &DatabaseConnection,
+    user_id: u32,
+    token_id: i64,
+) -> Result<(), SenmonError> {
+    let revoked = db
+        .run(move |cnx| {
+            cnx.execute(
+                "DELETE FROM api_tokens WHERE user_id=?1 AND token_id=?2;",
+                (user_id, token_id),
+            )
+        })
+        .await?;
+    match revoked {
+        0 => Err(SenmonError::NotFound),
+        _ => Ok(()),
+    }
+}
+
+/// Looks up an unexpired token and notes that it was used. The lookup is a
+/// plain read; `last_used_at` is only written when it is more than
+/// [`LAST_USED_RESOLUTION`] old, so a busy token does not take the write
+/// lock on every request.
+pub async fn authenticate(
+    db: &DatabaseConnection,
+    token: &str,
+) -> Result<Option<TokenOwner>, rusqlite::Error> {
+    if !token.starts_with(TOKEN_PREFIX) {
+        return Ok(None);
+    }
+    let token_hash = hash(token);
+    db.run(move |cnx| {
+        let now = Utc::now();
+        let found = cnx
+            .query_row(
+                "SELECT t.token_id, t.user_id, t.scopes, t.last_used_at FROM api_tokens t JOIN user_reg u ON u.user_id = t.user_id WHERE t.token_hash=?1 AND t.expires_at > ?2;",
+                (&token_hash, now.to_rfc3339()),
+                |row| {
+                    let owner = TokenOwner {
+                        token_id: row.get(0)?,
+                        user_id: row.get(1)?,
+                        scopes: parse_scopes(&row.get::<_, String>(2)?),
+                    };
+                    Ok((owner, row.get::<_, Option<String>>(3)?))
+                },
+            )
+            .optional()?;
+        let Some((owner, last_used_at)) = found else {
+            return Ok(None);
+        };
+        let stale = last_used_at
+            .and_then(|at| DateTime::parse_from_rfc3339(&at).ok())
+            .is_none_or(|at| at < now - LAST_USED_RESOLUTION);
+        if stale {
+            cnx.execute(
+                "UPDATE api_tokens SET last_used_at=?1 WHERE token_id=?2;",
+                (now.to_rfc3339(), owner.token_id),
+            )?;
+        }
+        Ok(Some(owner))
+    })
+    .await
+}
+
+/// Removes expired tokens, for `senmon gc`.
+pub async fn purge_expired(
+    db: &DatabaseConnection,
+    dry_run: bool,
+) -> Result<usize, rusqlite::Error> {
+    let now = Utc::now().to_rfc3339();
+    db.run(move |cnx| {
+        if dry_run {
+            cnx.query_row(
+                "SELECT COUNT(*) FROM api_tokens WHERE expires_at <= ?1;",
+                [now],
+                |r| r.get(0),
+            )
+        } else {
+            cnx.execute("DELETE FROM api_tokens WHERE expires_at <= ?1;", [now])
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDb;
+
+    #[tokio::test]
+    async fn authenticates_with_its_scopes() {
+        let test = TestDb::open().await;
+        let scopes = [Scope::Write, Scope::Read, Scope::Write];
+        let (token, record) = create(&test.db, test.user_id, " cli ", &scopes, 30)
+            .await
+            .unwrap();
+        assert_eq!(record.name, "cli");
+        assert_eq!(record.scope_list(), "read, write");
+
+        let owner = authenticate(&test.db, &token).await.unwrap().unwrap();
+        assert_eq!(
+            (owner.token_id, owner.user_id),
+            (record.token_id, test.user_id)
+        );
+        assert_eq!(owner.scopes, [Scope::Read, Scope::Write]);
+        let listed = list(&test.db, test.user_id).await.unwrap();
+        assert!(listed[0].last_used_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn last_use_is_recorded_once_a_minute() {
+        let test = TestDb::open().await;
+        let (token, _) = create(&test.db, test.user_id, "cli", &[Scope::Read], 1)
+            .await
+            .unwrap();
+        let last_used = || async {
+            list(&test.db, test.user_id).await.unwrap()[0]
+                .last_used_at
+                .clone()
+        };
+        assert!(last_used().await.is_none());
+
+        authenticate(&test.db, &token).await.unwrap().unwrap();
+        let first = last_used().await.unwrap();
+        authenticate(&test.db, &token).await.unwrap().unwrap();
+        assert_eq!(last_used().await.unwrap(), first);
+
+        let stale = (Utc::now() - LAST_USED_RESOLUTION - Duration::seconds(1)).to_rfc3339();
+        test.db
+            .run({
+                let stale = stale.clone();
+                move |cnx| cnx.execute("UPDATE api_tokens SET last_used_at=?1;", [stale])
+            })
+            .await
+            .unwrap();
+        authenticate(&test.db, &token).await.unwrap().unwrap();
+        assert!(last_used().await.unwrap() > stale);
+    }
+
+    #[tokio::test]
+    async fn unknown_tokens() {
+        let test = TestDb::open().await;
+        let (token, _) = create(&test.db, test.user_id, "cli", &[Scope::Read], 1)
+            .await
+            .unwrap();
+        let mut other = token.clone();
+        other.pop();
+        other.push(if token.ends_with('0') { '1' } else { '0' });
+        assert!(authenticate(&test.db, &other).await.unwrap().is_none());
+        assert!(authenticate(&test.db, &token[TOKEN_PREFIX.len()..])
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_and_revoked_tokens() {
+        let test = TestDb::open().await;
+        let (expired, _) = create(&test.db, test.user_id, "old", &[Scope::Read], 1)
+            .await
+            .unwrap();
+        let (revoked, record) = create(&test.db, test.user_id, "cli", &[Scope::Read], 1)
+            .await
+            .unwrap();
+        let past = (Utc::now() - Duration::seconds(1)).to_rfc3339();
+        test.db
+            .run(move |cnx| {
+                cnx.execute(
+                    "UPDATE api_tokens SET expires_at=?1 WHERE name='old';",
+                    [past],
+                )
+            })
+            .await
+            .unwrap();
+        assert!(authenticate(&test.db, &expired).await.unwrap().is_none());
+        assert_eq!(purge_expired(&test.db, false).await.unwrap(), 1);
+
+        revoke(&test.db, test.user_id, record.token_id)
+            .await
+            .unwrap();
+        assert!(authenticate(&test.db, &revoked).await.unwrap().is_none());
+        assert!(matches!(
+            revoke(&test.db, test.user_id, record.token_id).await,
+            Err(SenmonError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_requests() {
+        let test = TestDb::open().await;
+        let long_name = "x".repeat(MAX_NAME_LEN + 1);
+        for (name, scopes, days) in [
+            ("  ", &[Scope::Read][..], 30),
+            (long_name.as_str(), &[Scope::Read], 30),
+            ("cli", &[], 30),
+            ("cli", &[Scope::Read], 0),
+            ("cli", &[Scope::Read], MAX_LIFETIME_DAYS + 1),
+        ] {
+            assert!(matches!(
+                create(&test.db, test.user_id, name, scopes, days).await,
+                Err(SenmonError::BadRequest)
+            ));
+        }
+        assert!(list(&test.db, test.user_id).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn scopes_round_trip() {
+        assert_eq!(join_scopes(&[Scope::Read, Scope::Write]), "read write");
+        assert_eq!(
+            parse_scopes("read write admin"),
+            [Scope::Read, Scope::Write]
+        );
+    }
+}
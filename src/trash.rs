@@ -0,0 +1,265 @@
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+use rusqlite::TransactionBehavior;
+
+use crate::config::Config;
+use crate::db::DatabaseConnection;
+use crate::folders::ROOT_FOLDER;
+use crate::keyring::{Field, Keyring};
+use crate::types::SenmonError;
+
+const PURGE_INTERVAL: chrono::TimeDelta = Duration::hours(1);
+
+pub struct TrashEntry {
+    pub trash_id: i64,
+    pub file_name: String,
+    pub deleted_at: String,
+}
+
+/// Moves a `file_state` row into the recycle bin within an open transaction.
+/// The blob stays where it is, blob paths are never reused, and so does the
+/// file id the blob is bound to.
+pub fn trash_in_tx(tx: &rusqlite::Transaction, file_id: i64) -> rusqlite::Result<()> {
+    tx.execute(
+        "INSERT INTO file_trash(file_id, file_owner, folder_id, name_tag, name_sealed, meta_sealed, salt, kdf_iterations, blob, e2e, deleted_at)
+        SELECT file_id, file_owner, folder_id, name_tag, name_sealed, meta_sealed, salt, kdf_iterations, blob, e2e, ?2 FROM file_state WHERE file_id=?1;",
+        (file_id, Utc::now().to_rfc3339()),
+    )?;
+    tx.execute("DELETE FROM file_state WHERE file_id=?1;", [file_id])?;
+    Ok(())
+}
+
+/// Moves a file to the recycle bin.
+pub async fn trash_file(
+    db: &DatabaseConnection,
+    user_id: u32,
+    file_id: i64,
+) -> Result<(), SenmonError> {
+    db.run(move |cnx| {
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let owned: bool = tx.query_row(
+            "SELECT COUNT(*) > 0 FROM file_state WHERE file_owner=?1 AND file_id=?2;",
+            (user_id, file_id),
+            |r| r.get(0),
+        )?;
+        if !owned {
+            return Err(SenmonError::NotFound);
+        }
+        trash_in_tx(&tx, file_id)?;
+        Ok(tx.commit()?)
+    })
+    .await
+}
+
+/// Puts a trashed file back where it was, provided that name has not been
+/// reused in the meantime. Files whose folder has since been deleted are
+/// restored into the root folder. The file keeps its id, which is never
+/// handed out again; files trashed before ids were kept get a new one.
+pub async fn restore(
+    db: &DatabaseConnection,
+    user_id: u32,
+    trash_id: i64,
+) -> Result<(), SenmonError> {
+    db.run(move |cnx| {
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let (folder_id, name_tag): (i64, String) = tx
+            .query_row(
+                "SELECT folder_id, name_tag FROM file_trash WHERE trash_id=?1 AND file_owner=?2;",
+                (trash_id, user_id),
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .map_err(|_| SenmonError::NotFound)?;
+
+        let folder_exists: Result<u32, _> = tx.query_row(
+            "SELECT 1 FROM folders WHERE folder_id=?1 AND owner=?2;",
+            (folder_id, user_id),
+            |r| r.get(0),
+        );
+        let folder_id = match folder_exists {
+            Ok(_) => folder_id,
+            Err(_) => ROOT_FOLDER,
+        };
+
+        let taken: Result<u32, _> = tx.query_row(
+            "SELECT 1 FROM file_state WHERE file_owner=?1 AND folder_id=?2 AND name_tag=?3;",
+            (user_id, folder_id, &name_tag),
+            |r| r.get(0),
+        );
+        if taken.is_ok() {
+            return Err(SenmonError::Conflict);
+        }
+
+        tx.execute(
+            "INSERT INTO file_state(file_id, file_owner, folder_id, name_tag, name_sealed, meta_sealed, salt, kdf_iterations, blob, e2e)
+            SELECT file_id, file_owner, ?2, name_tag, name_sealed, meta_sealed, salt, kdf_iterations, blob, e2e FROM file_trash WHERE trash_id=?1;",
+            (trash_id, folder_id),
+        )
+        .and_then(|_| tx.execute("DELETE FROM file_trash WHERE trash_id=?1;", [trash_id]))
+        .and_then(|_| tx.commit())
+        .map_err(SenmonError::from)
+    })
+    .await
+}
+
+pub async fn list_trash(
+    db: &DatabaseConnection,
+    keyring: &Keyring,
+    user_id: u32,
+) -> Result<Vec<TrashEntry>, rusqlite::Error> {
+    let keyring = keyring.clone();
+    db.run(move |cnx| {
+        let key = keyring.user_key(cnx, user_id)?;
+        let mut stmt = cnx.prepare_cached(
+            "SELECT trash_id, name_sealed, deleted_at FROM file_trash WHERE file_owner=?1 ORDER BY deleted_at DESC;",
+        )?;
+        let rows = stmt.query_map([user_id], |row| {
+            Ok(TrashEntry {
+                trash_id: row.get(0)?,
+                file_name: key.open_name(Field::FileName, &row.get::<_, String>(1)?)?,
+                deleted_at: row.get(2)?,
+            })
+        })?;
+        rows.collect()
+    })
+    .await
+}
+
+/// Permanently removes every entry that has been in the recycle bin for
+/// longer than `retention`, together with its blob.
+pub async fn purge_expired(
+    db: &DatabaseConnection,
+    config: Arc<Config>,
+) -> Result<usize, rusqlite::Error> {
+    let cutoff = (Utc::now() - config.trash_retention()).to_rfc3339();
+    db.run(move |cnx| {
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let expired: Vec<(i64, String)> = {
+            let mut stmt =
+                tx.prepare_cached("SELECT trash_id, blob FROM file_trash WHERE deleted_at < ?1;")?;
+            let rows = stmt.query_map([&cutoff], |r| Ok((r.get(0)?, r.get(1)?)))?;
+            rows.collect::<Result<_, _>>()?
+        };
+
+        for (trash_id, blob) in &expired {
+            tx.execute("DELETE FROM file_trash WHERE trash_id=?1;", [trash_id])?;
+            tx.execute("DELETE FROM file_health WHERE blob=?1;", [blob])?;
+        }
+        tx.commit()?;
+
+        for (_, blob) in &expired {
+            if let Err(why) = std::fs::remove_file(config.blob_path(blob)) {
+                if why.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("purge: {blob}: {why}");
+                }
+            }
+        }
+        Ok(expired.len())
+    })
+    .await
+}
+
+pub fn spawn_purger(db: DatabaseConnection, config: Arc<Config>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL.to_std().unwrap());
+        loop {
+            interval.tick().await;
+            if let Err(why) = purge_expired(&db, config.clone()).await {
+                tracing::error!("purge: {why}");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDb;
+
+    async fn trash(test: &TestDb, file_name: &str) -> i64 {
+        let file_id = test.add_file(ROOT_FOLDER, file_name);
+        trash_file(&test.db, test.user_id, file_id).await.unwrap();
+        let entries = list_trash(&test.db, &test.keyring, test.user_id)
+            .await
+            .unwrap();
+        entries
+            .iter()
+            .find(|e| e.file_name == file_name)
+            .unwrap()
+            .trash_id
+    }
+
+    fn files(test: &TestDb) -> u32 {
+        let cnx = test.db.get().unwrap();
+        cnx.query_row("SELECT COUNT(*) FROM file_state;", [], |r| r.get(0))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn restores_under_the_old_name() {
+        let test = TestDb::open().await;
+        let trash_id = trash(&test, "notes.txt").await;
+        assert_eq!(files(&test), 0);
+
+        restore(&test.db, test.user_id, trash_id).await.unwrap();
+        assert_eq!(files(&test), 1);
+        assert!(list_trash(&test.db, &test.keyring, test.user_id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn restore_into_a_taken_name() {
+        let test = TestDb::open().await;
+        let trash_id = trash(&test, "notes.txt").await;
+        test.add_file(ROOT_FOLDER, "notes.txt");
+
+        let restored = restore(&test.db, test.user_id, trash_id).await;
+        assert!(matches!(restored, Err(SenmonError::Conflict)));
+        assert_eq!(files(&test), 1);
+        assert_eq!(
+            list_trash(&test.db, &test.keyring, test.user_id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn restore_of_another_users_file() {
+        let test = TestDb::open().await;
+        let trash_id = trash(&test, "notes.txt").await;
+        let restored = restore(&test.db, test.user_id + 1, trash_id).await;
+        assert!(matches!(restored, Err(SenmonError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn purges_after_the_retention_period() {
+        let test = TestDb::open().await;
+        let old = trash(&test, "old.txt").await;
+        trash(&test, "new.txt").await;
+        let deleted_at =
+            (Utc::now() - test.config.trash_retention() - Duration::minutes(1)).to_rfc3339();
+        {
+            let cnx = test.db.get().unwrap();
+            cnx.execute(
+                "UPDATE file_trash SET deleted_at=?1 WHERE trash_id=?2;",
+                (deleted_at, old),
+            )
+            .unwrap();
+        }
+
+        assert_eq!(
+            purge_expired(&test.db, test.config.clone()).await.unwrap(),
+            1
+        );
+        let entries = list_trash(&test.db, &test.keyring, test.user_id)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].file_name, "new.txt");
+    }
+}
@@ -0,0 +1,688 @@
+//! Resumable uploads following the tus 1.0 protocol
+//! (<https://tus.io/protocols/resumable-upload>), with the `creation`,
+//! `termination` and `expiration` extensions.
+//!
+//! Chunks are appended to a staging file under `.uploads` in the stash. Once
+//! the last byte arrives the staged file is encrypted and stored through
+//! [`handlers::store_upload`], exactly like a multipart upload. The file
+//! password only ever lives in memory, so pending uploads do not survive a
+//! restart and the staging directory is cleared on startup. Uploads that
+//! see no request for [`UPLOAD_LIFETIME`] expire, and a sweeper drops them
+//! together with their staged data.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::Response;
+use axum_extra::extract::CookieJar;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use futures_util::StreamExt;
+use rand::Rng;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::Config;
+use crate::db::DatabaseConnection;
+use crate::folders;
+use crate::handlers::{self, Sealing, UploadBody, UploadFile};
+use crate::keyring::Keyring;
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+/// Largest upload accepted, by tus and by `POST /api/v1/files`.
+pub const TUS_MAX_SIZE: u64 = 256 * 1024 * 1024;
+/// Staging directory, relative to the stash.
+const STAGING_DIR: &str = ".uploads";
+/// How long an upload is kept after its last request.
+const UPLOAD_LIFETIME: chrono::TimeDelta = Duration::hours(24);
+const SWEEP_INTERVAL: chrono::TimeDelta = Duration::minutes(10);
+
+struct PendingUpload {
+    owner: u32,
+    file_name: String,
+    content_type: Option<String>,
+    folder: String,
+    sealing: Sealing,
+    salt: String,
+    length: u64,
+    offset: u64,
+    in_progress: bool,
+    expires_at: DateTime<Utc>,
+}
+
+impl PendingUpload {
+    fn is_live(&self, now: DateTime<Utc>) -> bool {
+        self.in_progress || self.expires_at > now
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct TusUploads {
+    pending: Arc<Mutex<HashMap<String, PendingUpload>>>,
+}
+
+impl TusUploads {
+    /// Drops the uploads that expired and returns their ids. Uploads being
+    /// written to are kept, they get a new expiry when the write ends.
+    fn sweep(&self) -> Vec<String> {
+        let now = Utc::now();
+        let mut expired = Vec::new();
+        self.pending.lock().unwrap().retain(|upload_id, upload| {
+            let live = upload.is_live(now);
+            if !live {
+                expired.push(upload_id.clone());
+            }
+            live
+        });
+        expired
+    }
+}
+
+pub fn spawn_sweeper(uploads: TusUploads, config: Arc<Config>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL.to_std().unwrap());
+        loop {
+            interval.tick().await;
+            for upload_id in uploads.sweep() {
+                let _ = tokio::fs::remove_file(staging_path(&config, &upload_id)).await;
+            }
+        }
+    });
+}
+
+/// `Upload-Expires` is an HTTP date.
+fn http_date(at: DateTime<Utc>) -> String {
+    at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Scratch file in the staging directory. It is on the same file system as
+/// the stash, so finished blobs can be renamed into place.
+pub fn staging_path(config: &Config, upload_id: &str) -> PathBuf {
+    config.stash_dir.join(STAGING_DIR).join(upload_id)
+}
+
+/// Removes staged data left over from a previous run.
+pub fn clear_staging(config: &Config) -> std::io::Result<()> {
+    let staging = config.stash_dir.join(STAGING_DIR);
+    match std::fs::remove_dir_all(&staging) {
+        Err(why) if why.kind() != std::io::ErrorKind::NotFound => return Err(why),
+        _ => {}
+    }
+    std::fs::create_dir_all(staging)
+}
+
+fn tus_response(status: StatusCode) -> axum::http::response::Builder {
+    Response::builder()
+        .status(status)
+        .header("Tus-Resumable", TUS_VERSION)
+}
+
+fn empty(status: StatusCode) -> Response {
+    tus_response(status).body(Body::empty()).unwrap()
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// Parses `Upload-Metadata`, a comma separated list of keys each followed by
+/// a base64 encoded value.
+fn parse_metadata(value: Option<&HeaderValue>) -> Option<HashMap<String, String>> {
+    let mut metadata = HashMap::new();
+    let Some(value) = value else {
+        return Some(metadata);
+    };
+    for pair in value.to_str().ok()?.split(',') {
+        let mut parts = pair.trim().splitn(2, ' ');
+        let key = parts.next()?;
+        let value = match parts.next() {
+            Some(encoded) => base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())?,
+            None => String::new(),
+        };
+        metadata.insert(key.to_string(), value);
+    }
+    Some(metadata)
+}
+
+fn supported_version(headers: &HeaderMap) -> bool {
+    headers
+        .get("Tus-Resumable")
+        .is_some_and(|v| v.as_bytes() == TUS_VERSION.as_bytes())
+}
+
+pub async fn options() -> Response {
+    tus_response(StatusCode::NO_CONTENT)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Extension", TUS_EXTENSIONS)
+        .header("Tus-Max-Size", TUS_MAX_SIZE)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Creation. Expects `Upload-Length` and `Upload-Metadata` carrying
+/// `filename`, optionally `folder`, and either `pwd` or, for uploads the
+/// client sealed itself, `salt` and `kdf_iterations`.
+pub async fn create(
+    State(db): State<DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    State(uploads): State<TusUploads>,
+    State(keyring): State<Keyring>,
+    jar: CookieJar,
+    headers: HeaderMap,
+) -> Response {
+    if !supported_version(&headers) {
+        return empty(StatusCode::PRECONDITION_FAILED);
+    }
+    let Some(owner) = handlers::session_user_id(&db, &jar).await else {
+        return empty(StatusCode::UNAUTHORIZED);
+    };
+    let Some(length) = header_u64(&headers, "Upload-Length") else {
+        return empty(StatusCode::BAD_REQUEST);
+    };
+    if length > TUS_MAX_SIZE {
+        return empty(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let Some(mut metadata) = parse_metadata(headers.get("Upload-Metadata")) else {
+        return empty(StatusCode::BAD_REQUEST);
+    };
+    let Some(file_name) = metadata.remove("filename") else {
+        return empty(StatusCode::BAD_REQUEST);
+    };
+    let Ok((sealing, salt)) = handlers::upload_sealing(
+        metadata.remove("pwd"),
+        metadata.remove("salt"),
+        metadata.remove("kdf_iterations"),
+    ) else {
+        return empty(StatusCode::BAD_REQUEST);
+    };
+
+    let folder = metadata.remove("folder").unwrap_or_default();
+    if !folders::is_valid_name(&file_name) {
+        return empty(StatusCode::BAD_REQUEST);
+    }
+    if let Err(why) = folders::resolve_folder(&db, &keyring, owner, &folder).await {
+        return empty(why.status());
+    }
+
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill(&mut id);
+    let upload_id = hex::encode(id);
+    if tokio::fs::write(staging_path(&config, &upload_id), b"").await.is_err() {
+        return empty(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let expires_at = Utc::now() + UPLOAD_LIFETIME;
+    uploads.pending.lock().unwrap().insert(
+        upload_id.clone(),
+        PendingUpload {
+            owner,
+            file_name,
+            content_type: metadata.remove("filetype"),
+            folder,
+            sealing,
+            salt,
+            length,
+            offset: 0,
+            in_progress: false,
+            expires_at,
+        },
+    );
+
+    tus_response(StatusCode::CREATED)
+        .header("Location", format!("/api/tus/{}", upload_id))
+        .header("Upload-Offset", 0)
+        .header("Upload-Expires", http_date(expires_at))
+        .body(Body::empty())
+        .unwrap()
+}
+
+pub async fn status(
+    State(db): State<DatabaseConnection>,
+    State(uploads): State<TusUploads>,
+    jar: CookieJar,
+    Path(upload_id): Path<String>,
+) -> Response {
+    let Some(user_id) = handlers::session_user_id(&db, &jar).await else {
+        return empty(StatusCode::UNAUTHORIZED);
+    };
+    let pending = uploads.pending.lock().unwrap();
+    match pending.get(&upload_id) {
+        Some(upload) if upload.owner == user_id && upload.is_live(Utc::now()) => {
+            tus_response(StatusCode::OK)
+                .header("Upload-Offset", upload.offset)
+                .header("Upload-Length", upload.length)
+                .header("Upload-Expires", http_date(upload.expires_at))
+                .header("Cache-Control", "no-store")
+                .body(Body::empty())
+                .unwrap()
+        }
+        _ => empty(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Appends a chunk at `Upload-Offset`. The request that completes the
+/// upload also finalises it, so its status reflects whether the file was
+/// stored.
+#[allow(clippy::too_many_arguments)]
+pub async fn append(
+    State(db): State<DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    State(uploads): State<TusUploads>,
+    State(keyring): State<Keyring>,
+    jar: CookieJar,
+    Path(upload_id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if !supported_version(&headers) {
+        return empty(StatusCode::PRECONDITION_FAILED);
+    }
+    if headers
+        .get("Content-Type")
+        .is_none_or(|v| v.as_bytes() != b"application/offset+octet-stream")
+    {
+        return empty(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+    let Some(user_id) = handlers::session_user_id(&db, &jar).await else {
+        return empty(StatusCode::UNAUTHORIZED);
+    };
+    let Some(offset) = header_u64(&headers, "Upload-Offset") else {
+        return empty(StatusCode::BAD_REQUEST);
+    };
+
+    let length = {
+        let mut pending = uploads.pending.lock().unwrap();
+        match pending.get_mut(&upload_id) {
+            Some(upload) if upload.owner == user_id && upload.is_live(Utc::now()) => {
+                if upload.in_progress {
+                    return empty(StatusCode::LOCKED);
+                }
+                if upload.offset != offset {
+                    return empty(StatusCode::CONFLICT);
+                }
+                upload.in_progress = true;
+                upload.length
+            }
+            _ => return empty(StatusCode::NOT_FOUND),
+        }
+    };
+
+    let staged = staging_path(&config, &upload_id);
+    let claim = ClaimedUpload {
+        uploads: &uploads,
+        upload_id: &upload_id,
+        staged: &staged,
+    };
+    let mut status = write_chunk(&staged, length, body).await;
+    drop(claim);
+
+    let (written, expires_at, finished) = {
+        let mut pending = uploads.pending.lock().unwrap();
+        match pending.get(&upload_id) {
+            Some(upload) if upload.offset == upload.length => {
+                (upload.offset, None, pending.remove(&upload_id))
+            }
+            Some(upload) => (upload.offset, Some(upload.expires_at), None),
+            None => return empty(StatusCode::NOT_FOUND),
+        }
+    };
+
+    if let Some(upload) = finished {
+        status = match finalise(&db, &config, &keyring, &staged, upload).await {
+            Ok(()) => status,
+            Err(failed) => failed,
+        };
+    }
+
+    let mut response = tus_response(status).header("Upload-Offset", written);
+    if let Some(expires_at) = expires_at {
+        response = response.header("Upload-Expires", http_date(expires_at));
+    }
+    response.body(Body::empty()).unwrap()
+}
+
+/// Marks an upload as being written to by a PATCH request. Dropping it
+/// releases the upload and takes the new offset from the staged file, so
+/// whatever made it to disk counts even if the client went away mid-body.
+/// The upload expires [`UPLOAD_LIFETIME`] after the write ends.
+struct ClaimedUpload<'a> {
+    uploads: &'a TusUploads,
+    upload_id: &'a str,
+    staged: &'a std::path::Path,
+}
+
+impl Drop for ClaimedUpload<'_> {
+    fn drop(&mut self) {
+        let staged = std::fs::metadata(self.staged).map(|m| m.len());
+        let mut pending = self.uploads.pending.lock().unwrap();
+        if let Some(upload) = pending.get_mut(self.upload_id) {
+            upload.in_progress = false;
+            upload.expires_at = Utc::now() + UPLOAD_LIFETIME;
+            if let Ok(staged) = staged {
+                upload.offset = staged;
+            }
+        }
+    }
+}
+
+async fn write_chunk(staged: &std::path::Path, length: u64, body: Body) -> StatusCode {
+    let file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(staged)
+        .await;
+    let Ok(mut file) = file else {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    };
+    let Ok(mut written) = file.metadata().await.map(|m| m.len()) else {
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    };
+
+    let mut stream = body.into_data_stream();
+    let mut status = StatusCode::NO_CONTENT;
+    while let Some(chunk) = stream.next().await {
+        let Ok(chunk) = chunk else {
+            break;
+        };
+        if written + chunk.len() as u64 > length {
+            status = StatusCode::BAD_REQUEST;
+            break;
+        }
+        if file.write_all(&chunk).await.is_err() {
+            status = StatusCode::INTERNAL_SERVER_ERROR;
+            break;
+        }
+        written += chunk.len() as u64;
+    }
+    if file.flush().await.is_err() {
+        status = StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    status
+}
+
+async fn finalise(
+    db: &DatabaseConnection,
+    config: &Config,
+    keyring: &Keyring,
+    staged: &std::path::Path,
+    upload: PendingUpload,
+) -> Result<(), StatusCode> {
+    let request = UploadFile {
+        file_name: upload.file_name,
+        folder: upload.folder,
+        file_contents: UploadBody::Staged(staged.to_path_buf()),
+        content_type: upload.content_type,
+        sealing: upload.sealing,
+        salt: upload.salt,
+    };
+    let stored = handlers::store_upload(db, config, keyring, upload.owner, request).await;
+    let _ = tokio::fs::remove_file(staged).await;
+    stored.map(|_| ()).map_err(|why| {
+        tracing::error!("tus: {why}");
+        why.status()
+    })
+}
+
+/// Termination.
+pub async fn terminate(
+    State(db): State<DatabaseConnection>,
+    State(config): State<Arc<Config>>,
+    State(uploads): State<TusUploads>,
+    jar: CookieJar,
+    Path(upload_id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if !supported_version(&headers) {
+        return empty(StatusCode::PRECONDITION_FAILED);
+    }
+    let Some(user_id) = handlers::session_user_id(&db, &jar).await else {
+        return empty(StatusCode::UNAUTHORIZED);
+    };
+    {
+        let mut pending = uploads.pending.lock().unwrap();
+        match pending.get(&upload_id) {
+            Some(upload) if upload.owner == user_id && upload.is_live(Utc::now()) => {
+                pending.remove(&upload_id);
+            }
+            _ => return empty(StatusCode::NOT_FOUND),
+        }
+    }
+    let _ = tokio::fs::remove_file(staging_path(&config, &upload_id)).await;
+    empty(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDb;
+
+    /// alice, logged in, with an empty staging directory.
+    struct Server {
+        test: TestDb,
+        uploads: TusUploads,
+        jar: CookieJar,
+    }
+
+    impl Server {
+        async fn start() -> Self {
+            let test = TestDb::open().await;
+            clear_staging(&test.config).unwrap();
+            Server {
+                jar: test.login().await,
+                uploads: TusUploads::default(),
+                test,
+            }
+        }
+
+        async fn create(&self, length: u64) -> String {
+            let encode = |v: &str| base64::engine::general_purpose::STANDARD.encode(v);
+            let mut headers = HeaderMap::new();
+            headers.insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+            headers.insert("Upload-Length", length.into());
+            let metadata = format!("filename {},pwd {}", encode("notes.txt"), encode("pw"));
+            headers.insert("Upload-Metadata", metadata.parse().unwrap());
+            let response = create(
+                State(self.test.db.clone()),
+                State(self.test.config.clone()),
+                State(self.uploads.clone()),
+                State(self.test.keyring.clone()),
+                self.jar.clone(),
+                headers,
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+            let location = response.headers()["Location"].to_str().unwrap();
+            location.rsplit('/').next().unwrap().to_string()
+        }
+
+        async fn patch(&self, upload_id: &str, offset: u64, chunk: &'static [u8]) -> Response {
+            let mut headers = HeaderMap::new();
+            headers.insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+            headers.insert(
+                "Content-Type",
+                HeaderValue::from_static("application/offset+octet-stream"),
+            );
+            headers.insert("Upload-Offset", offset.into());
+            self.append(upload_id, headers, chunk).await
+        }
+
+        async fn append(
+            &self,
+            upload_id: &str,
+            headers: HeaderMap,
+            chunk: &'static [u8],
+        ) -> Response {
+            append(
+                State(self.test.db.clone()),
+                State(self.test.config.clone()),
+                State(self.uploads.clone()),
+                State(self.test.keyring.clone()),
+                self.jar.clone(),
+                Path(upload_id.to_string()),
+                headers,
+                Body::from(chunk),
+            )
+            .await
+        }
+
+        async fn offset(&self, upload_id: &str) -> u64 {
+            let response = status(
+                State(self.test.db.clone()),
+                State(self.uploads.clone()),
+                self.jar.clone(),
+                Path(upload_id.to_string()),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            upload_offset(&response)
+        }
+
+        fn staged(&self, upload_id: &str) -> PathBuf {
+            staging_path(&self.test.config, upload_id)
+        }
+
+        async fn stored_files(&self) -> u32 {
+            self.test
+                .db
+                .run(|cnx| cnx.query_row("SELECT COUNT(*) FROM file_state;", [], |r| r.get(0)))
+                .await
+                .unwrap()
+        }
+    }
+
+    fn upload_offset(response: &Response) -> u64 {
+        response.headers()["Upload-Offset"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn resumes_at_the_offset() {
+        let server = Server::start().await;
+        let upload_id = server.create(11).await;
+
+        let response = server.patch(&upload_id, 0, b"hello ").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(upload_offset(&response), 6);
+        assert!(response.headers().contains_key("Upload-Expires"));
+        assert_eq!(server.offset(&upload_id).await, 6);
+        assert_eq!(std::fs::read(server.staged(&upload_id)).unwrap(), b"hello ");
+        assert_eq!(server.stored_files().await, 0);
+
+        let response = server.patch(&upload_id, 6, b"world").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(upload_offset(&response), 11);
+        assert_eq!(server.stored_files().await, 1);
+        assert!(!server.staged(&upload_id).exists());
+    }
+
+    #[tokio::test]
+    async fn offset_mismatch() {
+        let server = Server::start().await;
+        let upload_id = server.create(11).await;
+
+        let ahead = server.patch(&upload_id, 3, b"hello").await;
+        assert_eq!(ahead.status(), StatusCode::CONFLICT);
+        assert_eq!(server.offset(&upload_id).await, 0);
+
+        assert_eq!(
+            server.patch(&upload_id, 0, b"hello ").await.status(),
+            StatusCode::NO_CONTENT
+        );
+        let behind = server.patch(&upload_id, 0, b"hello ").await;
+        assert_eq!(behind.status(), StatusCode::CONFLICT);
+        assert_eq!(server.offset(&upload_id).await, 6);
+    }
+
+    #[tokio::test]
+    async fn missing_offset() {
+        let server = Server::start().await;
+        let upload_id = server.create(5).await;
+        let mut headers = HeaderMap::new();
+        headers.insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+        headers.insert(
+            "Content-Type",
+            HeaderValue::from_static("application/offset+octet-stream"),
+        );
+        let response = server.append(&upload_id, headers, b"hello").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn wrong_content_type() {
+        let server = Server::start().await;
+        let upload_id = server.create(5).await;
+        let mut headers = HeaderMap::new();
+        headers.insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+        headers.insert("Content-Type", HeaderValue::from_static("text/plain"));
+        headers.insert("Upload-Offset", 0.into());
+        let response = server.append(&upload_id, headers, b"hello").await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(server.offset(&upload_id).await, 0);
+    }
+
+    #[tokio::test]
+    async fn past_the_length() {
+        let server = Server::start().await;
+        let upload_id = server.create(5).await;
+        let response = server.patch(&upload_id, 0, b"hello world").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(server.offset(&upload_id).await, 0);
+    }
+
+    #[tokio::test]
+    async fn unknown_upload() {
+        let server = Server::start().await;
+        let response = server.patch("0123", 0, b"hello").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn terminated_upload() {
+        let server = Server::start().await;
+        let upload_id = server.create(5).await;
+        let mut headers = HeaderMap::new();
+        headers.insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+        let response = terminate(
+            State(server.test.db.clone()),
+            State(server.test.config.clone()),
+            State(server.uploads.clone()),
+            server.jar.clone(),
+            Path(upload_id.clone()),
+            headers,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!server.staged(&upload_id).exists());
+        let response = server.patch(&upload_id, 0, b"hello").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn abandoned_uploads_expire() {
+        let server = Server::start().await;
+        let stale = server.create(5).await;
+        let fresh = server.create(5).await;
+        server
+            .uploads
+            .pending
+            .lock()
+            .unwrap()
+            .get_mut(&stale)
+            .unwrap()
+            .expires_at = Utc::now() - Duration::seconds(1);
+
+        assert_eq!(server.uploads.sweep(), [stale.as_str()]);
+        let response = server.patch(&stale, 0, b"hello").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(server.offset(&fresh).await, 0);
+    }
+}
@@ -1,12 +1,20 @@
+use std::fmt;
+
+use axum::body::Body;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
 use serde::{Deserialize, Serialize};
 
+use crate::db::PoolError;
 
+#[allow(dead_code)]
 #[derive(Deserialize, Serialize)]
 pub struct User {
     pub id: u64,
     pub name: String,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Serialize)]
 pub struct FileAsset {
     pub id: u64,
@@ -14,12 +22,14 @@ pub struct FileAsset {
     pub owner_id: u64,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Serialize)]
 pub enum Transaction {
     Upload(User, FileAsset),
     Download(User, FileAsset),
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Serialize)]
 pub struct Block {
     pub index: usize,
@@ -28,8 +38,172 @@ pub struct Block {
     pub data: Option<Transaction>,
 }
 
-#[derive(PartialEq, Eq)]
+/// Everything a request can fail with. Handlers return
+/// `Result<_, SenmonError>` and the [`IntoResponse`] impl turns the error into
+/// a status code, a short plain text reason and, for HTMX, a redirect to the
+/// login page when the session is gone.
+#[derive(Debug)]
 pub enum SenmonError {
+    #[allow(dead_code)]
     InvalidIndex,
+    #[allow(dead_code)]
     InvalidParent,
+    /// No session cookie, or one that does not belong to a live session.
+    Unauthenticated,
+    InvalidCredentials,
+    /// The user is logged in but not allowed to do this.
+    Forbidden,
+    UserExists,
+    BadRequest,
+    NotFound,
+    /// The name is taken, or the row changed underneath the request.
+    Conflict,
+    /// The file password or unlock token does not open the file.
+    WrongFileKey,
+    RangeNotSatisfiable(u64),
+    /// The request body is over the size limit.
+    TooLarge,
+    /// The file is end-to-end encrypted, only the client can open it.
+    EndToEnd,
+    /// The blob is not bound to its file and `accept_legacy_blobs` is off.
+    LegacyFormat,
+    Storage(std::io::Error),
+    Database(rusqlite::Error),
+    /// No database connection became free in time.
+    Unavailable,
+}
+
+impl SenmonError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            SenmonError::InvalidIndex | SenmonError::InvalidParent => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            SenmonError::Unauthenticated
+            | SenmonError::InvalidCredentials
+            | SenmonError::WrongFileKey => StatusCode::UNAUTHORIZED,
+            SenmonError::Forbidden => StatusCode::FORBIDDEN,
+            SenmonError::UserExists
+            | SenmonError::Conflict
+            | SenmonError::EndToEnd
+            | SenmonError::LegacyFormat => StatusCode::CONFLICT,
+            SenmonError::BadRequest => StatusCode::BAD_REQUEST,
+            SenmonError::NotFound => StatusCode::NOT_FOUND,
+            SenmonError::RangeNotSatisfiable(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            SenmonError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            SenmonError::Storage(_) | SenmonError::Database(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            SenmonError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl SenmonError {
+    /// Stable machine readable name of the error, for JSON error bodies.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SenmonError::InvalidIndex => "invalid_index",
+            SenmonError::InvalidParent => "invalid_parent",
+            SenmonError::Unauthenticated => "unauthenticated",
+            SenmonError::InvalidCredentials => "invalid_credentials",
+            SenmonError::Forbidden => "forbidden",
+            SenmonError::UserExists => "user_exists",
+            SenmonError::BadRequest => "bad_request",
+            SenmonError::NotFound => "not_found",
+            SenmonError::Conflict => "conflict",
+            SenmonError::WrongFileKey => "wrong_file_key",
+            SenmonError::RangeNotSatisfiable(_) => "range_not_satisfiable",
+            SenmonError::TooLarge => "too_large",
+            SenmonError::EndToEnd => "end_to_end",
+            SenmonError::LegacyFormat => "legacy_format",
+            SenmonError::Storage(_) => "storage",
+            SenmonError::Database(_) => "database",
+            SenmonError::Unavailable => "unavailable",
+        }
+    }
+}
+
+impl fmt::Display for SenmonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SenmonError::InvalidIndex => f.write_str("invalid block index"),
+            SenmonError::InvalidParent => f.write_str("invalid block parent"),
+            SenmonError::Unauthenticated => f.write_str("not logged in"),
+            SenmonError::InvalidCredentials => f.write_str("wrong user name or password"),
+            SenmonError::Forbidden => f.write_str("forbidden"),
+            SenmonError::UserExists => f.write_str("user name is taken"),
+            SenmonError::BadRequest => f.write_str("bad request"),
+            SenmonError::NotFound => f.write_str("not found"),
+            SenmonError::Conflict => f.write_str("name is already taken"),
+            SenmonError::WrongFileKey => f.write_str("wrong file password"),
+            SenmonError::RangeNotSatisfiable(_) => f.write_str("range not satisfiable"),
+            SenmonError::TooLarge => f.write_str("file is too large"),
+            SenmonError::EndToEnd => {
+                f.write_str("file is end-to-end encrypted, decrypt it on the client")
+            }
+            SenmonError::LegacyFormat => {
+                f.write_str("file is stored in a legacy format that is no longer accepted")
+            }
+            SenmonError::Storage(why) => write!(f, "storage error: {why}"),
+            SenmonError::Database(why) => write!(f, "database error: {why}"),
+            SenmonError::Unavailable => f.write_str("database is busy"),
+        }
+    }
+}
+
+impl IntoResponse for SenmonError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            tracing::error!("{self}");
+        }
+        let mut response = Response::builder().status(status);
+        match &self {
+            SenmonError::Unauthenticated => {
+                response = response.header("HX-Redirect", "/assets/html/home.html");
+            }
+            SenmonError::RangeNotSatisfiable(len) => {
+                response = response.header(header::CONTENT_RANGE, format!("bytes */{len}"));
+            }
+            _ => {}
+        }
+        // Server side details only go to the log.
+        let reason = if status.is_server_error() {
+            status.canonical_reason().unwrap_or_default().to_string()
+        } else {
+            self.to_string()
+        };
+        response
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from(reason))
+            .unwrap()
+    }
+}
+
+impl From<std::io::Error> for SenmonError {
+    fn from(why: std::io::Error) -> Self {
+        SenmonError::Storage(why)
+    }
+}
+
+impl From<rusqlite::Error> for SenmonError {
+    fn from(why: rusqlite::Error) -> Self {
+        match why {
+            rusqlite::Error::QueryReturnedNoRows => SenmonError::NotFound,
+            rusqlite::Error::SqliteFailure(e, _)
+                if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                SenmonError::Conflict
+            }
+            why => SenmonError::Database(why),
+        }
+    }
+}
+
+impl From<PoolError> for SenmonError {
+    fn from(why: PoolError) -> Self {
+        tracing::warn!("{why}");
+        SenmonError::Unavailable
+    }
 }
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use zeroize::Zeroizing;
+
+const UNLOCK_LIFETIME: chrono::TimeDelta = Duration::minutes(15);
+const SWEEP_INTERVAL: chrono::TimeDelta = Duration::minutes(1);
+
+/// What an unlock token is bound to: the login session or the API token
+/// that created it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Holder {
+    Session(u32),
+    ApiToken(i64),
+}
+
+struct UnlockedFile {
+    holder: Holder,
+    file_id: i64,
+    key: Zeroizing<[u8; 32]>,
+    expires_at: DateTime<Utc>,
+}
+
+/// File keys derived by `POST /api/files/{id}/unlock`, indexed by the token
+/// handed back to the client. Keys are wiped from memory when they expire or
+/// are evicted.
+#[derive(Clone, Default)]
+pub struct UnlockKeys {
+    unlocked: Arc<Mutex<HashMap<String, UnlockedFile>>>,
+}
+
+impl UnlockKeys {
+    pub fn insert(&self, holder: Holder, file_id: i64, key: [u8; 32]) -> (String, DateTime<Utc>) {
+        let mut token = [0u8; 32];
+        rand::thread_rng().fill(&mut token);
+        let token = hex::encode(token);
+        let expires_at = Utc::now() + UNLOCK_LIFETIME;
+        self.unlocked.lock().unwrap().insert(
+            token.clone(),
+            UnlockedFile {
+                holder,
+                file_id,
+                key: Zeroizing::new(key),
+                expires_at,
+            },
+        );
+        (token, expires_at)
+    }
+
+    /// Returns the key for `file_id` if `token` was issued to this holder
+    /// and has not expired yet.
+    pub fn get(&self, token: &str, holder: Holder, file_id: i64) -> Option<Zeroizing<[u8; 32]>> {
+        let unlocked = self.unlocked.lock().unwrap();
+        let entry = unlocked.get(token)?;
+        if entry.holder != holder
+            || entry.file_id != file_id
+            || entry.expires_at <= Utc::now()
+        {
+            return None;
+        }
+        Some(entry.key.clone())
+    }
+
+    pub fn sweep(&self) {
+        let now = Utc::now();
+        self.unlocked
+            .lock()
+            .unwrap()
+            .retain(|_, entry| entry.expires_at > now);
+    }
+}
+
+pub fn spawn_sweeper(keys: UnlockKeys) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL.to_std().unwrap());
+        loop {
+            interval.tick().await;
+            keys.sweep();
+        }
+    });
+}
@@ -0,0 +1,193 @@
+//! Upgrades of data written by older versions that need more than SQL, run
+//! at startup after the schema migrations. Each step only looks at what is
+//! still in the old form, so running them again is cheap and a run that was
+//! interrupted picks up where it stopped.
+
+use std::io::Read;
+use std::path::Path;
+
+use rusqlite::TransactionBehavior;
+
+use crate::config::Config;
+use crate::container;
+use crate::db::DatabaseConnection;
+use crate::handlers;
+use crate::keyring::{Field, FileMeta, Keyring};
+use crate::types::SenmonError;
+
+/// Tables whose names are sealed: table, key column, owner column and what
+/// kind of name they hold.
+const NAMED: &[(&str, &str, &str, Field)] = &[
+    ("file_state", "file_id", "file_owner", Field::FileName),
+    ("file_trash", "trash_id", "file_owner", Field::FileName),
+    ("folders", "folder_id", "owner", Field::FolderName),
+];
+
+pub async fn run(
+    db: &DatabaseConnection,
+    config: &Config,
+    keyring: &Keyring,
+) -> Result<(), SenmonError> {
+    if let Err(why) = check_user_keys(db, keyring).await {
+        tracing::error!(
+            "{} does not open the keys of existing users",
+            config.master_key_file.display()
+        );
+        return Err(why);
+    }
+    let sealed = seal_names(db, config, keyring).await?;
+    if sealed > 0 {
+        tracing::info!("sealed {sealed} plaintext name(s)");
+        scrub_free_pages(db).await?;
+    }
+    let moved = move_blobs(db, config).await?;
+    if moved > 0 {
+        tracing::info!("moved {moved} blob(s) out of per-user directories");
+    }
+    Ok(())
+}
+
+/// Unwraps every stored user key, so that a wrong `master_key_file` stops
+/// the server at startup instead of failing every request.
+async fn check_user_keys(db: &DatabaseConnection, keyring: &Keyring) -> Result<(), SenmonError> {
+    let keyring = keyring.clone();
+    db.run(move |cnx| {
+        let user_ids: Vec<u32> = {
+            let mut stmt = cnx.prepare_cached("SELECT user_id FROM user_keys;")?;
+            let rows = stmt.query_map([], |r| r.get(0))?;
+            rows.collect::<Result<_, _>>()?
+        };
+        for user_id in user_ids {
+            keyring.user_key(cnx, user_id)?;
+        }
+        Ok::<_, rusqlite::Error>(())
+    })
+    .await
+    .map_err(SenmonError::from)
+}
+
+/// Seals the names that migration 5 left in plaintext in `name_tag`, and
+/// records the size of such files, which the blob header gives away anyway.
+async fn seal_names(
+    db: &DatabaseConnection,
+    config: &Config,
+    keyring: &Keyring,
+) -> Result<usize, SenmonError> {
+    let (config, keyring) = (config.clone(), keyring.clone());
+    db.run(move |cnx| {
+        let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut sealed = 0;
+        for (table, id_column, owner_column, field) in NAMED {
+            let is_file = matches!(field, Field::FileName);
+            let blob_column = if is_file { "blob" } else { "NULL" };
+            let plaintext: Vec<(i64, u32, Option<String>, Option<String>)> = {
+                let mut stmt = tx.prepare(&format!(
+                    "SELECT {id_column}, {owner_column}, name_tag, {blob_column} FROM {table} WHERE name_sealed IS NULL;"
+                ))?;
+                let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))?;
+                rows.collect::<Result<_, _>>()?
+            };
+            for (id, owner, name, blob) in plaintext {
+                let key = keyring.user_key(&tx, owner)?;
+                let (tag, name_sealed) = key.seal_name(*field, &name.unwrap_or_default());
+                tx.execute(
+                    &format!("UPDATE {table} SET name_tag=?1, name_sealed=?2 WHERE {id_column}=?3;"),
+                    (&tag, &name_sealed, id),
+                )?;
+                if let Some(blob) = blob {
+                    let meta = FileMeta {
+                        size: plaintext_len(&config.blob_path(&blob)),
+                        content_type: None,
+                    };
+                    tx.execute(
+                        &format!("UPDATE {table} SET meta_sealed=?1 WHERE {id_column}=?2;"),
+                        (key.seal_meta(&meta), id),
+                    )?;
+                }
+                sealed += 1;
+            }
+        }
+        tx.commit()?;
+        Ok::<_, rusqlite::Error>(sealed)
+    })
+    .await
+    .map_err(SenmonError::from)
+}
+
+/// The plaintext names are still in free pages of the database and in the
+/// WAL after sealing. Rewrite the one and empty the other.
+async fn scrub_free_pages(db: &DatabaseConnection) -> Result<(), SenmonError> {
+    db.run(|cnx| cnx.execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE);"))
+        .await
+        .map_err(SenmonError::from)
+}
+
+/// Plaintext size of a blob, from its header or, for legacy blobs, from
+/// the length of the hex encoded `nonce || ciphertext || tag`.
+fn plaintext_len(path: &Path) -> Option<u64> {
+    let mut blob = std::fs::File::open(path).ok()?;
+    let mut header = [0u8; container::HEADER_LEN];
+    if blob.read_exact(&mut header).is_ok() {
+        if let Some(header) = container::Header::parse(&header) {
+            return Some(header.plaintext_len);
+        }
+    }
+    let len = blob.metadata().ok()?.len();
+    (len / 2).checked_sub(12 + container::TAG_LEN)
+}
+
+/// Gives every blob stored under its owner's name a fresh opaque name. The
+/// new name is linked in before the rows point at it and the old one
+/// removed after, so a crash leaves at worst an unreferenced copy for
+/// `senmon gc`.
+async fn move_blobs(db: &DatabaseConnection, config: &Config) -> Result<usize, SenmonError> {
+    let blobs: Vec<String> = db
+        .run(|cnx| {
+            let mut stmt = cnx.prepare_cached(
+                "SELECT blob FROM file_state UNION SELECT blob FROM file_trash;",
+            )?;
+            let rows = stmt.query_map([], |r| r.get(0))?;
+            rows.collect::<Result<_, _>>()
+        })
+        .await?;
+
+    let mut moved = 0;
+    for old in blobs {
+        if handlers::is_opaque_blob_name(&old) {
+            continue;
+        }
+        let new = handlers::new_blob_name();
+        let (old_path, new_path) = (config.blob_path(&old), config.blob_path(&new));
+        if !old_path.is_file() {
+            tracing::warn!("{}: blob is missing, leaving it for scrub", old_path.display());
+            continue;
+        }
+        if let Some(dir) = new_path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        if std::fs::hard_link(&old_path, &new_path).is_err() {
+            std::fs::copy(&old_path, &new_path)?;
+            std::fs::File::open(&new_path)?.sync_all()?;
+        }
+
+        db.run({
+            let (old, new) = (old.clone(), new.clone());
+            move |cnx| {
+                let tx = cnx.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                tx.execute("UPDATE file_state SET blob=?1 WHERE blob=?2;", (&new, &old))?;
+                tx.execute("UPDATE file_trash SET blob=?1 WHERE blob=?2;", (&new, &old))?;
+                tx.execute("UPDATE file_health SET blob=?1 WHERE blob=?2;", (&new, &old))?;
+                tx.commit()
+            }
+        })
+        .await?;
+
+        std::fs::remove_file(&old_path)?;
+        // Only succeeds once the user's directory is empty.
+        if let Some(dir) = old_path.parent().filter(|dir| *dir != config.stash_dir) {
+            let _ = std::fs::remove_dir(dir);
+        }
+        moved += 1;
+    }
+    Ok(moved)
+}